@@ -0,0 +1,219 @@
+use std::{collections::HashSet, fmt, net::IpAddr, path::PathBuf, str::FromStr};
+
+use crate::condition::Expr;
+use crate::monitor::NetworkState;
+
+/// Names that hosts-style lists map to themselves rather than block
+const HOSTS_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// Set of domains, either exact names or whole subtrees
+#[derive(Default)]
+pub struct Domains {
+    exact: HashSet<String>,
+    subtrees: HashSet<String>,
+}
+
+impl Domains {
+    /// Whether `name`, with or without the trailing dot, is in the set
+    pub fn contains(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.exact.contains(&name) {
+            return true;
+        }
+        let mut suffix = name.as_str();
+        loop {
+            if self.subtrees.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.subtrees.len()
+    }
+
+    pub fn extend(&mut self, other: Domains) {
+        self.exact.extend(other.exact);
+        self.subtrees.extend(other.subtrees);
+    }
+
+    fn insert(&mut self, name: &str, subtree: bool) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if subtree {
+            self.subtrees.insert(name);
+        } else {
+            self.exact.insert(name);
+        }
+    }
+}
+
+/// Lists have hundreds of thousands of entries, too many to print
+impl fmt::Debug for Domains {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Domains")
+            .field("exact", &self.exact.len())
+            .field("subtrees", &self.subtrees.len())
+            .finish()
+    }
+}
+
+fn is_domain(name: &str) -> bool {
+    !name.is_empty()
+        && name.parse::<IpAddr>().is_err()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Parse a list mixing the supported formats line by line, returns the blocked and allowed domains.
+///
+/// ```plain
+/// 0.0.0.0 ads.example         # hosts format, the name only
+/// tracker.example             # plain domain, the name only
+/// ||ads.example^              # adblock, the domain and its subdomains
+/// @@||cdn.ads.example^        # adblock exception, allowed
+/// ```
+///
+/// Comments, adblock rules with options and cosmetic filters are skipped.
+pub fn parse(source: &str) -> (Domains, Domains) {
+    let mut blocked = Domains::default();
+    let mut allowed = Domains::default();
+    for line in source.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', '!', '[']) {
+            continue;
+        }
+        if let Some(rule) = line.strip_prefix("@@||") {
+            if let Some(domain) = rule.strip_suffix('^').filter(|d| is_domain(d)) {
+                allowed.insert(domain, true);
+            }
+            continue;
+        }
+        if let Some(rule) = line.strip_prefix("||") {
+            if let Some(domain) = rule.strip_suffix('^').filter(|d| is_domain(d)) {
+                blocked.insert(domain, true);
+            }
+            continue;
+        }
+
+        // `#` starts a comment after whitespace, otherwise it is a cosmetic filter
+        let line = match line.find('#') {
+            Some(idx) if line[..idx].ends_with(char::is_whitespace) => &line[..idx],
+            Some(_) => continue,
+            None => line,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let names = match words.as_slice() {
+            [name] => std::slice::from_ref(name),
+            [address, names @ ..] if address.parse::<IpAddr>().is_ok() => names,
+            _ => continue,
+        };
+        for name in names {
+            if is_domain(name) && !HOSTS_NAMES.contains(name) {
+                blocked.insert(name, false);
+            }
+        }
+    }
+    (blocked, allowed)
+}
+
+/// Domains from `path` blocked while all `conditions` hold,
+/// except for the exceptions of the same list
+#[derive(Debug)]
+pub struct Blocklist {
+    pub path: PathBuf,
+    pub conditions: Vec<Expr>,
+    pub domains: Domains,
+    pub exceptions: Domains,
+}
+
+impl Blocklist {
+    pub fn blocks(&self, name: &str, state: &NetworkState) -> bool {
+        self.conditions.iter().all(|c| c.eval(state))
+            && self.domains.contains(name)
+            && !self.exceptions.contains(name)
+    }
+}
+
+impl fmt::Display for Blocklist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "blocklist {}", self.path.display())?;
+        for condition in &self.conditions {
+            write!(f, ", {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// How blocked names are answered
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BlockResponse {
+    /// `0.0.0.0` or `::`
+    #[default]
+    Null,
+    NxDomain,
+    Refused,
+}
+
+impl FromStr for BlockResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "null" | "0.0.0.0" => Ok(BlockResponse::Null),
+            "nxdomain" => Ok(BlockResponse::NxDomain),
+            "refused" => Ok(BlockResponse::Refused),
+            _ => Err(format!(
+                "invalid block response {:?}, expected null, nxdomain or refused",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let (blocked, allowed) = parse(
+            "\
+[Adblock Plus 2.0]
+! comment
+# comment
+127.0.0.1 localhost
+0.0.0.0 0.0.0.0
+0.0.0.0 ads.example tracker.example  # inline comment
+Metrics.Example.
+||ads.network^
+||ads.network^$third-party
+@@||cdn.ads.network^
+example.com##.banner
+not a domain
+",
+        );
+        assert_eq!(blocked.len(), 4);
+        assert!(blocked.contains("ads.example."));
+        assert!(!blocked.contains("www.ads.example."));
+        assert!(blocked.contains("tracker.example"));
+        assert!(blocked.contains("metrics.example."));
+        assert!(blocked.contains("ads.network."));
+        assert!(blocked.contains("x.cdn.ads.network."));
+        assert!(!blocked.contains("localhost."));
+        assert!(!blocked.contains("network."));
+        assert!(allowed.contains("x.cdn.ads.network."));
+        assert!(!allowed.contains("ads.network."));
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::core::*;
+use crate::monitor::NetworkState;
+
+/// Upstream answers are never kept longer than this, whatever their TTL says
+const MAX_TTL: u32 = 86400;
+
+const TYPE_OPT: u16 = 41;
+
+/// Cache of upstream responses, keyed by the upstream rule that answered and the question.
+///
+/// Responses are kept as raw bytes, so records of types unknown to the codec are cached too.
+/// Everything is dropped once the network state changes.
+pub struct Cache {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    capacity: usize,
+    state: NetworkState,
+    entries: HashMap<(usize, String, DnsType), Entry>,
+}
+
+struct Entry {
+    response: Vec<u8>,
+    /// Offsets of the TTL fields in `response`
+    ttls: Vec<usize>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl Cache {
+    /// Cache up to `capacity` responses, a capacity of 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                capacity,
+                state: NetworkState::default(),
+                entries: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+
+    /// Drop everything and change the capacity
+    pub fn reset(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        inner.entries.clear();
+    }
+
+    /// Cached response to `question` with the ID set to `id` and TTLs counted down
+    pub fn get(
+        &self,
+        state: &NetworkState,
+        upstream: usize,
+        question: &DnsQuestion,
+        id: u16,
+    ) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync(state);
+        let key = (upstream, question.name.clone(), question.r#type);
+        let now = Instant::now();
+        let entry = inner.entries.get(&key)?;
+        if entry.expires <= now {
+            inner.entries.remove(&key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut response = entry.response.clone();
+        response[..2].copy_from_slice(&id.to_be_bytes());
+        for &offset in &entry.ttls {
+            let ttl = u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap());
+            response[offset..offset + 4]
+                .copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// Keep `response` for as long as its smallest TTL,
+    /// only successful and NXDOMAIN responses with records are cached
+    pub fn insert(
+        &self,
+        state: &NetworkState,
+        upstream: usize,
+        question: &DnsQuestion,
+        response: &[u8],
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync(state);
+        if inner.capacity == 0 || response.len() < 12 {
+            return;
+        }
+        if !matches!(rcode_of(response), Some(Rcode::NoError | Rcode::NXDomain)) {
+            return;
+        }
+        let Some(ttls) = ttl_offsets(response) else {
+            return;
+        };
+        let Some(ttl) = ttls
+            .iter()
+            .map(|&offset| u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap()))
+            .min()
+        else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        if inner.entries.len() >= inner.capacity {
+            inner.entries.retain(|_, e| e.expires > now);
+            if inner.entries.len() >= inner.capacity {
+                return;
+            }
+        }
+        inner.entries.insert(
+            (upstream, question.name.clone(), question.r#type),
+            Entry {
+                response: response.to_vec(),
+                ttls,
+                stored: now,
+                expires: now + Duration::from_secs(ttl.min(MAX_TTL) as u64),
+            },
+        );
+    }
+}
+
+impl Inner {
+    fn sync(&mut self, state: &NetworkState) {
+        // the client only selects the upstream rule, which is part of the key
+        let state = NetworkState {
+            client: None,
+            client_groups: Vec::new(),
+            ..state.clone()
+        };
+        if self.state != state {
+            self.state = state;
+            self.entries.clear();
+        }
+    }
+}
+
+/// Offsets of the TTL fields of all resource records in `msg`, except the EDNS OPT pseudo record.
+/// `None` if the message is malformed.
+fn ttl_offsets(msg: &[u8]) -> Option<Vec<usize>> {
+    if msg.len() < 12 {
+        return None;
+    }
+    let count = |idx: usize| u16::from_be_bytes([msg[idx], msg[idx + 1]]) as usize;
+    let (qdcount, rrcount) = (count(4), count(6) + count(8) + count(10));
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    let mut offsets = Vec::with_capacity(rrcount);
+    for _ in 0..rrcount {
+        pos = skip_name(msg, pos)?;
+        let fixed = msg.get(pos..pos + 10)?;
+        let r#type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if r#type != TYPE_OPT {
+            offsets.push(pos + 4);
+        }
+        pos += 10 + rdlength;
+    }
+    (pos <= msg.len()).then_some(offsets)
+}
+
+/// Position right after the name starting at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // compression pointer ends the name
+            _ if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            _ => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion {
+            name: name.to_string(),
+            r#type: DnsType::A,
+            class: DnsClass::In,
+        }
+    }
+
+    fn response(name: &str, rcode: Rcode, ttl: u32) -> Vec<u8> {
+        let mut packet = DnsPacket {
+            header: DnsHeader {
+                id: 1,
+                qr: true,
+                rcode,
+                ..Default::default()
+            },
+            questions: vec![question(name)],
+            answers: vec![DnsRecord::address(name, ttl, "10.0.0.1".parse().unwrap())],
+            ..Default::default()
+        };
+        packet.encode().unwrap()
+    }
+
+    #[test]
+    fn ttl_countdown() {
+        let cache = Cache::new(16);
+        let state = NetworkState::default();
+        let q = question("example.com.");
+        cache.insert(&state, 0, &q, &response("example.com.", Rcode::NoError, 60));
+
+        assert!(cache.get(&state, 1, &q, 2).is_none());
+        thread::sleep(Duration::from_millis(1100));
+        let cached = DnsPacket::parse(&cache.get(&state, 0, &q, 2).unwrap()).unwrap();
+        assert_eq!(cached.header.id, 2);
+        assert_eq!(cached.answers[0].ttl, 59);
+        assert_eq!(cached.answers[0].data[0].to_string(), "10.0.0.1");
+    }
+
+    #[test]
+    fn eviction() {
+        let cache = Cache::new(1);
+        let state = NetworkState::default();
+        let q = question("example.com.");
+
+        // failures and records without TTL are not kept
+        cache.insert(
+            &state,
+            0,
+            &q,
+            &response("example.com.", Rcode::ServFail, 60),
+        );
+        assert!(cache.get(&state, 0, &q, 1).is_none());
+        cache.insert(&state, 0, &q, &response("example.com.", Rcode::NoError, 0));
+        assert!(cache.get(&state, 0, &q, 1).is_none());
+        // the header alone says NOERROR
+        cache.insert(&state, 0, &q, &response("example.com.", Rcode::BadVers, 60));
+        assert!(cache.get(&state, 0, &q, 1).is_none());
+
+        // full
+        cache.insert(
+            &state,
+            0,
+            &q,
+            &response("example.com.", Rcode::NXDomain, 60),
+        );
+        let other = question("example.org.");
+        cache.insert(
+            &state,
+            0,
+            &other,
+            &response("example.org.", Rcode::NoError, 60),
+        );
+        assert!(cache.get(&state, 0, &q, 1).is_some());
+        assert!(cache.get(&state, 0, &other, 1).is_none());
+
+        // flushed by network changes
+        let cellular = NetworkState {
+            cellular: true,
+            ..Default::default()
+        };
+        assert!(cache.get(&cellular, 0, &q, 1).is_none());
+        assert!(cache.get(&state, 0, &q, 1).is_none());
+    }
+
+    #[test]
+    fn malformed() {
+        let mut msg = response("example.com.", Rcode::NoError, 60);
+        assert_eq!(ttl_offsets(&msg).unwrap().len(), 1);
+        msg.truncate(msg.len() - 2);
+        assert!(ttl_offsets(&msg).is_none());
+    }
+}
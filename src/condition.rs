@@ -0,0 +1,732 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::monitor::{InterfaceType, MacAddr, NetworkState};
+use crate::probe::Target;
+use crate::schedule::{TimeRange, Weekdays};
+
+/// Condition on the network state
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// `ssid="home"`
+    Ssid(String),
+    /// `cellular="on"` or `cellular="off"`
+    Cellular(bool),
+    /// `iface="wg0"`, an interface with this name is up
+    Iface(String),
+    /// `wired="on"`, the current path goes through an Ethernet interface
+    Wired(bool),
+    /// `subnet="10.1.0.0/16"`, a local address is within the network
+    Subnet(Cidr),
+    /// `gateway="192.168.1.1"`
+    Gateway(IpAddr),
+    /// `gateway_mac="a4:91:b1:05:0e:01"`, tells apart networks sharing the same addresses
+    GatewayMac(MacAddr),
+    /// `vpn="on"`, a tunnel interface is up
+    Vpn(bool),
+    /// `time="09:00-18:00"`, optionally in a time zone, `time="09:00-18:00 Asia/Shanghai"`
+    Time(TimeRange),
+    /// `days="mon-fri"`, optionally in a time zone like `time`
+    Days(Weekdays),
+    /// `reachable="tcp://192.168.1.10:445"`, according to the background prober
+    Reachable(Target),
+    /// `client="192.168.10.0/24"`, the query comes from within the network
+    Client(Cidr),
+    /// `client="guests"`, the query comes from a group defined with `client-group`
+    ClientGroup(String),
+}
+
+impl Condition {
+    pub fn new(key: &str, value: &str) -> Result<Self, String> {
+        match key {
+            "ssid" => Ok(Condition::Ssid(value.to_string())),
+            "cellular" => parse_switch(value).map(Condition::Cellular),
+            "iface" => Ok(Condition::Iface(value.to_string())),
+            "wired" => parse_switch(value).map(Condition::Wired),
+            "subnet" => value.parse().map(Condition::Subnet),
+            "gateway" => value
+                .parse()
+                .map(Condition::Gateway)
+                .map_err(|_| format!("invalid gateway address {:?}", value)),
+            "gateway_mac" => value.parse().map(Condition::GatewayMac),
+            "vpn" => parse_switch(value).map(Condition::Vpn),
+            "time" => value.parse().map(Condition::Time),
+            "days" => value.parse().map(Condition::Days),
+            "reachable" => value.parse().map(Condition::Reachable),
+            "client" => Ok(match value.parse() {
+                Ok(cidr) => Condition::Client(cidr),
+                Err(_) => Condition::ClientGroup(value.to_string()),
+            }),
+            key => Err(format!("unknown condition {:?}", key)),
+        }
+    }
+
+    pub fn eval(&self, state: &NetworkState) -> bool {
+        match self {
+            Condition::Ssid(ssid) => state.ssid.as_ref() == Some(ssid),
+            Condition::Cellular(on) => state.cellular == *on,
+            Condition::Iface(name) => state.interfaces.contains(name),
+            Condition::Wired(on) => (state.interface == InterfaceType::Wired) == *on,
+            Condition::Subnet(cidr) => state.addresses.iter().any(|a| cidr.contains(a)),
+            Condition::Gateway(gateway) => state.gateways.contains(gateway),
+            Condition::GatewayMac(mac) => state.gateway_macs.contains(mac),
+            Condition::Vpn(on) => state.vpn == *on,
+            Condition::Time(range) => range.contains(&state.now()),
+            Condition::Days(days) => days.contains(&state.now()),
+            Condition::Reachable(target) => state.reachable.contains(target),
+            Condition::Client(cidr) => state.client.is_some_and(|c| cidr.contains(&c)),
+            Condition::ClientGroup(group) => state.client_groups.contains(group),
+        }
+    }
+
+    /// Whether the condition depends on the time rather than the network
+    pub fn is_scheduled(&self) -> bool {
+        matches!(self, Condition::Time(_) | Condition::Days(_))
+    }
+
+    /// Change `state` so that the condition holds, used to override the monitor
+    pub fn apply(&self, state: &mut NetworkState) -> Result<(), String> {
+        match self {
+            Condition::Ssid(ssid) => state.ssid = Some(ssid.clone()),
+            Condition::Cellular(on) => state.cellular = *on,
+            Condition::Iface(name) => {
+                if !state.interfaces.contains(name) {
+                    state.interfaces.push(name.clone());
+                }
+            }
+            Condition::Wired(true) => {
+                state.interface = InterfaceType::Wired;
+                state.cellular = false;
+            }
+            Condition::Wired(false) => {
+                if state.interface == InterfaceType::Wired {
+                    state.interface = InterfaceType::Other;
+                }
+            }
+            Condition::Subnet(cidr) => {
+                if !state.addresses.iter().any(|a| cidr.contains(a)) {
+                    state.addresses.push(cidr.addr);
+                }
+            }
+            Condition::Gateway(gateway) => state.gateways = vec![*gateway],
+            Condition::GatewayMac(mac) => state.gateway_macs = vec![*mac],
+            Condition::Vpn(on) => state.vpn = *on,
+            Condition::Reachable(target) => {
+                if !state.reachable.contains(target) {
+                    state.reachable.push(target.clone());
+                }
+            }
+            Condition::Time(_)
+            | Condition::Days(_)
+            | Condition::Client(_)
+            | Condition::ClientGroup(_) => {
+                return Err(format!("{} is not a network condition", self))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Ssid(ssid) => write!(f, "ssid=\"{}\"", escape(ssid)),
+            Condition::Cellular(on) => write!(f, "cellular=\"{}\"", switch(*on)),
+            Condition::Iface(name) => write!(f, "iface=\"{}\"", escape(name)),
+            Condition::Wired(on) => write!(f, "wired=\"{}\"", switch(*on)),
+            Condition::Subnet(cidr) => write!(f, "subnet=\"{}\"", cidr),
+            Condition::Gateway(gateway) => write!(f, "gateway=\"{}\"", gateway),
+            Condition::GatewayMac(mac) => write!(f, "gateway_mac=\"{}\"", mac),
+            Condition::Vpn(on) => write!(f, "vpn=\"{}\"", switch(*on)),
+            Condition::Time(range) => write!(f, "time=\"{}\"", range),
+            Condition::Days(days) => write!(f, "days=\"{}\"", days),
+            Condition::Reachable(target) => write!(f, "reachable=\"{}\"", target),
+            Condition::Client(cidr) => write!(f, "client=\"{}\"", cidr),
+            Condition::ClientGroup(group) => write!(f, "client=\"{}\"", escape(group)),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=\"value\" condition, found {:?}", s))?;
+        let value = value
+            .trim()
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .ok_or_else(|| format!("expected quoted value in condition {:?}", s))?;
+        Condition::new(key.trim(), &unescape(value))
+    }
+}
+
+/// Escape the quotes and backslashes of a value to be quoted
+pub fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Undo `escape`, a backslash before any other character is kept
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next @ ('"' | '\\'))) => {
+                unescaped.push(next);
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+pub fn parse_switch(value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected \"on\" or \"off\", found {:?}", value)),
+    }
+}
+
+fn switch(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Network in CIDR notation, `10.1.0.0/16` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    /// Network address, host bits cleared
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                mask(u32::from(*addr).into(), self.prefix, 32) == u32::from(net).into()
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                mask(u128::from(*addr), self.prefix, 128) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keep the leading `prefix` bits of a `bits` wide address
+fn mask(addr: u128, prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        _ => addr & (u128::MAX << (bits - prefix)),
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// A bare address is a single host network
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid network {:?}", s);
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr.parse().map_err(|_| err())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= bits).ok_or_else(err)?,
+            None => bits,
+        };
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                IpAddr::V4((mask(u32::from(addr).into(), prefix, 32) as u32).into())
+            }
+            IpAddr::V6(addr) => IpAddr::V6(mask(u128::from(addr), prefix, 128).into()),
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Boolean expression over conditions:
+///
+/// ```plain
+/// (ssid="work" or ssid in ["home", "home-5g"]) and not cellular="on"
+/// ```
+///
+/// `not` binds tighter than `and`, which binds tighter than `or`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Condition(Condition),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, state: &NetworkState) -> bool {
+        match self {
+            Expr::Condition(condition) => condition.eval(state),
+            Expr::Not(expr) => !expr.eval(state),
+            Expr::And(exprs) => exprs.iter().all(|e| e.eval(state)),
+            Expr::Or(exprs) => exprs.iter().any(|e| e.eval(state)),
+        }
+    }
+
+    /// Every condition in the expression
+    pub fn conditions(&self) -> Vec<&Condition> {
+        match self {
+            Expr::Condition(condition) => vec![condition],
+            Expr::Not(expr) => expr.conditions(),
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                exprs.iter().flat_map(|e| e.conditions()).collect()
+            }
+        }
+    }
+
+    /// Write `self` as an operand of an operator binding as tight as `precedence`
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, precedence: u8) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Or(_) => 0,
+            Expr::And(_) => 1,
+            Expr::Not(_) | Expr::Condition(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (exprs, op) = match self {
+            Expr::Condition(condition) => return write!(f, "{}", condition),
+            Expr::Not(expr) => {
+                write!(f, "not ")?;
+                return expr.fmt_operand(f, 2);
+            }
+            Expr::And(exprs) => (exprs, " and "),
+            Expr::Or(exprs) => (exprs, " or "),
+        };
+        for (idx, expr) in exprs.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "{}", op)?;
+            }
+            // both operators are associative, nested ones of the same kind need no parentheses
+            expr.fmt_operand(f, self.precedence())?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        match parser.next() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {} after condition", token)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Eq,
+    Comma,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "word {:?}", word),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::Eq => write!(f, "\"=\""),
+            Token::Comma => write!(f, "\",\""),
+            Token::LParen => write!(f, "\"(\""),
+            Token::RParen => write!(f, "\")\""),
+            Token::LBracket => write!(f, "\"[\""),
+            Token::RBracket => write!(f, "\"]\""),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        let token = match c {
+            _ if c.is_whitespace() => continue,
+            '=' => Token::Eq,
+            ',' => Token::Comma,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '"' => {
+                // ends at the first quote not escaped by a backslash
+                let mut escaped = false;
+                let end = loop {
+                    let Some((end, c)) = chars.next() else {
+                        return Err(format!("unterminated string {:?}", &s[idx..]));
+                    };
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => break end,
+                        _ => {}
+                    }
+                };
+                Token::Str(unescape(&s[idx + 1..end]))
+            }
+            _ if c.is_alphanumeric() || c == '_' || c == '-' => {
+                let mut word = c.to_string();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_' || c == '-') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+            _ => return Err(format!("unexpected character {:?} in condition", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, one method per precedence level
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Word(w)) if w == word)
+    }
+
+    fn expect(&mut self, expected: Token, context: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!(
+                "expected {} {}, found {}",
+                expected, context, token
+            )),
+            None => Err(format!("expected {} {}, found nothing", expected, context)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.and()?];
+        while self.peek_word("or") {
+            self.pos += 1;
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::Or(exprs)
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut exprs = vec![self.not()?];
+        while self.peek_word("and") {
+            self.pos += 1;
+            exprs.push(self.not()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::And(exprs)
+        })
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.peek_word("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        let key = match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                self.expect(Token::RParen, "to close \"(\"")?;
+                return Ok(expr);
+            }
+            Some(Token::Word(key)) if !matches!(key.as_str(), "and" | "or" | "not" | "in") => key,
+            Some(token) => return Err(format!("expected condition, found {}", token)),
+            None => return Err("expected condition, found nothing".to_string()),
+        };
+        match self.next() {
+            Some(Token::Eq) => match self.next() {
+                Some(Token::Str(value)) => Condition::new(&key, &value).map(Expr::Condition),
+                token => Err(format!(
+                    "expected quoted value after \"{}=\", found {}",
+                    key,
+                    token.map_or("nothing".to_string(), |t| t.to_string())
+                )),
+            },
+            Some(Token::Word(word)) if word == "in" => {
+                self.expect(Token::LBracket, &format!("after \"{} in\"", key))?;
+                let mut exprs = Vec::new();
+                loop {
+                    match self.next() {
+                        Some(Token::Str(value)) => {
+                            exprs.push(Expr::Condition(Condition::new(&key, &value)?))
+                        }
+                        Some(Token::RBracket) if exprs.is_empty() => {
+                            return Err(format!("empty value list for {:?}", key))
+                        }
+                        token => {
+                            return Err(format!(
+                                "expected quoted value in the list of {:?}, found {}",
+                                key,
+                                token.map_or("nothing".to_string(), |t| t.to_string())
+                            ))
+                        }
+                    }
+                    match self.next() {
+                        Some(Token::Comma) => {}
+                        Some(Token::RBracket) => break,
+                        token => {
+                            return Err(format!(
+                                "expected \",\" or \"]\" in the list of {:?}, found {}",
+                                key,
+                                token.map_or("nothing".to_string(), |t| t.to_string())
+                            ))
+                        }
+                    }
+                }
+                Ok(if exprs.len() == 1 {
+                    exprs.remove(0)
+                } else {
+                    Expr::Or(exprs)
+                })
+            }
+            token => Err(format!(
+                "expected \"=\" or \"in\" after {:?}, found {}",
+                key,
+                token.map_or("nothing".to_string(), |t| t.to_string())
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(ssid: Option<&str>, cellular: bool) -> NetworkState {
+        NetworkState {
+            ssid: ssid.map(|s| s.to_string()),
+            cellular,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let expr: Expr = r#"ssid="work" or ssid="home" and not cellular="on""#
+            .parse()
+            .unwrap();
+        assert_eq!(
+            expr,
+            Expr::Or(vec![
+                Expr::Condition(Condition::Ssid("work".to_string())),
+                Expr::And(vec![
+                    Expr::Condition(Condition::Ssid("home".to_string())),
+                    Expr::Not(Box::new(Expr::Condition(Condition::Cellular(true)))),
+                ]),
+            ])
+        );
+        assert!(expr.eval(&state(Some("work"), true)));
+        assert!(expr.eval(&state(Some("home"), false)));
+        assert!(!expr.eval(&state(Some("home"), true)));
+    }
+
+    #[test]
+    fn grouping() {
+        let expr: Expr = r#"(ssid = "work" or ssid in ["home", "home-5g"]) and not cellular="on""#
+            .parse()
+            .unwrap();
+        assert!(expr.eval(&state(Some("home-5g"), false)));
+        assert!(!expr.eval(&state(Some("home-5g"), true)));
+        assert!(!expr.eval(&state(Some("cafe"), false)));
+        assert_eq!(
+            expr.to_string(),
+            r#"(ssid="work" or ssid="home" or ssid="home-5g") and not cellular="on""#
+        );
+
+        let expr: Expr = r#"not (ssid="a" and ssid="b")"#.parse().unwrap();
+        assert_eq!(expr.to_string(), r#"not (ssid="a" and ssid="b")"#);
+        assert_eq!(expr.to_string().parse::<Expr>().unwrap(), expr);
+        let expr: Expr = r#"ssid in ["cafe, #2"]"#.parse().unwrap();
+        assert_eq!(
+            expr,
+            Expr::Condition(Condition::Ssid("cafe, #2".to_string()))
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        let expr: Expr = r#"ssid="say \"hi\"" or ssid="a\\b" or ssid="c\d""#.parse().unwrap();
+        assert_eq!(
+            expr,
+            Expr::Or(vec![
+                Expr::Condition(Condition::Ssid(r#"say "hi""#.to_string())),
+                Expr::Condition(Condition::Ssid(r"a\b".to_string())),
+                Expr::Condition(Condition::Ssid(r"c\d".to_string())),
+            ])
+        );
+        assert_eq!(
+            expr.to_string(),
+            r#"ssid="say \"hi\"" or ssid="a\\b" or ssid="c\\d""#
+        );
+        assert_eq!(expr.to_string().parse::<Expr>().unwrap(), expr);
+        let condition: Condition = r#"ssid="\"\\""#.parse().unwrap();
+        assert_eq!(condition, Condition::Ssid(r#""\"#.to_string()));
+        assert_eq!(
+            condition.to_string().parse::<Condition>().unwrap(),
+            condition
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| s.parse::<Expr>().unwrap_err();
+        assert_eq!(
+            err(r#"(ssid="a""#),
+            r#"expected ")" to close "(", found nothing"#
+        );
+        assert_eq!(
+            err("ssid=home"),
+            r#"expected quoted value after "ssid=", found word "home""#
+        );
+        assert_eq!(err(r#"wifi="on""#), r#"unknown condition "wifi""#);
+        assert_eq!(err(r#"ssid="a" or"#), "expected condition, found nothing");
+        assert_eq!(
+            err(r#"ssid="a" ssid="b""#),
+            r#"unexpected word "ssid" after condition"#
+        );
+        assert_eq!(
+            err(r#"ssid in ["a" "b"]"#),
+            r#"expected "," or "]" in the list of "ssid", found string "b""#
+        );
+        assert_eq!(err(r#"ssid in []"#), r#"empty value list for "ssid""#);
+        assert_eq!(err(r#"ssid="a"#), r#"unterminated string "\"a""#);
+        assert_eq!(
+            err(r#"subnet="10.1.0.0/33""#),
+            r#"invalid network "10.1.0.0/33""#
+        );
+        assert_eq!(
+            err(r#"ssid!="a""#),
+            r#"unexpected character '!' in condition"#
+        );
+    }
+
+    #[test]
+    fn network() {
+        let state = NetworkState {
+            interface: InterfaceType::Wired,
+            interfaces: vec!["enp3s0".to_string(), "wg0".to_string()],
+            addresses: vec!["10.1.2.3".parse().unwrap(), "fd00::2".parse().unwrap()],
+            gateways: vec!["10.1.0.1".parse().unwrap()],
+            gateway_macs: vec!["a4:91:b1:05:0e:01".parse().unwrap()],
+            vpn: true,
+            ..Default::default()
+        };
+        let eval = |s: &str| s.parse::<Expr>().unwrap().eval(&state);
+        assert!(eval(r#"iface="wg0" and wired="on" and vpn="on""#));
+        assert!(eval(r#"subnet="10.1.0.0/16" and subnet="fd00::/8""#));
+        assert!(!eval(r#"subnet="10.2.0.0/16" or subnet="10.1.2.4""#));
+        assert!(eval(
+            r#"gateway="10.1.0.1" and gateway_mac="A4-91-B1-05-0E-01""#
+        ));
+        assert!(!eval(
+            r#"iface="wlan0" or gateway="192.168.1.1" or vpn="off""#
+        ));
+
+        let cidr: Cidr = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.1.0.0/16");
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&state.addresses[0]));
+        assert!(!"0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&state.addresses[1]));
+
+        let mut overridden = NetworkState::default();
+        for condition in [r#"iface="wg0""#, r#"subnet="10.1.0.0/16""#, r#"wired="on""#] {
+            let condition: Condition = condition.parse().unwrap();
+            condition.apply(&mut overridden).unwrap();
+            assert!(condition.eval(&overridden), "{}", condition);
+        }
+        assert_eq!(
+            overridden.addresses,
+            vec!["10.1.0.0".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn schedule() {
+        let expr: Expr = r#"days="mon-fri UTC" and time="09:00-18:00 UTC""#.parse().unwrap();
+        let at = |time: &str| NetworkState {
+            time: Some(time.parse().unwrap()),
+            ..Default::default()
+        };
+        // Thursday
+        assert!(expr.eval(&at("2024-08-01T09:00:00Z")));
+        assert!(!expr.eval(&at("2024-08-01T18:00:00Z")));
+        // Saturday
+        assert!(!expr.eval(&at("2024-08-03T10:00:00Z")));
+        assert_eq!(
+            expr.to_string(),
+            r#"days="mon-fri UTC" and time="09:00-18:00 UTC""#
+        );
+        assert!(expr.conditions().iter().all(|c| c.is_scheduled()));
+
+        let condition: Condition = r#"time="09:00-18:00""#.parse().unwrap();
+        assert!(condition.apply(&mut NetworkState::default()).is_err());
+    }
+}
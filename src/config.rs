@@ -0,0 +1,2395 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt, fs, io, mem,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, error};
+use tracing_appender::rolling::Rotation;
+
+use crate::blocklist::{self, BlockResponse, Blocklist, Domains};
+use crate::condition::{parse_switch, Cidr, Condition, Expr};
+use crate::core::{DnsRData, DnsType, Soa, SvcParam, Svcb};
+use crate::monitor::NetworkState;
+use crate::probe::{Check, Target};
+use crate::remote::Remote;
+use crate::upstream::Upstream;
+
+/// Smart hosts configuration
+///
+/// Every non-empty line is either a host rule or a directive:
+///
+/// ```plain
+/// 127.0.0.1   *.home.local, ssid="home"
+/// upstream 10.0.0.53   *.corp.example, ssid="work"
+/// upstream tls://1.1.1.1#cloudflare-dns.com https://dns.google/dns-query
+/// tls-listen 0.0.0.0:853
+/// https-listen 0.0.0.0:443
+/// metrics-listen 127.0.0.1:9153
+/// include conf.d/*.hosts priority 10
+/// hosts-file /etc/hosts
+/// zone-file home.zone origin home.example.com
+/// blocklist /etc/smart_hosts/ads.txt, ssid="cafe"
+/// remote https://example.com/team.hosts cache/team.hosts interval 1h
+/// txt home.local "v=spf1 -all"
+/// zone home.example.com ns1.example.com serial 2026101901
+/// ```
+#[derive(Debug)]
+pub struct Config {
+    pub rules: Vec<Rule>,
+    /// TXT, MX, SRV, SVCB and HTTPS records, all of those matching are answered
+    pub records: Vec<RecordRule>,
+    pub upstreams: Vec<UpstreamRule>,
+    /// Zones answered authoritatively
+    pub zones: Vec<Zone>,
+    /// Forward queries not answered locally, otherwise they are refused
+    pub forwarding: bool,
+    pub blocklists: Vec<Blocklist>,
+    /// Never blocked
+    pub allowlist: Domains,
+    pub block_response: BlockResponse,
+    pub ttl: TtlConfig,
+    /// Order of the addresses of rules without their own
+    pub address_order: Order,
+    /// Answer all addresses of a rule when none of them is healthy, rather than skipping the rule
+    pub health_fail_open: bool,
+    /// Networks of the groups matched by `client="NAME"` conditions
+    pub client_groups: HashMap<String, Vec<Cidr>>,
+    /// Extra CA certificates trusted for encrypted upstreams
+    pub upstream_ca: Option<PathBuf>,
+    pub tls: TlsConfig,
+    pub doh: DohConfig,
+    pub query_log: QueryLogConfig,
+    /// Prometheus metrics listener, disabled unless set
+    pub metrics_listen: Option<SocketAddr>,
+    /// Upstream responses to cache, 0 disables the cache
+    pub cache_size: usize,
+    /// Unix socket of the control API
+    pub control_socket: PathBuf,
+    pub probe: ProbeConfig,
+    /// Files downloaded and refreshed in the background, paths resolved
+    pub remotes: Vec<Remote>,
+    /// Files read and directories searched by includes, watched for changes
+    pub sources: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            records: Vec::new(),
+            upstreams: Vec::new(),
+            zones: Vec::new(),
+            forwarding: true,
+            blocklists: Vec::new(),
+            allowlist: Domains::default(),
+            block_response: BlockResponse::default(),
+            ttl: TtlConfig::default(),
+            address_order: Order::default(),
+            health_fail_open: true,
+            client_groups: HashMap::new(),
+            upstream_ca: None,
+            tls: TlsConfig::default(),
+            doh: DohConfig::default(),
+            query_log: QueryLogConfig::default(),
+            metrics_listen: None,
+            cache_size: 4096,
+            control_socket: crate::control::DEFAULT_SOCKET.into(),
+            probe: ProbeConfig::default(),
+            remotes: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+}
+
+/// TTLs of local answers in seconds. Answers of conditional rules get a short TTL by default,
+/// so clients do not hold on to them long after the network changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TtlConfig {
+    pub default: u32,
+    pub conditional: u32,
+    /// TTL of the start of authority record in answers without any address
+    pub negative: u32,
+}
+
+impl Default for TtlConfig {
+    fn default() -> Self {
+        Self {
+            default: 3600,
+            conditional: 60,
+            negative: 60,
+        }
+    }
+}
+
+impl TtlConfig {
+    /// TTL of answers given while `conditions` hold
+    pub fn of(&self, conditions: &[Expr]) -> u32 {
+        if conditions.is_empty() {
+            self.default
+        } else {
+            self.conditional
+        }
+    }
+}
+
+/// Probing of the targets of `reachable` conditions.
+/// A target only becomes reachable after `rise` successful probes in a row,
+/// and unreachable after `fall` failed ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub rise: u32,
+    pub fall: u32,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+/// DNS-over-TLS listener, disabled unless `listen` is set.
+/// The certificate is shared with the DNS-over-HTTPS listener.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub listen: Option<SocketAddr>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Close connections without any query for this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            cert: None,
+            key: None,
+            idle_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Answer `addresses` for names matching `patterns` while all `conditions` hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub addresses: Vec<IpAddr>,
+    pub patterns: Vec<Pattern>,
+    pub conditions: Vec<Expr>,
+    /// Overrides the TTL given by the config, `ttl=30s`
+    pub ttl: Option<u32>,
+    /// Overrides the order given by the config, `order=round-robin`
+    pub order: Option<Order>,
+    /// Probe every address, only answering the healthy ones, `health=tcp:443`
+    pub health: Option<Check>,
+}
+
+/// Order of the addresses of a rule in answers
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Order {
+    /// As listed
+    #[default]
+    Fixed,
+    /// Rotated by one for every answer
+    RoundRobin,
+    /// Shuffled for every answer
+    Shuffle,
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Order::Fixed => write!(f, "fixed"),
+            Order::RoundRobin => write!(f, "round-robin"),
+            Order::Shuffle => write!(f, "shuffle"),
+        }
+    }
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Order::Fixed),
+            "round-robin" => Ok(Order::RoundRobin),
+            "shuffle" => Ok(Order::Shuffle),
+            _ => Err(format!(
+                "invalid order {:?}, expected fixed, round-robin or shuffle",
+                s
+            )),
+        }
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, name: &str, state: &NetworkState) -> bool {
+        self.patterns.iter().any(|p| p.matches(name))
+            && self.conditions.iter().all(|c| c.eval(state))
+    }
+}
+
+/// DNS-over-HTTPS listeners, plain HTTP is meant to sit behind a reverse proxy
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DohConfig {
+    pub https: Option<SocketAddr>,
+    pub http: Option<SocketAddr>,
+    /// Proxies trusted to tell the client in `Forwarded` or `X-Forwarded-For`
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addresses: Vec<String> = self.addresses.iter().map(|a| a.to_string()).collect();
+        write!(f, "{}", addresses.join(","))?;
+        for pattern in &self.patterns {
+            write!(f, " {}", pattern)?;
+        }
+        for condition in &self.conditions {
+            write!(f, ", {}", condition)?;
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, ", ttl={}", ttl)?;
+        }
+        if let Some(order) = self.order {
+            write!(f, ", order={}", order)?;
+        }
+        if let Some(health) = &self.health {
+            write!(f, ", health={}", health)?;
+        }
+        Ok(())
+    }
+}
+
+/// Answer a record of `r#type` with `data` for names matching `pattern` while all `conditions` hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordRule {
+    pub pattern: Pattern,
+    pub r#type: DnsType,
+    /// Character-strings of a TXT record, or the single data of other records
+    pub data: Vec<DnsRData>,
+    pub conditions: Vec<Expr>,
+    /// Overrides the TTL given by the config, `ttl=30s`
+    pub ttl: Option<u32>,
+}
+
+impl RecordRule {
+    pub fn matches(&self, name: &str, state: &NetworkState) -> bool {
+        self.pattern.matches(name) && self.conditions.iter().all(|c| c.eval(state))
+    }
+}
+
+impl fmt::Display for RecordRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let directive = self.r#type.to_string().to_ascii_lowercase();
+        write!(f, "{} {}", directive, self.pattern)?;
+        for data in &self.data {
+            write!(f, " {}", data)?;
+        }
+        for condition in &self.conditions {
+            write!(f, ", {}", condition)?;
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, ", ttl={}", ttl)?;
+        }
+        Ok(())
+    }
+}
+
+/// Zone served authoritatively, `zone home.example.com ns1.example.com serial 2026101901 ttl 1d`.
+/// Answers within it have the AA bit set, and names without rules do not exist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    /// Lowercase, without the trailing dot
+    pub apex: String,
+    pub nameservers: Vec<String>,
+    pub serial: u32,
+    /// Start of authority of an imported zone file, served as it was written
+    pub soa: Option<Soa>,
+    /// TTL of the start of authority and name servers at the apex, `ttl` of the config if unset
+    pub ttl: Option<u32>,
+}
+
+impl Zone {
+    pub fn contains(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        name.strip_suffix(&self.apex)
+            .is_some_and(|sub| sub.is_empty() || sub.ends_with('.'))
+    }
+
+    /// Start of authority of the zone, negative answers are cached for `minimum`
+    /// unless the zone file said otherwise
+    pub fn soa(&self, minimum: u32) -> Soa {
+        if let Some(soa) = &self.soa {
+            return soa.clone();
+        }
+        Soa {
+            mname: self.nameservers[0].clone(),
+            rname: format!("hostmaster.{}.", self.apex),
+            serial: self.serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+        }
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "zone {}", self.apex)?;
+        for nameserver in &self.nameservers {
+            write!(f, " {}", nameserver)?;
+        }
+        write!(f, " serial {}", self.serial)?;
+        if let Some(ttl) = self.ttl {
+            write!(f, " ttl {}", ttl)?;
+        }
+        Ok(())
+    }
+}
+
+/// Query log written as JSON lines into `dir`, disabled unless it is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryLogConfig {
+    pub dir: Option<PathBuf>,
+    pub rotation: Rotation,
+    /// Rotated files to keep, all of them are kept if unset
+    pub keep: Option<usize>,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            rotation: Rotation::DAILY,
+            keep: None,
+        }
+    }
+}
+
+/// Forward queries to `servers` for names matching `patterns` while all `conditions` hold,
+/// an upstream without patterns serves every name.
+/// Servers are tried in order until one of them answers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamRule {
+    pub servers: Vec<Upstream>,
+    pub patterns: Vec<Pattern>,
+    pub conditions: Vec<Expr>,
+}
+
+impl UpstreamRule {
+    /// `name` is `None` for queries that could not be parsed,
+    /// only upstreams without patterns match them
+    pub fn matches(&self, name: Option<&str>, state: &NetworkState) -> bool {
+        let name_matched = match name {
+            _ if self.patterns.is_empty() => true,
+            Some(name) => self.patterns.iter().any(|p| p.matches(name)),
+            None => false,
+        };
+        name_matched && self.conditions.iter().all(|c| c.eval(state))
+    }
+}
+
+impl fmt::Display for UpstreamRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upstream")?;
+        for server in &self.servers {
+            write!(f, " {}", server)?;
+        }
+        for pattern in &self.patterns {
+            write!(f, " {}", pattern)?;
+        }
+        for condition in &self.conditions {
+            write!(f, ", {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Domain name pattern, either an exact name or a `*.` wildcard matching any subdomain
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern(String);
+
+impl Pattern {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        match self.0.strip_prefix("*.") {
+            Some(suffix) => name
+                .strip_suffix(suffix)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => name == self.0,
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim_end_matches('.').to_ascii_lowercase();
+        let name = pattern.strip_prefix("*.").unwrap_or(&pattern);
+        if name.is_empty() || name.contains('*') {
+            return Err(format!("invalid pattern {:?}", s));
+        }
+        Ok(Self(pattern))
+    }
+}
+
+/// TTL in seconds from a duration
+pub fn parse_ttl(s: &str) -> Result<u32, String> {
+    u32::try_from(parse_duration(s)?.as_secs()).map_err(|_| format!("invalid ttl {:?}", s))
+}
+
+/// Parse durations like `500ms`, `10s`, `5m`, `1h` or `1d`, plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = || format!("invalid duration {:?}", s);
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| err())?;
+    let secs = |unit: u64| {
+        value
+            .checked_mul(unit)
+            .map(Duration::from_secs)
+            .ok_or_else(err)
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => secs(1),
+        "m" => secs(60),
+        "h" => secs(3600),
+        "d" => secs(86400),
+        _ => Err(err()),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// `file` is `None` when parsed from a string
+    Syntax {
+        file: Option<PathBuf>,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "failed to read config: {}", e),
+            ConfigError::Syntax {
+                file: Some(file),
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", file.display(), line, message),
+            ConfigError::Syntax {
+                file: None,
+                line,
+                message,
+            } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(value: io::Error) -> Self {
+        ConfigError::Io(value)
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let mut loader = Loader::default();
+        loader.file(path.as_ref(), 0)?;
+        Ok(loader.finish())
+    }
+
+    /// Outcome of every time and day condition, it changes whenever a schedule flips
+    pub fn schedule(&self, state: &NetworkState) -> Vec<bool> {
+        let rules = self.rules.iter().flat_map(|r| &r.conditions);
+        let upstreams = self.upstreams.iter().flat_map(|u| &u.conditions);
+        rules
+            .chain(upstreams)
+            .flat_map(|e| e.conditions())
+            .filter(|c| c.is_scheduled())
+            .map(|c| c.eval(state))
+            .collect()
+    }
+
+    /// Names of the client groups `client` belongs to
+    pub fn client_groups_of(&self, client: IpAddr) -> Vec<String> {
+        let mut groups: Vec<String> = self
+            .client_groups
+            .iter()
+            .filter(|(_, networks)| networks.iter().any(|n| n.contains(&client)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    /// TTL of answers given by `rule`
+    pub fn answer_ttl(&self, rule: &Rule) -> u32 {
+        rule.ttl.unwrap_or_else(|| self.ttl.of(&rule.conditions))
+    }
+
+    /// TTL of the record given by `record`
+    pub fn record_ttl(&self, record: &RecordRule) -> u32 {
+        record
+            .ttl
+            .unwrap_or_else(|| self.ttl.of(&record.conditions))
+    }
+
+    /// Order of the addresses of `rule` in answers
+    pub fn address_order(&self, rule: &Rule) -> Order {
+        rule.order.unwrap_or(self.address_order)
+    }
+
+    /// The innermost zone containing `name`
+    pub fn zone_of(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|z| z.contains(name))
+            .max_by_key(|z| z.apex.len())
+    }
+
+    /// The active blocklist blocking `name`, unless it is allowed
+    pub fn blocked(&self, name: &str, state: &NetworkState) -> Option<&Blocklist> {
+        if self.allowlist.contains(name) {
+            return None;
+        }
+        self.blocklists.iter().find(|b| b.blocks(name, state))
+    }
+
+    /// Targets of the `reachable` conditions, each one once
+    pub fn probe_targets(&self) -> Vec<Target> {
+        let rules = self.rules.iter().flat_map(|r| &r.conditions);
+        let upstreams = self.upstreams.iter().flat_map(|u| &u.conditions);
+        let records = self.records.iter().flat_map(|r| &r.conditions);
+        let blocklists = self.blocklists.iter().flat_map(|b| &b.conditions);
+        let mut targets = Vec::new();
+        let conditions = rules.chain(records).chain(upstreams).chain(blocklists);
+        for condition in conditions.flat_map(|e| e.conditions()) {
+            if let Condition::Reachable(target) = condition {
+                if !targets.contains(target) {
+                    targets.push(target.clone());
+                }
+            }
+        }
+        for rule in &self.rules {
+            let Some(check) = &rule.health else {
+                continue;
+            };
+            for address in &rule.addresses {
+                let target = check.target(*address);
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+        targets
+    }
+
+    /// Split `line` into the part before the first comma and the conditions after it
+    fn split_conditions<'a>(&self, line: &'a str) -> Result<(&'a str, Vec<Expr>), String> {
+        let mut fields = split_unquoted(line, ',').into_iter();
+        let head = fields.next().unwrap_or_default();
+        let conditions = fields
+            .map(|c| c.trim().parse())
+            .collect::<Result<Vec<Expr>, _>>()?;
+        for condition in conditions.iter().flat_map(|e| e.conditions()) {
+            if let Condition::ClientGroup(group) = condition {
+                if !self.client_groups.contains_key(group) {
+                    return Err(format!("unknown client group {:?}", group));
+                }
+            }
+        }
+        Ok((head, conditions))
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = strip_comment(line);
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        // the addresses of host rules are separated by commas too, without spaces
+        let trimmed = line.trim_start();
+        let (first, rest) = trimmed
+            .split_once(char::is_whitespace)
+            .unwrap_or((trimmed, ""));
+        let addresses = first
+            .split(',')
+            .map(|a| a.parse::<IpAddr>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok();
+        let line = if addresses.is_some() { rest } else { line };
+
+        // `ttl=`, `order=` and `health=` are options of host rules rather than conditions
+        let mut options = Vec::new();
+        let fields: Vec<&str> = split_unquoted(line, ',')
+            .into_iter()
+            .filter(|f| match f.trim().split_once('=') {
+                Some((option @ ("ttl" | "order" | "health"), value)) => {
+                    options.push((option, value));
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        let line = fields.join(",");
+        let (head, conditions) = self.split_conditions(&line)?;
+        let mut words = head.split_whitespace();
+
+        if let Some(addresses) = addresses {
+            let patterns = words
+                .map(|w| w.parse())
+                .collect::<Result<Vec<Pattern>, _>>()?;
+            if patterns.is_empty() {
+                return Err(format!("no pattern for address {}", first));
+            }
+            let mut rule = Rule {
+                addresses,
+                patterns,
+                conditions,
+                ttl: None,
+                order: None,
+                health: None,
+            };
+            for (option, value) in options {
+                match option {
+                    "ttl" => rule.ttl = Some(parse_ttl(value)?),
+                    "order" => rule.order = Some(value.parse()?),
+                    _ => rule.health = Some(value.parse()?),
+                }
+            }
+            self.rules.push(rule);
+            return Ok(());
+        }
+        let first = words.next().ok_or("missing address")?;
+        // types without a directive of their own are written like `type65280` in zone files
+        let generic = first
+            .strip_prefix("type")
+            .is_some_and(|n| n.parse::<u16>().is_ok());
+        if generic
+            || matches!(
+                first,
+                "txt" | "mx" | "srv" | "svcb" | "https" | "cname" | "ptr"
+            )
+        {
+            let mut ttl = None;
+            for (option, value) in options {
+                match option {
+                    "ttl" => ttl = Some(parse_ttl(value)?),
+                    _ => return Err(format!("{}= only applies to host rules", option)),
+                }
+            }
+            let name = words
+                .next()
+                .ok_or_else(|| format!("missing name for {}", first))?;
+            let (r#type, data) = match first {
+                "txt" => {
+                    // the strings may contain spaces, so they are read from the rest of the line
+                    let rest = &head.trim_start()[first.len()..].trim_start()[name.len()..];
+                    let data = parse_character_strings(rest)?
+                        .iter()
+                        .flat_map(|s| DnsRData::txt(s))
+                        .collect();
+                    (DnsType::TXT, data)
+                }
+                "mx" => match words.collect::<Vec<_>>()[..] {
+                    [preference, exchange] => {
+                        let mx = DnsRData::Mx {
+                            preference: parse_number(preference, "preference")?,
+                            exchange: fqdn(exchange),
+                        };
+                        (DnsType::MX, vec![mx])
+                    }
+                    _ => return Err("mx takes a name, a preference and a host".to_string()),
+                },
+                "svcb" | "https" => {
+                    let (priority, target) = match (words.next(), words.next()) {
+                        (Some(priority), Some(target)) => (priority, target),
+                        _ => {
+                            return Err(format!(
+                                "{} takes a name, a priority, a target and parameters",
+                                first
+                            ))
+                        }
+                    };
+                    let params = words
+                        .map(|w| w.parse())
+                        .collect::<Result<Vec<SvcParam>, _>>()?;
+                    let priority = parse_number(priority, "priority")?;
+                    let svcb = Svcb::new(priority, fqdn(target), params)?;
+                    (first.parse()?, vec![DnsRData::Svcb(svcb)])
+                }
+                "srv" => match words.collect::<Vec<_>>()[..] {
+                    [priority, weight, port, target] => {
+                        let srv = DnsRData::Srv {
+                            priority: parse_number(priority, "priority")?,
+                            weight: parse_number(weight, "weight")?,
+                            port: parse_number(port, "port")?,
+                            target: fqdn(target),
+                        };
+                        (DnsType::SRV, vec![srv])
+                    }
+                    _ => {
+                        return Err(
+                            "srv takes a name, a priority, a weight, a port and a target"
+                                .to_string(),
+                        )
+                    }
+                },
+                _ => {
+                    let r#type: DnsType = first.parse()?;
+                    if generic && !matches!(r#type, DnsType::Other(_)) {
+                        return Err(format!("{} is written {}", first, r#type));
+                    }
+                    let fields: Vec<&str> = words.collect();
+                    let data = crate::zonefile::rdata(r#type, &fields, Some("."))
+                        .map_err(|e| format!("invalid {} record: {}", r#type, e))?;
+                    (r#type, data)
+                }
+            };
+            self.records.push(RecordRule {
+                pattern: name.parse()?,
+                r#type,
+                data,
+                conditions,
+                ttl,
+            });
+            return Ok(());
+        }
+        if let Some((option, _)) = options.first() {
+            return Err(format!("{}= only applies to host rules", option));
+        }
+
+        match first {
+            "upstream" => {
+                let mut words = words.peekable();
+                let mut servers = Vec::new();
+                while let Some(word) = words.peek() {
+                    match word.parse::<Upstream>() {
+                        Ok(server) => servers.push(server),
+                        Err(e) if word.contains("://") => return Err(e),
+                        Err(_) => break,
+                    }
+                    words.next();
+                }
+                if servers.is_empty() {
+                    return Err("missing upstream server".to_string());
+                }
+                self.upstreams.push(UpstreamRule {
+                    servers,
+                    patterns: words
+                        .map(|w| w.parse())
+                        .collect::<Result<Vec<Pattern>, _>>()?,
+                    conditions,
+                });
+            }
+            "zone" => {
+                let apex = words.next().ok_or("missing zone apex")?;
+                let apex: Pattern = apex.parse()?;
+                if apex.0.starts_with("*.") {
+                    return Err(format!("invalid zone apex {:?}", apex.0));
+                }
+                let mut nameservers = Vec::new();
+                let mut serial = 1;
+                let mut ttl = None;
+                while let Some(word) = words.next() {
+                    if word == "serial" {
+                        let value = words.next().ok_or("missing serial")?;
+                        serial = value
+                            .parse()
+                            .map_err(|_| format!("invalid serial {:?}", value))?;
+                    } else if word == "ttl" {
+                        ttl = Some(parse_ttl(words.next().ok_or("missing ttl")?)?);
+                    } else {
+                        nameservers.push(fqdn(word));
+                    }
+                }
+                if nameservers.is_empty() || !conditions.is_empty() {
+                    return Err("zone takes an apex, name servers and a serial".to_string());
+                }
+                if self.zones.iter().any(|z| z.apex == apex.0) {
+                    return Err(format!("duplicate zone {}", apex));
+                }
+                self.zones.push(Zone {
+                    apex: apex.0,
+                    nameservers,
+                    serial,
+                    soa: None,
+                    ttl,
+                });
+            }
+            "client-group" => {
+                let name = words.next().ok_or("missing client group name")?;
+                if name.parse::<Cidr>().is_ok() {
+                    return Err(format!("client group name {:?} is a network", name));
+                }
+                let networks = words.map(|w| w.parse()).collect::<Result<Vec<Cidr>, _>>()?;
+                if networks.is_empty() || !conditions.is_empty() {
+                    return Err("client-group takes a name and networks".to_string());
+                }
+                self.client_groups
+                    .entry(name.to_string())
+                    .or_default()
+                    .extend(networks);
+            }
+            "http-trusted-proxy" => {
+                let networks = words.map(|w| w.parse()).collect::<Result<Vec<Cidr>, _>>()?;
+                if networks.is_empty() || !conditions.is_empty() {
+                    return Err("http-trusted-proxy takes networks".to_string());
+                }
+                self.doh.trusted_proxies.extend(networks);
+            }
+            "tls-listen" | "tls-cert" | "tls-key" | "tls-idle-timeout" | "https-listen"
+            | "http-listen" | "upstream-ca" | "query-log" | "query-log-rotation"
+            | "query-log-keep" | "metrics-listen" | "cache-size" | "control-socket"
+            | "probe-interval" | "probe-timeout" | "probe-rise" | "probe-fall"
+            | "block-response" | "ttl" | "conditional-ttl" | "negative-ttl" | "address-order"
+            | "health-fail-open" | "forwarding" => {
+                let value = words
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", first))?;
+                if words.next().is_some() || !conditions.is_empty() {
+                    return Err(format!("{} takes a single value", first));
+                }
+                let listen = || {
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid listen address {:?}", value))
+                };
+                match first {
+                    "tls-listen" => self.tls.listen = Some(listen()?),
+                    "https-listen" => self.doh.https = Some(listen()?),
+                    "http-listen" => self.doh.http = Some(listen()?),
+                    "metrics-listen" => self.metrics_listen = Some(listen()?),
+                    "tls-cert" => self.tls.cert = Some(value.into()),
+                    "tls-key" => self.tls.key = Some(value.into()),
+                    "upstream-ca" => self.upstream_ca = Some(value.into()),
+                    "control-socket" => self.control_socket = value.into(),
+                    "query-log" => self.query_log.dir = Some(value.into()),
+                    "query-log-rotation" => {
+                        self.query_log.rotation = match value {
+                            "minutely" => Rotation::MINUTELY,
+                            "hourly" => Rotation::HOURLY,
+                            "daily" => Rotation::DAILY,
+                            "never" => Rotation::NEVER,
+                            _ => return Err(format!("invalid rotation {:?}", value)),
+                        }
+                    }
+                    "query-log-keep" => {
+                        let keep = value
+                            .parse()
+                            .map_err(|_| format!("invalid number of files {:?}", value))?;
+                        self.query_log.keep = Some(keep);
+                    }
+                    "cache-size" => {
+                        self.cache_size = value
+                            .parse()
+                            .map_err(|_| format!("invalid cache size {:?}", value))?;
+                    }
+                    "block-response" => self.block_response = value.parse()?,
+                    "address-order" => self.address_order = value.parse()?,
+                    "health-fail-open" => self.health_fail_open = parse_switch(value)?,
+                    "forwarding" => self.forwarding = parse_switch(value)?,
+                    "ttl" | "conditional-ttl" | "negative-ttl" => {
+                        let ttl = parse_ttl(value)?;
+                        match first {
+                            "ttl" => self.ttl.default = ttl,
+                            "conditional-ttl" => self.ttl.conditional = ttl,
+                            _ => self.ttl.negative = ttl,
+                        }
+                    }
+                    "probe-interval" => self.probe.interval = parse_duration(value)?,
+                    "probe-timeout" => self.probe.timeout = parse_duration(value)?,
+                    "probe-rise" | "probe-fall" => {
+                        let count = value
+                            .parse()
+                            .ok()
+                            .filter(|c| *c > 0)
+                            .ok_or_else(|| format!("invalid number of probes {:?}", value))?;
+                        if first == "probe-rise" {
+                            self.probe.rise = count;
+                        } else {
+                            self.probe.fall = count;
+                        }
+                    }
+                    _ => self.tls.idle_timeout = parse_duration(value)?,
+                }
+            }
+            _ => return Err(format!("unknown directive {:?}", first)),
+        }
+        Ok(())
+    }
+}
+
+/// Reads a config spread over included files.
+/// Rules and upstreams are matched by descending priority, then in the order they were read.
+#[derive(Default)]
+struct Loader {
+    config: Config,
+    rule_priorities: Vec<i32>,
+    upstream_priorities: Vec<i32>,
+    /// Canonical paths of the files being read, to detect include cycles
+    stack: Vec<PathBuf>,
+}
+
+impl Loader {
+    fn file(&mut self, path: &Path, priority: i32) -> Result<(), ConfigError> {
+        let source = fs::read_to_string(path)?;
+        self.config.sources.push(path.to_path_buf());
+        self.stack.push(fs::canonicalize(path)?);
+        let result = self.source(&source, Some(path), priority);
+        self.stack.pop();
+        result
+    }
+
+    fn source(
+        &mut self,
+        source: &str,
+        file: Option<&Path>,
+        priority: i32,
+    ) -> Result<(), ConfigError> {
+        for (idx, line) in source.lines().enumerate() {
+            let syntax = |message| ConfigError::Syntax {
+                file: file.map(Path::to_path_buf),
+                line: idx + 1,
+                message,
+            };
+            let mut words = strip_comment(line).split_whitespace();
+            // relative to the including file
+            let base = file.and_then(Path::parent).unwrap_or(Path::new(""));
+            match words.next() {
+                Some("include") => {}
+                Some(directive @ ("blocklist" | "allowlist")) => {
+                    self.list(directive, line, base).map_err(syntax)?;
+                    continue;
+                }
+                Some("remote") => {
+                    let mut remote: Remote = strip_comment(line)
+                        .trim_start()
+                        .trim_start_matches("remote")
+                        .parse()
+                        .map_err(syntax)?;
+                    remote.path = base.join(&remote.path);
+                    // without a cached copy the rules it provides would be missing
+                    crate::remote::ensure(&remote).map_err(syntax)?;
+                    self.config.remotes.push(remote);
+                    continue;
+                }
+                Some("zone-file") => {
+                    let (path, origin) =
+                        crate::zonefile::parse_directive(strip_comment(line)).map_err(syntax)?;
+                    let path = base.join(path);
+                    self.zone_file(&path, origin, priority).map_err(syntax)?;
+                    continue;
+                }
+                Some("hosts-file") => {
+                    let path = match (words.next(), words.next()) {
+                        (Some(path), None) => base.join(path),
+                        _ => return Err(syntax("hosts-file takes a single path".to_string())),
+                    };
+                    self.hosts_file(&path)
+                        .map_err(|e| syntax(format!("failed to read {}: {}", path.display(), e)))?;
+                    continue;
+                }
+                _ => {
+                    self.config.parse_line(line).map_err(syntax)?;
+                    self.rule_priorities
+                        .resize(self.config.rules.len(), priority);
+                    self.upstream_priorities
+                        .resize(self.config.upstreams.len(), priority);
+                    continue;
+                }
+            }
+
+            let (pattern, priority) = parse_include(words, priority).map_err(syntax)?;
+            for path in self.expand(&base.join(pattern)).map_err(syntax)? {
+                let canonical = fs::canonicalize(&path)
+                    .map_err(|e| syntax(format!("failed to read {}: {}", path.display(), e)))?;
+                if let Some(start) = self.stack.iter().position(|p| *p == canonical) {
+                    let cycle: Vec<String> = self.stack[start..]
+                        .iter()
+                        .chain([&canonical])
+                        .map(|p| p.display().to_string())
+                        .collect();
+                    return Err(syntax(format!("include cycle {}", cycle.join(" -> "))));
+                }
+                self.file(&path, priority).map_err(|e| match e {
+                    ConfigError::Io(e) => {
+                        syntax(format!("failed to read {}: {}", path.display(), e))
+                    }
+                    e => e,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unconditional rules from a file in the `/etc/hosts` format, after every smart hosts rule
+    fn hosts_file(&mut self, path: &Path) -> io::Result<()> {
+        let source = fs::read_to_string(path)?;
+        self.config.sources.push(path.to_path_buf());
+        // addresses of a name are answered together, like `127.0.0.1` and `::1` of localhost
+        let mut rules: Vec<Rule> = Vec::new();
+        for (address, names) in crate::hosts::entries(&source) {
+            for pattern in names.iter().filter_map(|n| n.parse::<Pattern>().ok()) {
+                match rules.iter_mut().find(|r| r.patterns == [pattern.clone()]) {
+                    Some(rule) if rule.addresses.contains(&address) => {}
+                    Some(rule) => rule.addresses.push(address),
+                    None => rules.push(Rule {
+                        addresses: vec![address],
+                        patterns: vec![pattern],
+                        conditions: Vec::new(),
+                        ttl: None,
+                        order: None,
+                        health: None,
+                    }),
+                }
+            }
+        }
+        self.rule_priorities.extend(rules.iter().map(|_| i32::MIN));
+        self.config.rules.extend(rules);
+        Ok(())
+    }
+
+    /// Unconditional rules and records from a zone file, a start of authority makes it a zone
+    fn zone_file(
+        &mut self,
+        path: &Path,
+        origin: Option<&str>,
+        priority: i32,
+    ) -> Result<(), String> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        self.config.sources.push(path.to_path_buf());
+        let entries = crate::zonefile::entries(&source, origin)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        // addresses of a name are answered together, as a single rule
+        let mut rules: Vec<Rule> = Vec::new();
+        for entry in &entries {
+            let pattern: Pattern = entry.name.parse()?;
+            let address: IpAddr = match entry.data[..] {
+                [DnsRData::IP(ip)] => ip.into(),
+                [DnsRData::IPv6(ip)] => ip.into(),
+                [DnsRData::Soa(ref soa)] => {
+                    let apex = entry.name.trim_end_matches('.').to_string();
+                    let mut nameservers: Vec<String> = entries
+                        .iter()
+                        .filter(|e| e.name == entry.name)
+                        .filter_map(|e| match &e.data[..] {
+                            [DnsRData::Ns(ns)] => Some(ns.clone()),
+                            _ => None,
+                        })
+                        .collect();
+                    if nameservers.is_empty() {
+                        nameservers.push(soa.mname.clone());
+                    }
+                    if self.config.zones.iter().any(|z| z.apex == apex) {
+                        return Err(format!("{}: duplicate zone {}", path.display(), apex));
+                    }
+                    self.config.zones.push(Zone {
+                        apex,
+                        nameservers,
+                        serial: soa.serial,
+                        soa: Some(soa.clone()),
+                        // as written for the start of authority, or else for the name servers
+                        ttl: entry.ttl.or_else(|| {
+                            entries
+                                .iter()
+                                .find(|e| e.name == entry.name && e.r#type == DnsType::NS)
+                                .and_then(|e| e.ttl)
+                        }),
+                    });
+                    continue;
+                }
+                // name servers of the zone itself
+                [DnsRData::Ns(_)]
+                    if entries.iter().any(|e| {
+                        e.name == entry.name && matches!(e.data[..], [DnsRData::Soa(_)])
+                    }) =>
+                {
+                    continue
+                }
+                // names below would not exist rather than be asked to their servers
+                [DnsRData::Ns(_)] => {
+                    return Err(format!(
+                        "{}: NS records of {}, delegations are not supported",
+                        path.display(),
+                        entry.name
+                    ))
+                }
+                _ => {
+                    self.config.records.push(RecordRule {
+                        pattern,
+                        r#type: entry.r#type,
+                        data: entry.data.clone(),
+                        conditions: Vec::new(),
+                        ttl: entry.ttl,
+                    });
+                    continue;
+                }
+            };
+            match rules.iter_mut().find(|r| r.patterns == [pattern.clone()]) {
+                Some(rule) => rule.addresses.push(address),
+                None => rules.push(Rule {
+                    addresses: vec![address],
+                    patterns: vec![pattern],
+                    conditions: Vec::new(),
+                    ttl: entry.ttl,
+                    order: None,
+                    health: None,
+                }),
+            }
+        }
+        debug!(?path, rules = rules.len(), "zone file loaded");
+        self.rule_priorities.extend(rules.iter().map(|_| priority));
+        self.config.rules.extend(rules);
+        Ok(())
+    }
+
+    /// `blocklist PATH, CONDITIONS` or `allowlist PATH`
+    fn list(&mut self, directive: &str, line: &str, base: &Path) -> Result<(), String> {
+        let (head, conditions) = self.config.split_conditions(strip_comment(line))?;
+        let path = match head.split_whitespace().collect::<Vec<_>>()[..] {
+            [_, path] => base.join(path),
+            _ => return Err(format!("{} takes a single path", directive)),
+        };
+        let source = fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        self.config.sources.push(path.clone());
+        let (domains, exceptions) = blocklist::parse(&source);
+        debug!(?path, domains = domains.len(), "{} loaded", directive);
+
+        if directive == "allowlist" {
+            if !conditions.is_empty() {
+                return Err("allowlist takes no conditions".to_string());
+            }
+            self.config.allowlist.extend(domains);
+            self.config.allowlist.extend(exceptions);
+        } else {
+            self.config.blocklists.push(Blocklist {
+                path,
+                conditions,
+                domains,
+                exceptions,
+            });
+        }
+        Ok(())
+    }
+
+    /// Files matching `pattern` in lexical order, glob patterns may match none
+    fn expand(&mut self, pattern: &Path) -> Result<Vec<PathBuf>, String> {
+        let s = pattern.to_string_lossy();
+        if !s.contains(['*', '?', '[']) {
+            return Ok(vec![pattern.to_path_buf()]);
+        }
+        // files added later show up as a change of the directory
+        if let Some(dir) = pattern
+            .parent()
+            .filter(|d| !d.to_string_lossy().contains('*'))
+        {
+            self.config.sources.push(dir.to_path_buf());
+        }
+        let paths = glob::glob(&s).map_err(|e| format!("invalid pattern {:?}: {}", s, e))?;
+        paths
+            .map(|p| p.map_err(|e| e.to_string()))
+            .filter(|p| !p.as_ref().is_ok_and(|p| p.is_dir()))
+            .collect()
+    }
+
+    fn finish(mut self) -> Config {
+        let rules = mem::take(&mut self.config.rules);
+        let mut rules: Vec<_> = self.rule_priorities.into_iter().zip(rules).collect();
+        rules.sort_by_key(|(priority, _)| Reverse(*priority));
+        self.config.rules = rules.into_iter().map(|(_, r)| r).collect();
+
+        let upstreams = mem::take(&mut self.config.upstreams);
+        let mut upstreams: Vec<_> = self
+            .upstream_priorities
+            .into_iter()
+            .zip(upstreams)
+            .collect();
+        upstreams.sort_by_key(|(priority, _)| Reverse(*priority));
+        self.config.upstreams = upstreams.into_iter().map(|(_, u)| u).collect();
+        self.config
+    }
+}
+
+/// `include PATH [priority N]`, included files inherit `priority` unless given
+fn parse_include<'a>(
+    mut words: impl Iterator<Item = &'a str>,
+    priority: i32,
+) -> Result<(&'a str, i32), String> {
+    let path = words.next().ok_or("missing path to include")?;
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => Ok((path, priority)),
+        (Some("priority"), Some(value), None) => value
+            .parse()
+            .map(|priority| (path, priority))
+            .map_err(|_| format!("invalid priority {:?}", value)),
+        _ => Err("expected include PATH [priority N]".to_string()),
+    }
+}
+
+/// Poll the config at `path`, and the files it includes, every `interval`
+/// and call `on_change` with the config once any of them is modified.
+/// Invalid configs are reported and skipped, leaving it to the caller to keep the previous one.
+pub fn watch(path: &Path, interval: Duration, mut on_change: impl FnMut(Config)) {
+    let modified = |path: &Path| -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    };
+    let fingerprint =
+        |sources: &[PathBuf]| -> Vec<_> { sources.iter().map(|p| modified(p)).collect() };
+    let mut sources = Config::load(path)
+        .map(|config| config.sources)
+        .unwrap_or_else(|_| vec![path.to_path_buf()]);
+    let mut last = fingerprint(&sources);
+    loop {
+        thread::sleep(interval);
+        let current = fingerprint(&sources);
+        if modified(path).is_none() || current == last {
+            continue;
+        }
+        last = current;
+        debug!(?path, "config changed");
+        match Config::load(path) {
+            Ok(config) => {
+                if config.sources != sources {
+                    sources = config.sources.clone();
+                    last = fingerprint(&sources);
+                }
+                on_change(config)
+            }
+            Err(e) => error!(?path, %e, "failed to reload config, keeping the previous one"),
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    /// Includes are relative to the working directory
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut loader = Loader::default();
+        loader.source(s, None, 0)?;
+        Ok(loader.finish())
+    }
+}
+
+pub fn parse_number(s: &str, what: &str) -> Result<u16, String> {
+    s.parse().map_err(|_| format!("invalid {} {:?}", what, s))
+}
+
+/// Fully qualified form of a host name, `.` stays the root
+pub fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+/// Quoted character-strings like `"v=spf1 -all" "more"`,
+/// with `\"`, `\\` and `\DDD` escapes as written by dig
+pub fn parse_character_strings(s: &str) -> Result<Vec<Vec<u8>>, String> {
+    let bytes = s.as_bytes();
+    let mut strings = Vec::new();
+    let mut i = 0;
+    loop {
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        match bytes.get(i) {
+            None => break,
+            Some(b'"') => i += 1,
+            Some(_) => return Err(format!("expected a quoted string at {:?}", &s[i..])),
+        }
+        let mut text = Vec::new();
+        loop {
+            match bytes.get(i) {
+                None => return Err(format!("unterminated string in {:?}", s.trim())),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let digits = bytes
+                        .get(i + 1..i + 4)
+                        .filter(|d| d.iter().all(u8::is_ascii_digit));
+                    if let Some(digits) = digits {
+                        let digits = std::str::from_utf8(digits).unwrap();
+                        text.push(
+                            digits
+                                .parse()
+                                .map_err(|_| format!("invalid escape \\{}", digits))?,
+                        );
+                        i += 3;
+                    } else {
+                        let c = bytes
+                            .get(i + 1)
+                            .ok_or_else(|| format!("unterminated string in {:?}", s.trim()))?;
+                        text.push(*c);
+                        i += 1;
+                    }
+                }
+                Some(c) => text.push(*c),
+            }
+            i += 1;
+        }
+        i += 1;
+        strings.push(text);
+    }
+    if strings.is_empty() {
+        return Err("missing text".to_string());
+    }
+    Ok(strings)
+}
+
+/// Comments start with `#` at the beginning of a line or after whitespace,
+/// so it can still be used inside values like `tls://1.1.1.1#cloudflare-dns.com`
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut prev = ' ';
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted && prev.is_whitespace() => return &line[..idx],
+            _ => {}
+        }
+        prev = c;
+    }
+    line
+}
+
+/// Split on `sep` outside of quotes, brackets and parentheses,
+/// so value lists like `ssid in ["home", "home-5g"]` stay in one piece
+pub fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut parts = Vec::new();
+    for (idx, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '(' | '[' if !quoted => depth += 1,
+            ')' | ']' if !quoted => depth = depth.saturating_sub(1),
+            _ if c == sep && !quoted && depth == 0 => {
+                parts.push(&s[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern() {
+        let p: Pattern = "*.home.local".parse().unwrap();
+        assert!(p.matches("nas.home.local."));
+        assert!(p.matches("a.b.HOME.local"));
+        assert!(!p.matches("home.local."));
+        assert!(!p.matches("nashome.local."));
+
+        let p: Pattern = "router.home.local.".parse().unwrap();
+        assert!(p.matches("router.home.local."));
+        assert!(!p.matches("nas.home.local."));
+
+        assert!("*".parse::<Pattern>().is_err());
+        assert!("a.*.local".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn readme_example() {
+        let config: Config = r#"
+127.0.0.1   *.home.local, ssid="home"
+127.0.0.2   *.home.local *.home.wg, ssid="work"
+127.0.0.2   *.home.local *.home.wg, cellular="on"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.rules.len(), 3);
+        assert_eq!(config.rules[1].patterns.len(), 2);
+        assert_eq!(
+            config.rules[2].conditions,
+            vec![Expr::Condition(Condition::Cellular(true))]
+        );
+
+        let state = NetworkState {
+            ssid: Some("work".to_string()),
+            ..Default::default()
+        };
+        let rule = config
+            .rules
+            .iter()
+            .find(|r| r.matches("nas.home.local.", &state))
+            .unwrap();
+        assert_eq!(rule.addresses, vec!["127.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            rule.to_string(),
+            r#"127.0.0.2 *.home.local *.home.wg, ssid="work""#
+        );
+    }
+
+    #[test]
+    fn upstreams() {
+        let config: Config = r#"
+# split DNS for the corporate domain
+upstream 10.0.0.53   *.corp.example, ssid="work"
+upstream [::1]:5353  *.lan
+upstream tls://1.1.1.1#cloudflare-dns.com https://dns.google/dns-query 1.1.1.1 # fallbacks
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.upstreams.len(), 3);
+        assert_eq!(
+            config.upstreams[0].servers,
+            vec![Upstream::Udp("10.0.0.53:53".parse().unwrap())]
+        );
+        assert_eq!(
+            config.upstreams[1].servers,
+            vec![Upstream::Udp("[::1]:5353".parse().unwrap())]
+        );
+        assert_eq!(
+            config.upstreams[2].servers,
+            vec![
+                Upstream::Tls {
+                    host: "1.1.1.1".to_string(),
+                    port: 853,
+                    server_name: "cloudflare-dns.com".to_string()
+                },
+                Upstream::Https("https://dns.google/dns-query".to_string()),
+                Upstream::Udp("1.1.1.1:53".parse().unwrap()),
+            ]
+        );
+
+        let work = NetworkState {
+            ssid: Some("work".to_string()),
+            ..Default::default()
+        };
+        let select = |name, state: &NetworkState| {
+            config.upstreams.iter().position(|u| u.matches(name, state))
+        };
+        assert_eq!(select(Some("git.corp.example."), &work), Some(0));
+        assert_eq!(
+            select(Some("git.corp.example."), &NetworkState::default()),
+            Some(2)
+        );
+        assert_eq!(select(None, &work), Some(2));
+        assert_eq!(
+            config.upstreams[0].to_string(),
+            r#"upstream 10.0.0.53:53 *.corp.example, ssid="work""#
+        );
+    }
+
+    #[test]
+    fn ttls() {
+        let config: Config = r#"
+ttl 1h
+conditional-ttl 30s
+negative-ttl 5m
+192.168.1.10 nas.home.local
+127.0.0.1    *.home.local, ssid="home"
+127.0.0.2    *.home.local, ttl=10, cellular="on"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.ttl,
+            TtlConfig {
+                default: 3600,
+                conditional: 30,
+                negative: 300
+            }
+        );
+        let ttls: Vec<u32> = config.rules.iter().map(|r| config.answer_ttl(r)).collect();
+        assert_eq!(ttls, vec![3600, 30, 10]);
+        assert_eq!(
+            config.rules[2].to_string(),
+            r#"127.0.0.2 *.home.local, cellular="on", ttl=10"#
+        );
+
+        let err = |config: &str| config.parse::<Config>().unwrap_err().to_string();
+        assert_eq!(
+            err("upstream 1.1.1.1, ttl=10"),
+            "line 1: ttl= only applies to host rules"
+        );
+        assert_eq!(
+            err("127.0.0.1 a.local, ttl=soon"),
+            r#"line 1: invalid duration "soon""#
+        );
+    }
+
+    #[test]
+    fn multiple_addresses() {
+        let config: Config = r#"
+address-order shuffle
+10.0.0.2,10.0.0.3,fd00::2   api.svc.local, order=round-robin, ssid="work"
+10.0.0.4,10.0.0.5           db.svc.local
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.rules[0].addresses.len(), 3);
+        assert_eq!(config.address_order(&config.rules[0]), Order::RoundRobin);
+        assert_eq!(config.address_order(&config.rules[1]), Order::Shuffle);
+        assert_eq!(
+            config.rules[0].to_string(),
+            r#"10.0.0.2,10.0.0.3,fd00::2 api.svc.local, ssid="work", order=round-robin"#
+        );
+
+        let err = |config: &str| config.parse::<Config>().unwrap_err().to_string();
+        assert_eq!(
+            err("10.0.0.2,10.0.0.3"),
+            "line 1: no pattern for address 10.0.0.2,10.0.0.3"
+        );
+        assert_eq!(
+            err("10.0.0.2 a.local, order=random"),
+            r#"line 1: invalid order "random", expected fixed, round-robin or shuffle"#
+        );
+        assert_eq!(
+            err("upstream 1.1.1.1, order=fixed"),
+            "line 1: order= only applies to host rules"
+        );
+    }
+
+    #[test]
+    fn records() {
+        let config: Config = r#"
+txt home.local "v=spf1 -all" "say \"hi\", # not a comment", ssid="home", ttl=5m
+mx  home.local 10 mail.home.local
+srv _sip._tcp.home.local 10 5 5060 sip.home.local.
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.records.len(), 3);
+        assert_eq!(config.records[0].r#type, DnsType::TXT);
+        assert_eq!(
+            config.records[0].data[1],
+            DnsRData::Txt(br#"say "hi", # not a comment"#.to_vec())
+        );
+        assert_eq!(config.record_ttl(&config.records[0]), 300);
+        assert_eq!(config.record_ttl(&config.records[1]), 3600);
+        let lines: Vec<String> = config.records.iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"txt home.local "v=spf1 -all" "say \"hi\", # not a comment", ssid="home", ttl=300"#,
+                "mx home.local 10 mail.home.local.",
+                "srv _sip._tcp.home.local 10 5 5060 sip.home.local.",
+            ]
+        );
+        // displayed records parse back to the same
+        let reparsed: Config = lines.join("\n").parse().unwrap();
+        assert_eq!(reparsed.records, config.records);
+
+        let config: Config = r#"
+https home.local 1 . port=8443 alpn="h2,h3" ipv4hint=192.168.1.10
+svcb _dns.home.local 0 dns.home.local
+        "#
+        .parse()
+        .unwrap();
+        let lines: Vec<String> = config.records.iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"https home.local 1 . alpn="h2,h3" port=8443 ipv4hint=192.168.1.10"#,
+                "svcb _dns.home.local 0 dns.home.local.",
+            ]
+        );
+        let reparsed: Config = lines.join("\n").parse().unwrap();
+        assert_eq!(reparsed.records, config.records);
+
+        let config: Config = r#"
+cname tv.home.local nas.home.local
+ptr 10.1.168.192.in-addr.arpa nas.home.local.
+type65280 app.home.local \# 3 0a0001, ttl=1m
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.records[0].data,
+            [DnsRData::Cname("nas.home.local.".to_string())]
+        );
+        let lines: Vec<String> = config.records.iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "cname tv.home.local nas.home.local.",
+                "ptr 10.1.168.192.in-addr.arpa nas.home.local.",
+                r"type65280 app.home.local \# 3 0a0001, ttl=60",
+            ]
+        );
+        let reparsed: Config = lines.join("\n").parse().unwrap();
+        assert_eq!(reparsed.records, config.records);
+
+        let err = |config: &str| config.parse::<Config>().unwrap_err().to_string();
+        assert_eq!(
+            err("cname tv.home.local"),
+            r#"line 1: invalid CNAME record: unexpected data """#
+        );
+        assert_eq!(
+            err("type1 a.home.local 10.0.0.1"),
+            "line 1: type1 is written A"
+        );
+        assert_eq!(
+            err("txt home.local v=spf1"),
+            r#"line 1: expected a quoted string at "v=spf1""#
+        );
+        assert_eq!(err("txt home.local"), "line 1: missing text");
+        assert_eq!(
+            err("mx home.local mail.home.local"),
+            "line 1: mx takes a name, a preference and a host"
+        );
+        assert_eq!(
+            err("srv _sip._tcp.home.local 10 5 99999 sip.home.local"),
+            r#"line 1: invalid port "99999""#
+        );
+        assert_eq!(
+            err("https home.local 1 . port=1 port=2"),
+            "line 1: duplicate service parameter port=2"
+        );
+        assert_eq!(
+            err("https home.local 0 www.home.local port=443"),
+            "line 1: the alias form, priority 0, takes no parameters"
+        );
+        assert_eq!(
+            err("mx home.local 10 mail.home.local, order=fixed"),
+            "line 1: order= only applies to host rules"
+        );
+    }
+
+    #[test]
+    fn zones() {
+        let config: Config = "\
+zone Home.Example.com. ns1.example.com ns2.example.com serial 2026101901 ttl 1d
+zone lab.home.example.com ns1.example.com
+forwarding off"
+            .parse()
+            .unwrap();
+        assert!(!config.forwarding);
+        assert_eq!(
+            config.zones[0].to_string(),
+            "zone home.example.com ns1.example.com. ns2.example.com. serial 2026101901 ttl 86400"
+        );
+        assert_eq!(config.zones[1].serial, 1);
+        assert_eq!(config.zones[1].ttl, None);
+        let apex = |name| config.zone_of(name).map(|z| z.apex.as_str());
+        assert_eq!(apex("home.example.com."), Some("home.example.com"));
+        assert_eq!(apex("nas.HOME.example.com."), Some("home.example.com"));
+        assert_eq!(
+            apex("pi.lab.home.example.com."),
+            Some("lab.home.example.com")
+        );
+        assert_eq!(apex("myhome.example.com."), None);
+
+        let err = |config: &str| config.parse::<Config>().unwrap_err().to_string();
+        assert_eq!(
+            err("zone home.example.com serial 2"),
+            "line 1: zone takes an apex, name servers and a serial"
+        );
+        assert_eq!(
+            err("zone home.example.com ns1.example.com serial x"),
+            r#"line 1: invalid serial "x""#
+        );
+        assert_eq!(
+            err("zone home.example.com ns1.example.com ttl"),
+            "line 1: missing ttl"
+        );
+        assert_eq!(
+            err("zone *.example.com ns1.example.com"),
+            r#"line 1: invalid zone apex "*.example.com""#
+        );
+    }
+
+    #[test]
+    fn quoted_values() {
+        let config: Config = r#"10.0.0.1 nas.local, ssid="cafe, #2", cellular="off""#
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.rules[0].conditions,
+            vec![
+                Expr::Condition(Condition::Ssid("cafe, #2".to_string())),
+                Expr::Condition(Condition::Cellular(false))
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        let config: Config = r#"127.0.0.2 *.home.local, (ssid in ["work", "work-5g"] or cellular="on"), not ssid="cafe""#
+            .parse()
+            .unwrap();
+        assert_eq!(config.rules[0].conditions.len(), 2);
+        let cellular = NetworkState {
+            cellular: true,
+            ..Default::default()
+        };
+        assert!(config.rules[0].matches("nas.home.local.", &cellular));
+        assert!(!config.rules[0].matches("nas.home.local.", &NetworkState::default()));
+        assert_eq!(
+            config.rules[0].to_string(),
+            r#"127.0.0.2 *.home.local, ssid="work" or ssid="work-5g" or cellular="on", not ssid="cafe""#
+        );
+
+        let err = "127.0.0.1 a.local\n127.0.0.1 a.local, ssid=\"a\" or"
+            .parse::<Config>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected condition, found nothing");
+    }
+
+    #[test]
+    fn schedule() {
+        let config: Config = r#"
+127.0.0.1 *.corp, days="mon-fri UTC" and time="09:00-18:00 UTC"
+upstream 10.0.0.53, ssid="work" or time="09:00-18:00 UTC"
+        "#
+        .parse()
+        .unwrap();
+        let at = |time: &str| NetworkState {
+            time: Some(time.parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(config.rules[0].matches("a.corp.", &at("2024-08-01T10:00:00Z")));
+        assert!(!config.rules[0].matches("a.corp.", &at("2024-08-03T10:00:00Z")));
+        assert_eq!(
+            config.schedule(&at("2024-08-01T10:00:00Z")),
+            vec![true, true, true]
+        );
+        assert_eq!(
+            config.schedule(&at("2024-08-03T10:00:00Z")),
+            vec![false, true, true]
+        );
+        assert_eq!(
+            config.schedule(&at("2024-08-01T18:00:00Z")),
+            vec![true, false, false]
+        );
+
+        let err = "127.0.0.1 a.local, time=\"09:00\""
+            .parse::<Config>()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"line 1: expected time range like "09:00-18:00", found "09:00""#
+        );
+    }
+
+    #[test]
+    fn probes() {
+        let config: Config = r#"
+192.168.1.10 nas.home.local, reachable="tcp://192.168.1.10:445"
+upstream 10.0.0.53, reachable="udp-dns://10.0.0.53" or not reachable="tcp://192.168.1.10:445"
+mx home.local 10 mail.home.local, reachable="tcp://192.168.1.25:25"
+probe-interval 5s
+probe-timeout 500ms
+probe-rise 1
+probe-fall 2
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.probe_targets(),
+            vec![
+                Target::Tcp("192.168.1.10:445".parse().unwrap()),
+                Target::Tcp("192.168.1.25:25".parse().unwrap()),
+                Target::UdpDns("10.0.0.53:53".parse().unwrap()),
+            ]
+        );
+        assert_eq!(
+            config.probe,
+            ProbeConfig {
+                interval: Duration::from_secs(5),
+                timeout: Duration::from_millis(500),
+                rise: 1,
+                fall: 2,
+            }
+        );
+
+        let err = "probe-rise 0".parse::<Config>().unwrap_err();
+        assert_eq!(err.to_string(), r#"line 1: invalid number of probes "0""#);
+    }
+
+    #[test]
+    fn client_groups() {
+        let config: Config = r#"
+client-group trusted 192.168.10.0/24 fd00:10::/64
+client-group guests 192.168.20.0/24
+client-group guests 192.168.21.0/24
+192.168.1.10 nas.home.local, client="trusted"
+203.0.113.10 nas.home.local, client="192.168.20.0/22"
+        "#
+        .parse()
+        .unwrap();
+        let client = |addr: &str| config.client_groups_of(addr.parse().unwrap());
+        assert_eq!(client("192.168.10.7"), vec!["trusted"]);
+        assert_eq!(client("fd00:10::7"), vec!["trusted"]);
+        assert_eq!(client("192.168.21.7"), vec!["guests"]);
+        assert!(client("10.0.0.1").is_empty());
+        assert_eq!(
+            config.rules[1].to_string(),
+            r#"203.0.113.10 nas.home.local, client="192.168.20.0/22""#
+        );
+
+        let err = |s: &str| s.parse::<Config>().unwrap_err().to_string();
+        assert_eq!(
+            err("127.0.0.1 a.local, client=\"lan\"\nclient-group lan 10.0.0.0/8"),
+            r#"line 1: unknown client group "lan""#
+        );
+        assert_eq!(
+            err("client-group lan"),
+            "line 1: client-group takes a name and networks"
+        );
+        assert_eq!(
+            err("client-group lan 10.0.0.0/33"),
+            r#"line 1: invalid network "10.0.0.0/33""#
+        );
+    }
+
+    #[test]
+    fn tls() {
+        let config: Config = r#"
+tls-listen 0.0.0.0:853
+tls-cert /etc/smart_hosts/cert.pem
+tls-key /etc/smart_hosts/key.pem
+tls-idle-timeout 30s
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            config.tls,
+            TlsConfig {
+                listen: Some("0.0.0.0:853".parse().unwrap()),
+                cert: Some("/etc/smart_hosts/cert.pem".into()),
+                key: Some("/etc/smart_hosts/key.pem".into()),
+                idle_timeout: Duration::from_secs(30),
+            }
+        );
+
+        let config: Config = "https-listen [::]:443\nhttp-listen 127.0.0.1:8053"
+            .parse()
+            .unwrap();
+        assert_eq!(config.doh.https, Some("[::]:443".parse().unwrap()));
+        assert_eq!(config.doh.http, Some("127.0.0.1:8053".parse().unwrap()));
+        assert!(config.doh.trusted_proxies.is_empty());
+        let config: Config = "http-trusted-proxy 127.0.0.1 ::1\nhttp-trusted-proxy 10.0.0.0/8"
+            .parse()
+            .unwrap();
+        let proxies: Vec<String> = config
+            .doh
+            .trusted_proxies
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+        assert_eq!(proxies, ["127.0.0.1/32", "::1/128", "10.0.0.0/8"]);
+        assert!("http-trusted-proxy".parse::<Config>().is_err());
+
+        assert!("tls-listen 853".parse::<Config>().is_err());
+        assert!("tls-idle-timeout 10x".parse::<Config>().is_err());
+        assert!("tls-cert a.pem b.pem".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn query_log() {
+        let config: Config =
+            "query-log /var/log/smart_hosts\nquery-log-rotation hourly\nquery-log-keep 24"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config.query_log,
+            QueryLogConfig {
+                dir: Some("/var/log/smart_hosts".into()),
+                rotation: Rotation::HOURLY,
+                keep: Some(24),
+            }
+        );
+        assert!("query-log-rotation weekly".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        let config: Config = "metrics-listen 127.0.0.1:9153\ncache-size 0"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.metrics_listen,
+            Some("127.0.0.1:9153".parse().unwrap())
+        );
+        assert_eq!(config.cache_size, 0);
+        assert_eq!(Config::default().cache_size, 4096);
+        assert!("cache-size lots".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn control_socket() {
+        let config: Config = "control-socket /run/smart_hosts.sock".parse().unwrap();
+        assert_eq!(
+            config.control_socket,
+            PathBuf::from("/run/smart_hosts.sock")
+        );
+    }
+
+    #[test]
+    fn watching() {
+        let path =
+            std::env::temp_dir().join(format!("smart_hosts-watch-{}.conf", std::process::id()));
+        fs::write(&path, "127.0.0.1 a.local").unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        {
+            let path = path.clone();
+            thread::spawn(move || {
+                watch(&path, Duration::from_millis(50), |config| {
+                    tx.send(config).unwrap()
+                })
+            });
+        }
+        thread::sleep(Duration::from_millis(200));
+        assert!(rx.try_recv().is_err());
+
+        // broken configs are skipped
+        fs::write(&path, "127.0.0.1").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(rx.try_recv().is_err());
+
+        let included = path.with_extension("hosts");
+        fs::write(&included, "127.0.0.3 c.local").unwrap();
+        fs::write(
+            &path,
+            format!(
+                "127.0.0.2 a.local\n127.0.0.2 b.local\ninclude {}",
+                included.display()
+            ),
+        )
+        .unwrap();
+        let config = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(config.rules.len(), 3);
+
+        // included files are watched too
+        fs::write(&included, "127.0.0.3 c.local d.local").unwrap();
+        let config = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(config.rules[2].patterns.len(), 2);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&included).unwrap();
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604800)));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert_eq!(
+            parse_duration("213503982334602d"),
+            Err(r#"invalid duration "213503982334602d""#.to_string())
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let main = dir.join("smart_hosts.conf");
+        fs::write(
+            &main,
+            "127.0.0.1 a.local\n\
+             include team.hosts\n\
+             include conf.d/*.hosts\n\
+             include personal.hosts priority 10\n\
+             upstream 1.1.1.1",
+        )
+        .unwrap();
+        fs::write(dir.join("team.hosts"), "127.0.0.2 a.local b.local").unwrap();
+        fs::write(dir.join("conf.d/20-b.hosts"), "127.0.0.4 d.local").unwrap();
+        fs::write(dir.join("conf.d/10-a.hosts"), "127.0.0.3 c.local").unwrap();
+        fs::write(
+            dir.join("personal.hosts"),
+            "127.0.0.5 b.local\nupstream 9.9.9.9",
+        )
+        .unwrap();
+
+        let config = Config::load(&main).unwrap();
+        let addresses: Vec<String> = config
+            .rules
+            .iter()
+            .map(|r| r.addresses[0].to_string())
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                "127.0.0.5",
+                "127.0.0.1",
+                "127.0.0.2",
+                "127.0.0.3",
+                "127.0.0.4"
+            ]
+        );
+        assert_eq!(config.upstreams[0].to_string(), "upstream 9.9.9.9:53");
+        assert_eq!(
+            config.sources,
+            [
+                main.clone(),
+                dir.join("team.hosts"),
+                dir.join("conf.d"),
+                dir.join("conf.d/10-a.hosts"),
+                dir.join("conf.d/20-b.hosts"),
+                dir.join("personal.hosts"),
+            ]
+        );
+
+        let err = |config: &str| {
+            fs::write(&main, config).unwrap();
+            Config::load(&main).unwrap_err().to_string()
+        };
+        fs::write(
+            dir.join("conf.d/30-c.hosts"),
+            "127.0.0.1 a.local\n127.0.0.6",
+        )
+        .unwrap();
+        assert_eq!(
+            err("include conf.d/*.hosts"),
+            format!(
+                "{}: no pattern for address 127.0.0.6",
+                dir.join("conf.d/30-c.hosts:2").display()
+            )
+        );
+        assert!(err("\ninclude missing.hosts").starts_with(&format!(
+            "{}:2: failed to read {}: ",
+            main.display(),
+            dir.join("missing.hosts").display()
+        )));
+        fs::write(dir.join("conf.d/30-c.hosts"), "include ../smart_hosts.conf").unwrap();
+        assert_eq!(
+            err("include conf.d/30-c.hosts"),
+            format!(
+                "{}:1: include cycle {} -> {} -> {}",
+                dir.join("conf.d/30-c.hosts").display(),
+                main.canonicalize().unwrap().display(),
+                dir.join("conf.d/30-c.hosts")
+                    .canonicalize()
+                    .unwrap()
+                    .display(),
+                main.canonicalize().unwrap().display(),
+            )
+        );
+        assert_eq!(
+            err("include team.hosts priority high"),
+            format!("{}:1: invalid priority \"high\"", main.display())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hosts_file() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-hosts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("smart_hosts.conf");
+        fs::write(
+            &main,
+            "hosts-file hosts\n192.168.1.10 nas.home.local, ssid=\"home\"\n\
+             include personal.hosts priority -10",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("hosts"),
+            "127.0.0.1 localhost\n10.8.0.10 nas.home.local nas  # over the VPN\n\
+             ::1 localhost\n10.8.0.11 nas.home.local",
+        )
+        .unwrap();
+        fs::write(dir.join("personal.hosts"), "127.0.0.2 b.local").unwrap();
+
+        let config = Config::load(&main).unwrap();
+        let rules: Vec<String> = config.rules.iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            rules,
+            [
+                r#"192.168.1.10 nas.home.local, ssid="home""#,
+                "127.0.0.2 b.local",
+                "127.0.0.1,::1 localhost",
+                "10.8.0.10,10.8.0.11 nas.home.local",
+                "10.8.0.10 nas",
+            ]
+        );
+        assert!(config.sources.contains(&dir.join("hosts")));
+
+        fs::remove_file(dir.join("hosts")).unwrap();
+        let err = Config::load(&main).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("{}:1: failed to read ", main.display())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zone_file() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-zone-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("smart_hosts.conf");
+        fs::write(
+            &main,
+            "192.168.1.10 nas.home.example.com, ssid=\"home\"\n\
+             zone-file home.zone origin home.example.com",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("home.zone"),
+            "$TTL 1h\n\
+             @   SOA ns1 hostmaster 42 1h 10m 1d 5m\n\
+             \x20   NS  ns1.example.com.\n\
+             nas A   10.8.0.10\n\
+             \x20   A   10.8.0.11\n\
+             @   MX  10 mail\n\
+             tv  CNAME nas",
+        )
+        .unwrap();
+
+        let config = Config::load(&main).unwrap();
+        let rules: Vec<String> = config.rules.iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            rules,
+            [
+                r#"192.168.1.10 nas.home.example.com, ssid="home""#,
+                "10.8.0.10,10.8.0.11 nas.home.example.com, ttl=3600",
+            ]
+        );
+        assert_eq!(
+            config.records[0].to_string(),
+            "mx home.example.com 10 mail.home.example.com., ttl=3600"
+        );
+        assert_eq!(
+            config.records[1].to_string(),
+            "cname tv.home.example.com nas.home.example.com., ttl=3600"
+        );
+        assert_eq!(
+            config.zones[0].to_string(),
+            "zone home.example.com ns1.example.com. serial 42 ttl 3600"
+        );
+        assert_eq!(
+            config.zones[0].soa(60),
+            Soa {
+                mname: "ns1.home.example.com.".to_string(),
+                rname: "hostmaster.home.example.com.".to_string(),
+                serial: 42,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+            }
+        );
+        assert!(config.sources.contains(&dir.join("home.zone")));
+
+        fs::write(dir.join("home.zone"), "nas A 10.8.0.300").unwrap();
+        let err = Config::load(&main).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                r#"{}:2: {}: line 1: invalid A record: "10.8.0.300""#,
+                main.display(),
+                dir.join("home.zone").display()
+            )
+        );
+        fs::write(
+            dir.join("home.zone"),
+            "nas A 10.8.0.10\n@ CAA 0 issue ca.example",
+        )
+        .unwrap();
+        let err = Config::load(&main).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "{}:2: {}: line 2: unsupported type CAA",
+                main.display(),
+                dir.join("home.zone").display()
+            )
+        );
+
+        fs::write(
+            dir.join("home.zone"),
+            "@ SOA ns1 hostmaster 42 1h 10m 1d 5m\nlab NS ns1.lab",
+        )
+        .unwrap();
+        let err = Config::load(&main).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "{}:2: {}: NS records of lab.home.example.com., delegations are not supported",
+                main.display(),
+                dir.join("home.zone").display()
+            )
+        );
+
+        // the same zone twice, like with the zone directive
+        fs::write(
+            &main,
+            "zone home.example.com ns1.example.com\n\
+             zone-file home.zone origin home.example.com",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("home.zone"),
+            "@ SOA ns1 hostmaster 42 1h 10m 1d 5m",
+        )
+        .unwrap();
+        let err = Config::load(&main).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "{}:2: {}: duplicate zone home.example.com",
+                main.display(),
+                dir.join("home.zone").display()
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn blocklists() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-block-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("smart_hosts.conf");
+        fs::write(
+            &main,
+            "blocklist ads.txt, ssid=\"cafe\" or cellular=\"on\"\n\
+             blocklist trackers.txt\n\
+             allowlist allow.txt\n\
+             block-response nxdomain",
+        )
+        .unwrap();
+        fs::write(dir.join("ads.txt"), "||ads.example^\n@@||cdn.ads.example^").unwrap();
+        fs::write(
+            dir.join("trackers.txt"),
+            "0.0.0.0 tracker.example metrics.example",
+        )
+        .unwrap();
+        fs::write(dir.join("allow.txt"), "metrics.example").unwrap();
+
+        let config = Config::load(&main).unwrap();
+        assert_eq!(config.block_response, BlockResponse::NxDomain);
+        assert_eq!(
+            config.blocklists[0].to_string(),
+            format!(
+                r#"blocklist {}, ssid="cafe" or cellular="on""#,
+                dir.join("ads.txt").display()
+            )
+        );
+        let cafe = NetworkState {
+            ssid: Some("cafe".to_string()),
+            ..Default::default()
+        };
+        let home = NetworkState::default();
+        let blocked =
+            |name: &str, state: &NetworkState| config.blocked(name, state).map(|b| b.path.clone());
+        assert_eq!(blocked("x.ads.example.", &cafe), Some(dir.join("ads.txt")));
+        assert_eq!(blocked("x.ads.example.", &home), None);
+        assert_eq!(blocked("x.cdn.ads.example.", &cafe), None);
+        assert_eq!(
+            blocked("tracker.example.", &home),
+            Some(dir.join("trackers.txt"))
+        );
+        assert_eq!(blocked("metrics.example.", &home), None);
+
+        let err = |config: &str| config.parse::<Config>().unwrap_err().to_string();
+        assert_eq!(
+            err("block-response drop"),
+            r#"line 1: invalid block response "drop", expected null, nxdomain or refused"#
+        );
+        fs::write(
+            &main,
+            "blocklist ads.txt, not reachable=\"tcp://192.168.1.1:53\"",
+        )
+        .unwrap();
+        assert_eq!(
+            Config::load(&main).unwrap().probe_targets(),
+            vec![Target::Tcp("192.168.1.1:53".parse().unwrap())]
+        );
+        fs::write(&main, "allowlist allow.txt, ssid=\"home\"").unwrap();
+        assert_eq!(
+            Config::load(&main).unwrap_err().to_string(),
+            format!("{}:1: allowlist takes no conditions", main.display())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remotes() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-remotes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("smart_hosts.conf");
+        // nothing listens on the port once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        fs::write(
+            &main,
+            format!(
+                "remote http://{}/team.hosts cache/team.hosts interval 10m\ninclude cache/team.hosts",
+                addr
+            ),
+        )
+        .unwrap();
+        let err = Config::load(&main).unwrap_err().to_string();
+        assert!(err.starts_with(&format!(
+            "{}:1: failed to fetch http://{}/team.hosts",
+            main.display(),
+            addr
+        )));
+
+        // the cached copy is used while the remote is down
+        fs::create_dir_all(dir.join("cache")).unwrap();
+        fs::write(dir.join("cache/team.hosts"), "10.0.0.1 wiki.corp.example").unwrap();
+        let config = Config::load(&main).unwrap();
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.remotes[0].path, dir.join("cache/team.hosts"));
+        assert_eq!(config.remotes[0].interval, Duration::from_secs(600));
+        assert!(config.sources.contains(&dir.join("cache/team.hosts")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let err = "127.0.0.1 a.local\n127.0.0.1 b.local, wifi=\"on\""
+            .parse::<Config>()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Syntax { line: 2, .. }));
+
+        assert!("127.0.0.1".parse::<Config>().is_err());
+        assert!("upstream".parse::<Config>().is_err());
+        assert!("upstream example.com".parse::<Config>().is_err());
+        assert!("upstream tls://:853".parse::<Config>().is_err());
+        assert!("127.0.0.1 a.local, cellular=\"yes\""
+            .parse::<Config>()
+            .is_err());
+        assert!("127.0.0.1 a.local, ssid=home".parse::<Config>().is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info};
+
+use crate::condition::{escape, Expr};
+use crate::config::{parse_duration, split_unquoted, Config};
+use crate::resolver::Resolver;
+
+pub const DEFAULT_SOCKET: &str = "/tmp/smart_hosts.sock";
+
+/// Overrides without an explicit duration expire after this long
+const OVERRIDE_DURATION: Duration = Duration::from_secs(3600);
+/// Connections are served one at a time, a client stalling longer is dropped
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+const USAGE: &str = "\
+usage: smart_hosts ctl [--socket PATH] COMMAND
+
+commands:
+    state                               show the network state
+    rules                               list the rules and upstreams active now
+    health                              show the health of rule addresses
+    export                              print the rules active now as a zone file
+    override KEY=VALUE... [DURATION]    force network conditions, for 1h by default
+    override clear                      go back to the monitored network state
+    flush                               flush the cache
+    reload                              reload the config file";
+
+/// Listen on `path`, replacing the socket left behind by a previous run.
+/// Only the owner may connect, since it can change how every name resolves:
+/// the socket is created in a private directory and only moved to `path` once restricted.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use", path.display()),
+        ));
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no socket file name"))?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::remove_dir_all(&private).ok();
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let result = (|| {
+        let staged = private.join(name);
+        let listener = UnixListener::bind(&staged)?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    })();
+    fs::remove_dir_all(&private).ok();
+    result
+}
+
+/// Serve the control API, one command per connection.
+/// The request is a single line, the response is text ending with the connection,
+/// failures are reported as a line starting with `error: `.
+pub fn serve(listener: UnixListener, resolver: Arc<Resolver>, config_path: PathBuf) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| handle_connection(stream, &resolver, &config_path));
+        if let Err(e) = result {
+            debug!(%e, "control request failed");
+        }
+    }
+}
+
+fn handle_connection(
+    mut stream: UnixStream,
+    resolver: &Resolver,
+    config_path: &Path,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match execute(line.trim(), resolver, config_path) {
+        Ok(response) => response,
+        Err(e) => format!("error: {}\n", e),
+    };
+    stream.write_all(response.as_bytes())
+}
+
+fn execute(command: &str, resolver: &Resolver, config_path: &Path) -> Result<String, String> {
+    let (command, args) = command.split_once(' ').unwrap_or((command, ""));
+    let args: Vec<&str> = split_unquoted(args, ' ')
+        .into_iter()
+        .filter(|a| !a.is_empty())
+        .collect();
+    let mut out = String::new();
+    match (command, args.as_slice()) {
+        ("state", []) => {
+            let state = resolver.network_state();
+            writeln!(out, "ssid: {}", state.ssid.as_deref().unwrap_or("-")).unwrap();
+            writeln!(
+                out,
+                "cellular: {}",
+                if state.cellular { "on" } else { "off" }
+            )
+            .unwrap();
+            writeln!(out, "interface: {}", state.interface.as_str()).unwrap();
+            writeln!(out, "interfaces: {}", list(&state.interfaces)).unwrap();
+            writeln!(out, "addresses: {}", list(&state.addresses)).unwrap();
+            writeln!(out, "gateways: {}", list(&state.gateways)).unwrap();
+            writeln!(out, "gateway macs: {}", list(&state.gateway_macs)).unwrap();
+            writeln!(out, "vpn: {}", if state.vpn { "on" } else { "off" }).unwrap();
+            writeln!(out, "reachable: {}", list(&state.reachable)).unwrap();
+            if let Some(state_override) = resolver.state_override() {
+                let conditions: Vec<_> = state_override
+                    .conditions
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect();
+                let remaining = state_override.until.duration_since(Instant::now());
+                writeln!(
+                    out,
+                    "override: {} ({}s left)",
+                    conditions.join(" "),
+                    remaining.as_secs()
+                )
+                .unwrap();
+            }
+        }
+        ("rules", []) => {
+            let config = resolver.config();
+            let state = resolver.network_state();
+            let active = |conditions: &[Expr]| conditions.iter().all(|c| c.eval(&state));
+            for rule in config.rules.iter().filter(|r| active(&r.conditions)) {
+                writeln!(out, "{}", rule).unwrap();
+            }
+            for record in config.records.iter().filter(|r| active(&r.conditions)) {
+                writeln!(out, "{}", record).unwrap();
+            }
+            for upstream in config.upstreams.iter().filter(|u| active(&u.conditions)) {
+                writeln!(out, "{}", upstream).unwrap();
+            }
+        }
+        ("export", []) => {
+            let config = resolver.config();
+            out.push_str(&crate::zonefile::export(&config, &resolver.network_state()));
+        }
+        ("health", []) => {
+            let config = resolver.config();
+            for rule in &config.rules {
+                let Some(check) = &rule.health else {
+                    continue;
+                };
+                for address in &rule.addresses {
+                    let health = match resolver.health(&check.target(*address)) {
+                        Some(true) => "up",
+                        Some(false) => "down",
+                        None => "unknown",
+                    };
+                    writeln!(out, "{} {} {}", address, check, health).unwrap();
+                }
+            }
+        }
+        ("override", ["clear"]) => {
+            resolver.clear_override();
+            info!("network state override cleared");
+            out.push_str("override cleared\n");
+        }
+        ("override", [_, ..]) => {
+            let mut conditions = Vec::new();
+            let mut duration = OVERRIDE_DURATION;
+            for arg in args {
+                if arg.contains('=') {
+                    conditions.push(arg.parse()?);
+                } else {
+                    duration = parse_duration(arg)?;
+                }
+            }
+            if conditions.is_empty() {
+                return Err("nothing to override".to_string());
+            }
+            resolver.override_state(conditions, duration)?;
+            resolver.flush_cache();
+            info!(?duration, "network state overridden");
+            writeln!(out, "overridden for {}s", duration.as_secs()).unwrap();
+        }
+        ("flush", []) => {
+            resolver.flush_cache();
+            out.push_str("cache flushed\n");
+        }
+        ("reload", []) => {
+            let config = Config::load(config_path).map_err(|e| e.to_string())?;
+            resolver.reload(config);
+            info!(path = ?config_path, "config reloaded");
+            out.push_str("config reloaded\n");
+        }
+        _ => return Err(format!("invalid command {:?}", command)),
+    }
+    Ok(out)
+}
+
+/// Space separated, `-` when empty
+fn list<T: ToString>(items: &[T]) -> String {
+    if items.is_empty() {
+        return "-".to_string();
+    }
+    items
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Send `command` to the control socket at `path`, returns the response
+pub fn request(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// `smart_hosts ctl`, returns the exit code
+pub fn cli(mut args: impl Iterator<Item = String>) -> i32 {
+    let mut socket = PathBuf::from(DEFAULT_SOCKET);
+    let mut words = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" | "-s" => match args.next() {
+                Some(path) => socket = path.into(),
+                None => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return 0;
+            }
+            _ => words.push(quote_value(&arg)),
+        }
+    }
+    if words.is_empty() {
+        eprintln!("{}", USAGE);
+        return 2;
+    }
+
+    match request(&socket, &words.join(" ")) {
+        Ok(response) if response.starts_with("error: ") => {
+            eprint!("{}", response);
+            1
+        }
+        Ok(response) => {
+            print!("{}", response);
+            0
+        }
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", socket.display(), e);
+            1
+        }
+    }
+}
+
+/// The shell strips the quotes of `ssid="home"`, put them back
+fn quote_value(arg: &str) -> String {
+    match arg.split_once('=') {
+        Some((key, value)) if !value.starts_with('"') => format!("{}=\"{}\"", key, escape(value)),
+        _ => arg.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::RwLock, thread};
+
+    use super::*;
+    use crate::monitor::NetworkState;
+
+    #[test]
+    fn commands() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("smart_hosts.conf");
+        let config =
+            "127.0.0.1 *.home.local, ssid=\"home\"\n127.0.0.2 *.home.local, cellular=\"on\"";
+        fs::write(&config_path, config).unwrap();
+
+        let state = Arc::new(RwLock::new(NetworkState {
+            cellular: true,
+            ..Default::default()
+        }));
+        let resolver = Arc::new(Resolver::new(config.parse().unwrap(), state));
+        let socket = dir.join("control.sock");
+        let listener = bind(&socket).unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the private directory it was created in is gone
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        {
+            let resolver = resolver.clone();
+            thread::spawn(move || serve(listener, resolver, config_path));
+        }
+
+        let state = request(&socket, "state").unwrap();
+        assert_eq!(
+            state,
+            "ssid: -\ncellular: on\ninterface: other\ninterfaces: -\naddresses: -\n\
+             gateways: -\ngateway macs: -\nvpn: off\nreachable: -\n"
+        );
+        assert_eq!(
+            request(&socket, "rules").unwrap(),
+            "127.0.0.2 *.home.local, cellular=\"on\"\n"
+        );
+        assert_eq!(
+            request(&socket, "export").unwrap(),
+            "; active smart hosts rules\n$TTL 3600\n*.home.local. 60 IN A 127.0.0.2\n"
+        );
+
+        let response = request(&socket, r#"override ssid="My Home" cellular="off" 10m"#).unwrap();
+        assert_eq!(response, "overridden for 600s\n");
+        let state = request(&socket, "state").unwrap();
+        assert!(state.starts_with("ssid: My Home\ncellular: off\n"));
+        assert!(state.contains(r#"override: ssid="My Home" cellular="off" ("#));
+        assert_eq!(request(&socket, "rules").unwrap(), "");
+        request(&socket, r#"override vpn="on" gateway="10.1.0.1""#).unwrap();
+        let state = request(&socket, "state").unwrap();
+        assert!(state.contains("gateways: 10.1.0.1\n"));
+        assert!(state.contains("vpn: on\n"));
+
+        // the rest of the monitored state is kept
+        request(&socket, "override ssid=\"home\"").unwrap();
+        assert_eq!(
+            request(&socket, "rules").unwrap(),
+            "127.0.0.1 *.home.local, ssid=\"home\"\n127.0.0.2 *.home.local, cellular=\"on\"\n"
+        );
+        request(&socket, "override clear").unwrap();
+        assert!(request(&socket, "state").unwrap().starts_with("ssid: -\n"));
+
+        assert_eq!(request(&socket, "flush").unwrap(), "cache flushed\n");
+        fs::write(
+            dir.join("smart_hosts.conf"),
+            "127.0.0.3 *.home.local\n10.0.0.1,10.0.0.2 api.svc.local, health=tcp:443",
+        )
+        .unwrap();
+        assert_eq!(request(&socket, "reload").unwrap(), "config reloaded\n");
+        assert_eq!(
+            request(&socket, "rules").unwrap(),
+            "127.0.0.3 *.home.local\n10.0.0.1,10.0.0.2 api.svc.local, health=tcp:443\n"
+        );
+        resolver.set_reachability(vec![("tcp://10.0.0.1:443".parse().unwrap(), false)]);
+        assert_eq!(
+            request(&socket, "health").unwrap(),
+            "10.0.0.1 tcp:443 down\n10.0.0.2 tcp:443 unknown\n"
+        );
+
+        assert!(request(&socket, "override").unwrap().starts_with("error: "));
+        assert_eq!(
+            request(&socket, r#"override ssid="x" 106751991167301d"#).unwrap(),
+            "error: override duration too long\n"
+        );
+        assert!(request(&socket, "override wifi=\"on\"")
+            .unwrap()
+            .starts_with("error: "));
+        assert!(request(&socket, "restart").unwrap().starts_with("error: "));
+
+        // a stalled client does not hold off others for long
+        let _stalled = UnixStream::connect(&socket).unwrap();
+        let start = Instant::now();
+        assert_eq!(request(&socket, "flush").unwrap(), "cache flushed\n");
+        assert!(start.elapsed() < TIMEOUT * 2);
+
+        // already in use
+        assert!(bind(&socket).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_value("ssid=My Home"), "ssid=\"My Home\"");
+        assert_eq!(quote_value("ssid=\"home\""), "ssid=\"home\"");
+        assert_eq!(quote_value("30m"), "30m");
+        assert_eq!(quote_value(r#"ssid=say "hi""#), r#"ssid="say \"hi\"""#);
+    }
+}
@@ -1,14 +1,18 @@
 use std::{
     collections::HashMap,
-    io::{Seek, SeekFrom},
-    net::Ipv4Addr,
+    io::{Cursor, Seek, SeekFrom},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-use deku::prelude::*;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use deku::{
+    ctx::{BitSize, Endian},
+    prelude::*,
+};
 use tracing::trace;
 
 #[derive(Debug, Default, DekuRead, DekuWrite)]
-#[deku(ctx = "names: &mut HashMap<String, u8>")]
+#[deku(ctx = "names: &mut HashMap<String, u16>")]
 pub struct DnsPacket {
     pub header: DnsHeader,
     #[deku(
@@ -33,10 +37,189 @@ pub struct DnsPacket {
     pub additional: Vec<DnsRecord>,
 }
 
+/// Presentation format as printed by dig, sections without records are left out
+impl std::fmt::Display for DnsPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut header = self.header.clone();
+        header.qdcount = self.questions.len() as u16;
+        header.ancount = self.answers.len() as u16;
+        header.nscount = self.authorities.len() as u16;
+        header.arcount = self.additional.len() as u16;
+        header.rcode = self.rcode();
+        write!(f, "{}", header)?;
+        if let Some(opt) = self.edns() {
+            let flags = if opt.ttl & EDNS_DO != 0 { " do" } else { "" };
+            let payload = self.edns_payload().unwrap_or_default();
+            write!(
+                f,
+                "\n\n;; OPT PSEUDOSECTION:\n; EDNS: version: {}, flags:{}; udp: {}",
+                (opt.ttl >> 16) as u8,
+                flags,
+                payload
+            )?;
+        }
+        if !self.questions.is_empty() {
+            write!(f, "\n\n;; QUESTION SECTION:")?;
+            for question in &self.questions {
+                write!(f, "\n{}", question)?;
+            }
+        }
+        let additional: Vec<&DnsRecord> = self
+            .additional
+            .iter()
+            .filter(|r| r.r#type != DnsType::OPT)
+            .collect();
+        for (section, records) in [
+            ("ANSWER", self.answers.iter().collect()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", additional),
+        ] {
+            if records.is_empty() {
+                continue;
+            }
+            write!(f, "\n\n;; {} SECTION:", section)?;
+            for record in records {
+                write!(f, "\n{}", record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DnsPacket {
+    /// Parse a packet from its wire format, the response code includes the bits of the OPT record
+    pub fn parse(buf: &[u8]) -> Result<Self, DekuError> {
+        let mut cursor = Cursor::new(buf);
+        let mut reader = Reader::new(&mut cursor);
+        let mut packet = Self::from_reader_with_ctx(&mut reader, &mut HashMap::new())?;
+        packet.header.rcode = packet.rcode();
+        Ok(packet)
+    }
+
+    /// Serialize the packet into its wire format, section counts are updated beforehand.
+    /// Extended response codes are split between the header and an OPT record, added if missing.
+    pub fn encode(&mut self) -> Result<Vec<u8>, DekuError> {
+        let extended = (u16::from(self.header.rcode) >> 4) as u32;
+        if extended != 0 && self.edns().is_none() {
+            self.additional.push(DnsRecord::opt());
+        }
+        for opt in self
+            .additional
+            .iter_mut()
+            .filter(|r| r.r#type == DnsType::OPT)
+        {
+            opt.ttl = opt.ttl & 0x00ff_ffff | extended << 24;
+        }
+        self.header.qdcount = self.questions.len() as u16;
+        self.header.ancount = self.answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+        self.header.arcount = self.additional.len() as u16;
+
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut cursor);
+        self.to_writer(&mut writer, &mut HashMap::new())?;
+        writer.finalize()?;
+        Ok(cursor.into_inner())
+    }
+
+    /// OPT pseudo-record of the additional section, if the sender supports EDNS
+    pub fn edns(&self) -> Option<&DnsRecord> {
+        self.additional.iter().find(|r| r.r#type == DnsType::OPT)
+    }
+
+    /// Largest UDP payload the sender accepts, if it supports EDNS
+    pub fn edns_payload(&self) -> Option<u16> {
+        self.edns().map(|opt| match opt.class {
+            DnsClass::Other(payload) => payload,
+            DnsClass::In => 1,
+        })
+    }
+
+    /// EDNS version of the sender, if it supports EDNS
+    pub fn edns_version(&self) -> Option<u8> {
+        self.edns().map(|opt| (opt.ttl >> 16) as u8)
+    }
+
+    /// Response code of the header, extended by the OPT record if any
+    pub fn rcode(&self) -> Rcode {
+        let low = u16::from(self.header.rcode) & 0x0f;
+        let high = self.edns().map_or(0, |opt| (opt.ttl >> 24) as u16);
+        Rcode::from(high << 4 | low)
+    }
+
+    /// Query of tests asking for `r#type` of `name`
+    #[cfg(test)]
+    pub fn query(name: &str, r#type: DnsType) -> Self {
+        DnsPacket {
+            questions: vec![DnsQuestion {
+                name: name.to_string(),
+                r#type,
+                class: DnsClass::In,
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+/// Response code of a raw message, extended by its OPT record when the message parses
+/// and from the header alone otherwise
+pub fn rcode_of(message: &[u8]) -> Option<Rcode> {
+    match DnsPacket::parse(message) {
+        Ok(packet) => Some(packet.rcode()),
+        Err(_) => DnsHeader::from_bytes((message, 0))
+            .ok()
+            .map(|(_rest, header)| header.rcode),
+    }
+}
+
+/// Most bytes a UDP response to `query` may hold, 512 unless its OPT record allows more
+pub fn udp_limit(query: &[u8]) -> usize {
+    let payload = DnsPacket::parse(query)
+        .ok()
+        .and_then(|packet| packet.edns_payload());
+    (payload.unwrap_or_default() as usize).max(512)
+}
+
+/// `response` if it fits in `limit` bytes, otherwise only its question and OPT record
+/// with the TC bit set, telling the client to retry over TCP
+pub fn truncate(response: Vec<u8>, limit: usize) -> Vec<u8> {
+    if response.len() <= limit {
+        return response;
+    }
+    if let Ok(mut packet) = DnsPacket::parse(&response) {
+        packet.header.tc = true;
+        packet.answers.clear();
+        packet.authorities.clear();
+        packet.additional.retain(|r| r.r#type == DnsType::OPT);
+        if let Ok(truncated) = packet.encode() {
+            return truncated;
+        }
+    }
+    // unparsable upstream responses are cut down to the header
+    let Ok((_rest, mut header)) = DnsHeader::from_bytes((&response, 0)) else {
+        return Vec::new();
+    };
+    header.tc = true;
+    header.qdcount = 0;
+    header.ancount = 0;
+    header.nscount = 0;
+    header.arcount = 0;
+    header.to_bytes().unwrap_or_default()
+}
+
+/// Largest UDP payload advertised in OPT records, as recommended by the DNS flag day 2020
+pub const EDNS_PAYLOAD: u16 = 1232;
+
+/// DNSSEC OK flag of the OPT record TTL
+const EDNS_DO: u32 = 0x8000;
+
+/// Owner of the start of authority in negative answers outside of zones
+const SOA_OWNER: &str = "smart-hosts.";
+
 fn questions_read<R: std::io::Read + std::io::Seek>(
     reader: &mut Reader<R>,
     count: u16,
-    names: &mut HashMap<String, u8>,
+    names: &mut HashMap<String, u16>,
 ) -> Result<Vec<DnsQuestion>, DekuError> {
     let mut ans = Vec::with_capacity(count as usize);
     for _ in 0..count {
@@ -49,7 +232,7 @@ fn questions_read<R: std::io::Read + std::io::Seek>(
 fn questions_write<W: std::io::Write + std::io::Seek>(
     writer: &mut Writer<W>,
     value: &Vec<DnsQuestion>,
-    names: &mut HashMap<String, u8>,
+    names: &mut HashMap<String, u16>,
 ) -> Result<(), DekuError> {
     for q in value {
         q.to_writer(writer, names)?;
@@ -60,7 +243,7 @@ fn questions_write<W: std::io::Write + std::io::Seek>(
 fn records_read<R: std::io::Read + std::io::Seek>(
     reader: &mut Reader<R>,
     count: u16,
-    names: &mut HashMap<String, u8>,
+    names: &mut HashMap<String, u16>,
 ) -> Result<Vec<DnsRecord>, DekuError> {
     let mut ans = Vec::with_capacity(count as usize);
     for _ in 0..count {
@@ -73,7 +256,7 @@ fn records_read<R: std::io::Read + std::io::Seek>(
 fn records_write<W: std::io::Write + std::io::Seek>(
     writer: &mut Writer<W>,
     value: &Vec<DnsRecord>,
-    names: &mut HashMap<String, u8>,
+    names: &mut HashMap<String, u16>,
 ) -> Result<(), DekuError> {
     for q in value {
         q.to_writer(writer, names)?;
@@ -81,8 +264,203 @@ fn records_write<W: std::io::Write + std::io::Seek>(
     Ok(())
 }
 
+/// Kind of message, RFC 1035, 1996 and 2136
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    #[default]
+    Query,
+    IQuery,
+    Status,
+    Notify,
+    Update,
+    Other(u8),
+}
+
+impl From<u8> for Opcode {
+    fn from(opcode: u8) -> Self {
+        match opcode {
+            0 => Opcode::Query,
+            1 => Opcode::IQuery,
+            2 => Opcode::Status,
+            4 => Opcode::Notify,
+            5 => Opcode::Update,
+            opcode => Opcode::Other(opcode),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Other(opcode) => opcode,
+        }
+    }
+}
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Opcode::Query => write!(f, "QUERY"),
+            Opcode::IQuery => write!(f, "IQUERY"),
+            Opcode::Status => write!(f, "STATUS"),
+            Opcode::Notify => write!(f, "NOTIFY"),
+            Opcode::Update => write!(f, "UPDATE"),
+            Opcode::Other(opcode) => write!(f, "OPCODE{}", opcode),
+        }
+    }
+}
+
+/// Response code, the 4 bits of the header extended by 8 more in the OPT record, RFC 6891.
+/// Header parsed on their own only carry the lower bits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rcode {
+    #[default]
+    NoError,
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    BadVers,
+    BadCookie,
+    Other(u16),
+}
+
+impl From<u16> for Rcode {
+    fn from(rcode: u16) -> Self {
+        match rcode {
+            0 => Rcode::NoError,
+            1 => Rcode::FormErr,
+            2 => Rcode::ServFail,
+            3 => Rcode::NXDomain,
+            4 => Rcode::NotImp,
+            5 => Rcode::Refused,
+            6 => Rcode::YXDomain,
+            7 => Rcode::YXRRSet,
+            8 => Rcode::NXRRSet,
+            9 => Rcode::NotAuth,
+            10 => Rcode::NotZone,
+            16 => Rcode::BadVers,
+            23 => Rcode::BadCookie,
+            rcode => Rcode::Other(rcode),
+        }
+    }
+}
+
+impl From<Rcode> for u16 {
+    fn from(rcode: Rcode) -> Self {
+        match rcode {
+            Rcode::NoError => 0,
+            Rcode::FormErr => 1,
+            Rcode::ServFail => 2,
+            Rcode::NXDomain => 3,
+            Rcode::NotImp => 4,
+            Rcode::Refused => 5,
+            Rcode::YXDomain => 6,
+            Rcode::YXRRSet => 7,
+            Rcode::NXRRSet => 8,
+            Rcode::NotAuth => 9,
+            Rcode::NotZone => 10,
+            Rcode::BadVers => 16,
+            Rcode::BadCookie => 23,
+            Rcode::Other(rcode) => rcode,
+        }
+    }
+}
+
+impl std::fmt::Display for Rcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rcode::NoError => write!(f, "NOERROR"),
+            Rcode::FormErr => write!(f, "FORMERR"),
+            Rcode::ServFail => write!(f, "SERVFAIL"),
+            Rcode::NXDomain => write!(f, "NXDOMAIN"),
+            Rcode::NotImp => write!(f, "NOTIMP"),
+            Rcode::Refused => write!(f, "REFUSED"),
+            Rcode::YXDomain => write!(f, "YXDOMAIN"),
+            Rcode::YXRRSet => write!(f, "YXRRSET"),
+            Rcode::NXRRSet => write!(f, "NXRRSET"),
+            Rcode::NotAuth => write!(f, "NOTAUTH"),
+            Rcode::NotZone => write!(f, "NOTZONE"),
+            Rcode::BadVers => write!(f, "BADVERS"),
+            Rcode::BadCookie => write!(f, "BADCOOKIE"),
+            Rcode::Other(rcode) => write!(f, "RCODE{}", rcode),
+        }
+    }
+}
+
+fn opcode_read<R: std::io::Read + std::io::Seek>(
+    reader: &mut Reader<R>,
+) -> Result<Opcode, DekuError> {
+    let opcode = u8::from_reader_with_ctx(reader, (Endian::Big, BitSize(4)))?;
+    Ok(Opcode::from(opcode))
+}
+
+fn opcode_write<W: std::io::Write + std::io::Seek>(
+    writer: &mut Writer<W>,
+    opcode: Opcode,
+) -> Result<(), DekuError> {
+    u8::from(opcode).to_writer(writer, (Endian::Big, BitSize(4)))
+}
+
+fn rcode_read<R: std::io::Read + std::io::Seek>(
+    reader: &mut Reader<R>,
+) -> Result<Rcode, DekuError> {
+    let rcode = u8::from_reader_with_ctx(reader, (Endian::Big, BitSize(4)))?;
+    Ok(Rcode::from(rcode as u16))
+}
+
+/// Only the lower bits, the others go to the OPT record
+fn rcode_write<W: std::io::Write + std::io::Seek>(
+    writer: &mut Writer<W>,
+    rcode: Rcode,
+) -> Result<(), DekuError> {
+    let rcode = (u16::from(rcode) & 0x0f) as u8;
+    rcode.to_writer(writer, (Endian::Big, BitSize(4)))
+}
+
+/// Header lines as printed by dig, counts as in the header
+impl std::fmt::Display for DnsHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            self.opcode, self.rcode, self.id
+        )?;
+        let flags: Vec<&str> = [
+            ("qr", self.qr),
+            ("aa", self.aa),
+            ("tc", self.tc),
+            ("rd", self.rd),
+            ("ra", self.ra),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.qdcount,
+            self.ancount,
+            self.nscount,
+            self.arcount
+        )
+    }
+}
+
 /// DNS Header
-#[derive(Debug, Default, DekuRead, DekuWrite)]
+#[derive(Debug, Default, Clone, DekuRead, DekuWrite)]
 #[deku(endian = "big")]
 pub struct DnsHeader {
     /// Packet ID
@@ -93,8 +471,11 @@ pub struct DnsHeader {
     #[deku(bits = 1)]
     pub qr: bool,
     /// Operation code
-    #[deku(bits = 4)]
-    pub opcode: u8,
+    #[deku(
+        reader = "opcode_read(deku::reader)",
+        writer = "opcode_write(deku::writer, self.opcode)"
+    )]
+    pub opcode: Opcode,
     /// Authoritative Answer
     #[deku(bits = 1)]
     pub aa: bool,
@@ -110,8 +491,12 @@ pub struct DnsHeader {
     /// Reserved
     #[deku(bits = 3)]
     pub z: u8,
-    #[deku(bits = 4)]
-    pub rcode: u8,
+    /// Response code, see `Rcode` for the extended ones
+    #[deku(
+        reader = "rcode_read(deku::reader)",
+        writer = "rcode_write(deku::writer, self.rcode)"
+    )]
+    pub rcode: Rcode,
 
     /// Question Count
     #[deku(bits = 16)]
@@ -128,11 +513,94 @@ pub struct DnsHeader {
 }
 
 /// DNS Type
-#[derive(Debug, Clone, Copy, DekuRead, DekuWrite)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, DekuRead, DekuWrite)]
 #[deku(id_type = "u16", endian = "big")]
 pub enum DnsType {
     #[deku(id = 1)]
     A,
+    #[deku(id = 2)]
+    NS,
+    #[deku(id = 5)]
+    CNAME,
+    #[deku(id = 6)]
+    SOA,
+    #[deku(id = 12)]
+    PTR,
+    #[deku(id = 15)]
+    MX,
+    #[deku(id = 16)]
+    TXT,
+    #[deku(id = 28)]
+    AAAA,
+    #[deku(id = 33)]
+    SRV,
+    #[deku(id = 64)]
+    SVCB,
+    #[deku(id = 65)]
+    HTTPS,
+    /// EDNS pseudo-record, only found in the additional section
+    #[deku(id = 41)]
+    OPT,
+    /// Types the codec has no mnemonic for, their data is kept opaque
+    #[deku(id_pat = "_")]
+    Other(u16),
+}
+
+impl From<u16> for DnsType {
+    fn from(code: u16) -> Self {
+        // every code reads as a variant, `Other` catching the rest
+        let (_rest, r#type) = DnsType::from_bytes((&code.to_be_bytes(), 0)).unwrap();
+        r#type
+    }
+}
+
+impl From<DnsType> for u16 {
+    fn from(r#type: DnsType) -> Self {
+        match r#type {
+            DnsType::Other(code) => code,
+            r#type => r#type.deku_id().unwrap(),
+        }
+    }
+}
+
+/// Mnemonics, or `TYPE65280` as in RFC 3597 for the others
+impl std::fmt::Display for DnsType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsType::Other(code) => write!(f, "TYPE{}", code),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl std::str::FromStr for DnsType {
+    type Err = String;
+
+    /// Mnemonics in any case, like `AAAA` or `https`, or `TYPE65280`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        if let Some(code) = upper.strip_prefix("TYPE") {
+            return code
+                .parse::<u16>()
+                .map(DnsType::from)
+                .map_err(|_| format!("unknown type {:?}", s));
+        }
+        match upper.as_str() {
+            "A" => Ok(DnsType::A),
+            "NS" => Ok(DnsType::NS),
+            "CNAME" => Ok(DnsType::CNAME),
+            "SOA" => Ok(DnsType::SOA),
+            "PTR" => Ok(DnsType::PTR),
+            "MX" => Ok(DnsType::MX),
+            "TXT" => Ok(DnsType::TXT),
+            "AAAA" => Ok(DnsType::AAAA),
+            "SRV" => Ok(DnsType::SRV),
+            "SVCB" => Ok(DnsType::SVCB),
+            "HTTPS" => Ok(DnsType::HTTPS),
+            _ => Err(format!("unknown type {:?}", s)),
+        }
+    }
 }
 
 /// DNS Class
@@ -141,11 +609,23 @@ pub enum DnsType {
 pub enum DnsClass {
     #[deku(id = 1)]
     In,
+    /// Other classes, or the UDP payload size of OPT records
+    #[deku(id_pat = "_")]
+    Other(u16),
+}
+
+impl std::fmt::Display for DnsClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsClass::In => write!(f, "IN"),
+            DnsClass::Other(class) => write!(f, "CLASS{}", class),
+        }
+    }
 }
 
 /// DNS Question
 #[derive(Debug, DekuRead, DekuWrite)]
-#[deku(ctx = "names: &mut HashMap<String, u8>")]
+#[deku(ctx = "names: &mut HashMap<String, u16>")]
 pub struct DnsQuestion {
     #[deku(
         reader = "qname_read(deku::reader, names)",
@@ -158,7 +638,7 @@ pub struct DnsQuestion {
 
 /// DNS Record
 #[derive(Debug, DekuRead, DekuWrite)]
-#[deku(ctx = "names: &mut HashMap<String, u8>")]
+#[deku(ctx = "names: &mut HashMap<String, u16>")]
 pub struct DnsRecord {
     #[deku(
         reader = "qname_read(deku::reader, names)",
@@ -169,112 +649,782 @@ pub struct DnsRecord {
     pub class: DnsClass,
     #[deku(endian = "big")]
     pub ttl: u32,
-    #[deku(endian = "big")]
+    /// Length of `data` in the wire format, computed when written since names may be compressed
+    #[deku(endian = "big", writer = "Ok::<(), DekuError>(())")]
     pub len: u16,
-    #[deku(bytes_read = "len", ctx = "*r#type")]
+    #[deku(
+        reader = "rdata_read(deku::reader, *r#type, *len, names)",
+        writer = "rdata_write(deku::writer, *r#type, &self.data, names)"
+    )]
     pub data: Vec<DnsRData>,
 }
 
+/// `;google.com. IN A`
+impl std::fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, ";{} {} {}", self.name, self.class, self.r#type)
+    }
+}
+
+/// `google.com. 83 IN A 127.0.0.1`
+impl std::fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.name, self.ttl, self.class, self.r#type
+        )?;
+        for data in &self.data {
+            write!(f, " {}", data)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for DnsRecord {
+    type Err = String;
+
+    /// Presentation format as printed by dig, names are absolute
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let entries = crate::zonefile::entries(s, Some("."))?;
+        match &entries[..] {
+            [entry] => {
+                let ttl = entry.ttl.ok_or("missing ttl")?;
+                Ok(Self::new(
+                    &entry.name,
+                    entry.r#type,
+                    ttl,
+                    entry.data.clone(),
+                ))
+            }
+            _ => Err(format!("expected a single record, found {}", entries.len())),
+        }
+    }
+}
+
+impl DnsRecord {
+    pub fn new(name: &str, r#type: DnsType, ttl: u32, data: Vec<DnsRData>) -> Self {
+        Self {
+            name: name.to_string(),
+            r#type,
+            class: DnsClass::In,
+            ttl,
+            len: 0,
+            data,
+        }
+    }
+
+    /// Address record of `name`, A or AAAA depending on the address family
+    pub fn address(name: &str, ttl: u32, addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(ip) => Self::new(name, DnsType::A, ttl, vec![DnsRData::IP(ip)]),
+            IpAddr::V6(ip) => Self::new(name, DnsType::AAAA, ttl, vec![DnsRData::IPv6(ip)]),
+        }
+    }
+
+    /// EDNS pseudo-record of version 0 advertising `EDNS_PAYLOAD`
+    pub fn opt() -> Self {
+        Self {
+            name: ".".to_string(),
+            r#type: DnsType::OPT,
+            class: DnsClass::Other(EDNS_PAYLOAD),
+            ttl: 0,
+            len: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// Start of authority record for names outside of any zone, negative answers are cached
+    /// for `ttl`. It is owned by the `smart-hosts.` pseudo-zone, whatever name was asked,
+    /// rather than claiming authority over a zone the name is in.
+    pub fn soa(ttl: u32) -> Self {
+        let soa = Soa {
+            mname: SOA_OWNER.to_string(),
+            rname: format!("hostmaster.{}", SOA_OWNER),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: ttl,
+        };
+        Self::new(SOA_OWNER, DnsType::SOA, ttl, vec![DnsRData::Soa(soa)])
+    }
+}
+
+/// Start of authority data
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "big", ctx = "names: &mut HashMap<String, u16>")]
+pub struct Soa {
+    #[deku(
+        reader = "qname_read(deku::reader, names)",
+        writer = "qname_write(deku::writer, &self.mname, names)"
+    )]
+    pub mname: String,
+    #[deku(
+        reader = "qname_read(deku::reader, names)",
+        writer = "qname_write(deku::writer, &self.rname, names)"
+    )]
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
 /// DNS Recrod Specific Data
-#[derive(Debug, DekuRead, DekuWrite)]
-#[deku(ctx = "typ: DnsType", id = "typ")]
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(ctx = "typ: DnsType, names: &mut HashMap<String, u16>", id = "typ")]
 pub enum DnsRData {
     #[deku(id = "DnsType::A")]
     IP(#[deku(endian = "big")] Ipv4Addr),
+    #[deku(id = "DnsType::NS")]
+    Ns(
+        #[deku(
+            reader = "qname_read(deku::reader, names)",
+            writer = "qname_write(deku::writer, field_0, names)"
+        )]
+        String,
+    ),
+    #[deku(id = "DnsType::CNAME")]
+    Cname(
+        #[deku(
+            reader = "qname_read(deku::reader, names)",
+            writer = "qname_write(deku::writer, field_0, names)"
+        )]
+        String,
+    ),
+    #[deku(id = "DnsType::SOA")]
+    Soa(#[deku(ctx = "names")] Soa),
+    #[deku(id = "DnsType::PTR")]
+    Ptr(
+        #[deku(
+            reader = "qname_read(deku::reader, names)",
+            writer = "qname_write(deku::writer, field_0, names)"
+        )]
+        String,
+    ),
+    #[deku(id = "DnsType::MX")]
+    Mx {
+        #[deku(endian = "big")]
+        preference: u16,
+        #[deku(
+            reader = "qname_read(deku::reader, names)",
+            writer = "qname_write(deku::writer, exchange, names)"
+        )]
+        exchange: String,
+    },
+    /// One character-string, a record holds one or more of them
+    #[deku(id = "DnsType::TXT")]
+    Txt(
+        #[deku(
+            reader = "character_string_read(deku::reader)",
+            writer = "character_string_write(deku::writer, field_0)"
+        )]
+        Vec<u8>,
+    ),
+    #[deku(id = "DnsType::AAAA")]
+    IPv6(#[deku(endian = "big")] Ipv6Addr),
+    /// The target is never compressed, as required by RFC 2782
+    #[deku(id = "DnsType::SRV")]
+    Srv {
+        #[deku(endian = "big")]
+        priority: u16,
+        #[deku(endian = "big")]
+        weight: u16,
+        #[deku(endian = "big")]
+        port: u16,
+        #[deku(
+            reader = "qname_read(deku::reader, names)",
+            writer = "name_write(deku::writer, target, None)"
+        )]
+        target: String,
+    },
+    /// Read by `rdata_read`, as its parameters run to the end of the record data
+    #[deku(id_pat = "DnsType::SVCB | DnsType::HTTPS")]
+    Svcb(Svcb),
+    /// One EDNS option, a record holds zero or more of them
+    #[deku(id = "DnsType::OPT")]
+    Opt(EdnsOption),
+    /// Data of types the codec does not know, read by `rdata_read` as it runs to
+    /// the end of the record data. Never holds names to decompress, as per RFC 3597.
+    #[deku(id_pat = "_")]
+    Unknown(#[deku(read_all)] Vec<u8>),
 }
 
-/// Label
-#[derive(Debug, DekuRead, DekuWrite)]
-pub struct Label {
-    #[deku(update = "self.data.len()", bits = 8)]
-    pub len: u8,
+/// EDNS option of OPT records, RFC 6891
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub struct EdnsOption {
+    pub code: u16,
+    #[deku(writer = "(self.data.len() as u16).to_writer(deku::writer, Endian::Big)")]
+    len: u16,
     #[deku(count = "len")]
     pub data: Vec<u8>,
 }
 
-#[derive(Debug, DekuRead, DekuWrite)]
-pub struct Labels(#[deku(until = "|v: &Label| v.len == 0")] Vec<Label>);
+impl EdnsOption {
+    pub fn new(code: u16, data: Vec<u8>) -> Self {
+        Self {
+            code,
+            len: data.len() as u16,
+            data,
+        }
+    }
+}
 
-/// Label Sequence
-#[derive(Debug, DekuRead, DekuWrite)]
-#[deku(id_type = "u8")]
-pub enum LabelSeq {
-    #[deku(id = "192")]
-    Jump(#[deku(bytes = 1)] u8),
-    #[deku(id_pat = "_")]
-    Lables(Labels),
+/// Service binding data of SVCB and HTTPS records, RFC 9460
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub struct Svcb {
+    /// 0 for the alias form, which has no parameters
+    pub priority: u16,
+    /// Never compressed, `.` stands for the owner name in the service form
+    #[deku(
+        reader = "qname_read(deku::reader, &mut HashMap::new())",
+        writer = "name_write(deku::writer, &self.target, None)"
+    )]
+    pub target: String,
+    /// In increasing order of their keys
+    #[deku(
+        reader = "svc_params_read(deku::reader)",
+        writer = "svc_params_write(deku::writer, &self.params)"
+    )]
+    pub params: Vec<SvcParam>,
+}
+
+impl Svcb {
+    /// Parameters are put in order, duplicates and parameters of the alias form are rejected
+    pub fn new(priority: u16, target: String, mut params: Vec<SvcParam>) -> Result<Self, String> {
+        params.sort_by_key(|p| p.key());
+        if let Some(pair) = params.windows(2).find(|p| p[0].key() == p[1].key()) {
+            return Err(format!("duplicate service parameter {}", pair[1]));
+        }
+        if priority == 0 && !params.is_empty() {
+            return Err("the alias form, priority 0, takes no parameters".to_string());
+        }
+        Ok(Self {
+            priority,
+            target,
+            params,
+        })
+    }
+}
+
+impl std::fmt::Display for Svcb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.priority, self.target)?;
+        for param in &self.params {
+            write!(f, " {}", param)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parameter of a service binding, unknown keys are kept as they are
+#[derive(Debug, Clone, PartialEq)]
+pub enum SvcParam {
+    Mandatory(Vec<u16>),
+    Alpn(Vec<String>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    /// Encrypted ClientHello configuration list
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    Other(u16, Vec<u8>),
+}
+
+const SVC_KEYS: [&str; 7] = [
+    "mandatory",
+    "alpn",
+    "no-default-alpn",
+    "port",
+    "ipv4hint",
+    "ech",
+    "ipv6hint",
+];
+
+fn svc_key_name(key: u16) -> String {
+    match SVC_KEYS.get(key as usize) {
+        Some(name) => name.to_string(),
+        None => format!("key{}", key),
+    }
+}
+
+fn svc_key_parse(name: &str) -> Result<u16, String> {
+    match SVC_KEYS.iter().position(|k| *k == name) {
+        Some(key) => Ok(key as u16),
+        None => name
+            .strip_prefix("key")
+            .and_then(|key| key.parse().ok())
+            .ok_or_else(|| format!("unknown service parameter {:?}", name)),
+    }
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match self {
+            SvcParam::Mandatory(_) => 0,
+            SvcParam::Alpn(_) => 1,
+            SvcParam::NoDefaultAlpn => 2,
+            SvcParam::Port(_) => 3,
+            SvcParam::Ipv4Hint(_) => 4,
+            SvcParam::Ech(_) => 5,
+            SvcParam::Ipv6Hint(_) => 6,
+            SvcParam::Other(key, _) => *key,
+        }
+    }
+
+    /// Value in the wire format
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            SvcParam::Mandatory(keys) => keys.iter().flat_map(|k| k.to_be_bytes()).collect(),
+            SvcParam::Alpn(ids) => ids
+                .iter()
+                .flat_map(|id| [&[id.len() as u8], id.as_bytes()].concat())
+                .collect(),
+            SvcParam::NoDefaultAlpn => Vec::new(),
+            SvcParam::Port(port) => port.to_be_bytes().to_vec(),
+            SvcParam::Ipv4Hint(ips) => ips.iter().flat_map(|ip| ip.octets()).collect(),
+            SvcParam::Ech(config) | SvcParam::Other(_, config) => config.clone(),
+            SvcParam::Ipv6Hint(ips) => ips.iter().flat_map(|ip| ip.octets()).collect(),
+        }
+    }
+
+    fn decode(key: u16, value: &[u8]) -> Result<Self, String> {
+        let invalid = || format!("invalid {} parameter", svc_key_name(key));
+        let fixed = |size: usize| {
+            if value.is_empty() || !value.len().is_multiple_of(size) {
+                Err(invalid())
+            } else {
+                Ok(value.chunks(size))
+            }
+        };
+        Ok(match key {
+            0 => SvcParam::Mandatory(
+                fixed(2)?
+                    .map(|k| u16::from_be_bytes([k[0], k[1]]))
+                    .collect(),
+            ),
+            1 => {
+                let mut ids = Vec::new();
+                let mut rest = value;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let id = tail.get(..len as usize).ok_or_else(invalid)?;
+                    ids.push(String::from_utf8_lossy(id).into_owned());
+                    rest = &tail[len as usize..];
+                }
+                if ids.is_empty() {
+                    return Err(invalid());
+                }
+                SvcParam::Alpn(ids)
+            }
+            2 if value.is_empty() => SvcParam::NoDefaultAlpn,
+            3 if value.len() == 2 => SvcParam::Port(u16::from_be_bytes([value[0], value[1]])),
+            4 => SvcParam::Ipv4Hint(
+                fixed(4)?
+                    .map(|ip| Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()))
+                    .collect(),
+            ),
+            5 => SvcParam::Ech(value.to_vec()),
+            6 => SvcParam::Ipv6Hint(
+                fixed(16)?
+                    .map(|ip| Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()))
+                    .collect(),
+            ),
+            2 | 3 => return Err(invalid()),
+            _ => SvcParam::Other(key, value.to_vec()),
+        })
+    }
+}
+
+/// `key=value` as written by dig, values containing commas are quoted
+impl std::fmt::Display for SvcParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn join<T: ToString>(items: &[T]) -> String {
+            items
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+        let value = match self {
+            SvcParam::Mandatory(keys) => keys
+                .iter()
+                .map(|k| svc_key_name(*k))
+                .collect::<Vec<_>>()
+                .join(","),
+            SvcParam::Alpn(ids) => join(ids),
+            SvcParam::NoDefaultAlpn => return write!(f, "no-default-alpn"),
+            SvcParam::Port(port) => port.to_string(),
+            SvcParam::Ipv4Hint(ips) => join(ips),
+            SvcParam::Ech(config) => STANDARD.encode(config),
+            SvcParam::Ipv6Hint(ips) => join(ips),
+            SvcParam::Other(_, value) => String::from_utf8_lossy(value).into_owned(),
+        };
+        let key = svc_key_name(self.key());
+        if value.contains(',') {
+            write!(f, "{}=\"{}\"", key, value)
+        } else {
+            write!(f, "{}={}", key, value)
+        }
+    }
 }
 
-impl std::str::FromStr for LabelSeq {
-    type Err = DekuError;
+impl std::str::FromStr for SvcParam {
+    type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let labels = s.split('.').map(|l| {
-            let data = l.as_bytes().to_vec();
-            let len = data.len() as u8;
-            Label { len, data }
-        });
-        Ok(LabelSeq::Lables(Labels(labels.collect())))
+        let (name, value) = s.split_once('=').unwrap_or((s, ""));
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        let invalid = || format!("invalid {} {:?}", name, value);
+        let list = || value.split(',').filter(|v| !v.is_empty());
+        let key = svc_key_parse(name)?;
+        let param = match key {
+            0 => SvcParam::Mandatory(list().map(svc_key_parse).collect::<Result<_, _>>()?),
+            // ids are written with a one byte length
+            1 if value.split(',').any(|id| id.is_empty() || id.len() > 255) => {
+                return Err(invalid())
+            }
+            1 => SvcParam::Alpn(list().map(str::to_string).collect()),
+            2 if value.is_empty() => SvcParam::NoDefaultAlpn,
+            3 => SvcParam::Port(value.parse().map_err(|_| invalid())?),
+            4 => SvcParam::Ipv4Hint(
+                list()
+                    .map(|ip| ip.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid())?,
+            ),
+            5 => SvcParam::Ech(STANDARD.decode(value).map_err(|_| invalid())?),
+            6 => SvcParam::Ipv6Hint(
+                list()
+                    .map(|ip| ip.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid())?,
+            ),
+            2 => return Err(invalid()),
+            _ => SvcParam::Other(key, value.as_bytes().to_vec()),
+        };
+        let list = matches!(
+            param,
+            SvcParam::Mandatory(_)
+                | SvcParam::Alpn(_)
+                | SvcParam::Ipv4Hint(_)
+                | SvcParam::Ipv6Hint(_)
+        );
+        if list && param.encode().is_empty() {
+            return Err(invalid());
+        }
+        Ok(param)
     }
 }
 
-impl From<LabelSeq> for String {
-    fn from(value: LabelSeq) -> Self {
-        match value {
-            LabelSeq::Lables(labels) => labels
-                .0
-                .into_iter()
-                .map(|l| String::from_utf8(l.data).unwrap())
-                .reduce(|acc, x| acc + "." + &x)
-                .unwrap(),
-            LabelSeq::Jump(_) => panic!("Jump not supported"),
+fn svc_params_read<R: std::io::Read + std::io::Seek>(
+    reader: &mut Reader<R>,
+) -> Result<Vec<SvcParam>, DekuError> {
+    let mut params: Vec<SvcParam> = Vec::new();
+    while !reader.end() {
+        let key = u16::from_reader_with_ctx(reader, Endian::Big)?;
+        let len = u16::from_reader_with_ctx(reader, Endian::Big)?;
+        let value = (0..len)
+            .map(|_| u8::from_reader_with_ctx(reader, ()))
+            .collect::<Result<Vec<u8>, _>>()?;
+        if params.last().is_some_and(|p| p.key() >= key) {
+            return Err(DekuError::Parse("service parameters out of order".into()));
         }
+        params.push(SvcParam::decode(key, &value).map_err(|e| DekuError::Parse(e.into()))?);
+    }
+    Ok(params)
+}
+
+fn svc_params_write<W: std::io::Write + std::io::Seek>(
+    writer: &mut Writer<W>,
+    params: &[SvcParam],
+) -> Result<(), DekuError> {
+    for param in params {
+        let value = param.encode();
+        let len = u16::try_from(value.len())
+            .map_err(|_| DekuError::InvalidParam("service parameter too long".into()))?;
+        param.key().to_writer(writer, Endian::Big)?;
+        len.to_writer(writer, Endian::Big)?;
+        writer.write_bytes(&value)?;
+    }
+    Ok(())
+}
+
+impl DnsRData {
+    /// Character-strings of a TXT record holding `text`, split as they are at most 255 bytes
+    pub fn txt(text: &[u8]) -> Vec<DnsRData> {
+        if text.is_empty() {
+            return vec![DnsRData::Txt(Vec::new())];
+        }
+        text.chunks(255)
+            .map(|c| DnsRData::Txt(c.to_vec()))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for DnsRData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsRData::IP(ip) => write!(f, "{}", ip),
+            DnsRData::IPv6(ip) => write!(f, "{}", ip),
+            DnsRData::Ns(name) | DnsRData::Cname(name) | DnsRData::Ptr(name) => {
+                write!(f, "{}", name)
+            }
+            DnsRData::Soa(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                soa.mname, soa.rname, soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum
+            ),
+            DnsRData::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            DnsRData::Txt(text) => {
+                write!(f, "\"")?;
+                for &b in text {
+                    match b {
+                        b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+                        0x20..=0x7e => write!(f, "{}", b as char)?,
+                        _ => write!(f, "\\{:03}", b)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            DnsRData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            DnsRData::Svcb(svcb) => write!(f, "{}", svcb),
+            DnsRData::Unknown(data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                for b in data {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+            DnsRData::Opt(option) => {
+                write!(f, "{}=", option.code)?;
+                for b in &option.data {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn rdata_read<R: std::io::Read + std::io::Seek>(
+    reader: &mut Reader<R>,
+    r#type: DnsType,
+    len: u16,
+    names: &mut HashMap<String, u16>,
+) -> Result<Vec<DnsRData>, DekuError> {
+    if let DnsType::Other(_) = r#type {
+        let bytes = (0..len)
+            .map(|_| u8::from_reader_with_ctx(reader, ()))
+            .collect::<Result<Vec<u8>, _>>()?;
+        return Ok(vec![DnsRData::Unknown(bytes)]);
+    }
+    if let DnsType::SVCB | DnsType::HTTPS = r#type {
+        // read on their own, so the parameters end with the record data
+        let bytes = (0..len)
+            .map(|_| u8::from_reader_with_ctx(reader, ()))
+            .collect::<Result<Vec<u8>, _>>()?;
+        let (_rest, svcb) = Svcb::from_bytes((&bytes, 0))?;
+        return Ok(vec![DnsRData::Svcb(svcb)]);
+    }
+    let end = reader.bits_read + len as usize * 8;
+    let mut data = Vec::new();
+    while reader.bits_read < end {
+        data.push(DnsRData::from_reader_with_ctx(
+            reader,
+            (r#type, &mut *names),
+        )?);
+    }
+    if reader.bits_read != end {
+        return Err(DekuError::Parse("record data overruns its length".into()));
+    }
+    Ok(data)
+}
+
+/// Write `data` preceded by its length, patched in afterwards
+fn rdata_write<W: std::io::Write + std::io::Seek>(
+    writer: &mut Writer<W>,
+    r#type: DnsType,
+    data: &[DnsRData],
+    names: &mut HashMap<String, u16>,
+) -> Result<(), DekuError> {
+    let start = writer.stream_position().map_err(io_error)?;
+    0u16.to_writer(writer, Endian::Big)?;
+    for d in data {
+        d.to_writer(writer, (r#type, &mut *names))?;
     }
+    let end = writer.stream_position().map_err(io_error)?;
+    let len = u16::try_from(end - start - 2)
+        .map_err(|_| DekuError::InvalidParam("record data too long".into()))?;
+    writer.seek(SeekFrom::Start(start)).map_err(io_error)?;
+    len.to_writer(writer, Endian::Big)?;
+    writer.seek(SeekFrom::Start(end)).map_err(io_error)?;
+    Ok(())
+}
+
+fn character_string_read<R: std::io::Read + std::io::Seek>(
+    reader: &mut Reader<R>,
+) -> Result<Vec<u8>, DekuError> {
+    let len = u8::from_reader_with_ctx(reader, ())?;
+    (0..len)
+        .map(|_| u8::from_reader_with_ctx(reader, ()))
+        .collect()
+}
+
+fn character_string_write<W: std::io::Write + std::io::Seek>(
+    writer: &mut Writer<W>,
+    text: &[u8],
+) -> Result<(), DekuError> {
+    let len = u8::try_from(text.len())
+        .map_err(|_| DekuError::InvalidParam("character-string longer than 255 bytes".into()))?;
+    len.to_writer(writer, ())?;
+    writer.write_bytes(text)
+}
+
+/// Label
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct Label {
+    #[deku(update = "self.data.len()", bits = 8)]
+    pub len: u8,
+    #[deku(count = "len")]
+    pub data: Vec<u8>,
 }
 
+/// The two high bits of a length byte mark a pointer to a name earlier in the message
+const POINTER: u8 = 0xc0;
+/// Pointers only reach this far
+const MAX_OFFSET: u64 = 0x3fff;
+/// Following more pointers than this means they loop
+const MAX_JUMPS: usize = 32;
+/// Longest name on the wire, length bytes and the root included
+const MAX_NAME_LEN: usize = 255;
+
+fn io_error(e: std::io::Error) -> DekuError {
+    DekuError::Io(e.kind())
+}
+
+/// Read a name, following compression pointers. Names end with a dot, like the root `.`
 fn qname_read<R: std::io::Read + std::io::Seek>(
     reader: &mut Reader<R>,
-    _names: &mut HashMap<String, u8>,
+    _names: &mut HashMap<String, u16>,
 ) -> Result<String, DekuError> {
-    // TODO: reduce jumping
-    let mut jumped = 0;
-    let original = reader.stream_position().unwrap();
-    trace!("Original: {}", original);
-
-    let mut value = LabelSeq::from_reader_with_ctx(reader, ())?;
+    let bits_read = reader.bits_read;
+    let start = reader.stream_position().map_err(io_error)?;
+    let mut resume = None;
+    let mut jumps = 0;
+    let mut name = String::new();
+    let mut wire_len = 1;
     loop {
-        match value {
-            LabelSeq::Jump(offset) => {
-                jumped += 1;
-                reader.seek(SeekFrom::Start(offset as u64)).unwrap();
-                value = LabelSeq::from_reader_with_ctx(reader, ())?;
+        let len = u8::from_reader_with_ctx(reader, ())?;
+        if len & POINTER == POINTER {
+            let low = u8::from_reader_with_ctx(reader, ())?;
+            jumps += 1;
+            if jumps > MAX_JUMPS {
+                return Err(DekuError::Parse("compression pointer loop".into()));
             }
-            LabelSeq::Lables(_) => {
-                if jumped > 0 {
-                    trace!("Jumped: {}", jumped);
-                    reader.seek(SeekFrom::Start(original + 2)).unwrap();
-                    trace!("Forward: {}", reader.stream_position().unwrap());
-                }
-                return Ok(value.into());
+            if resume.is_none() {
+                resume = Some(reader.stream_position().map_err(io_error)?);
             }
+            let offset = u16::from_be_bytes([len & !POINTER, low]);
+            trace!("Jumping to: {}", offset);
+            reader
+                .seek(SeekFrom::Start(offset as u64))
+                .map_err(io_error)?;
+            continue;
+        }
+        // 0x40 and 0x80 are the extended and reserved label types of RFC 6891
+        if len & POINTER != 0 {
+            return Err(DekuError::Parse(
+                format!("unsupported label type {:#04x}", len & POINTER).into(),
+            ));
         }
+        if len == 0 {
+            break;
+        }
+        wire_len += len as usize + 1;
+        if wire_len > MAX_NAME_LEN {
+            return Err(DekuError::Parse("name longer than 255 bytes".into()));
+        }
+        let label: Vec<u8> = (0..len)
+            .map(|_| u8::from_reader_with_ctx(reader, ()))
+            .collect::<Result<_, _>>()?;
+        // names are kept as text, a dot would split the label
+        if label.contains(&b'.') {
+            return Err(DekuError::Parse("label containing a dot".into()));
+        }
+        name.push_str(&String::from_utf8_lossy(&label));
+        name.push('.');
+    }
+    if name.is_empty() {
+        name.push('.');
     }
+    if let Some(resume) = resume {
+        reader.seek(SeekFrom::Start(resume)).map_err(io_error)?;
+        // what was read after jumping is not part of this name
+        reader.bits_read = bits_read + (resume - start) as usize * 8;
+    }
+    Ok(name)
 }
 
+/// Write `name`, pointing to the longest suffix already written to the message
 fn qname_write<W: std::io::Write + std::io::Seek>(
     writer: &mut Writer<W>,
     name: &str,
-    names: &mut HashMap<String, u8>,
+    names: &mut HashMap<String, u16>,
 ) -> Result<(), DekuError> {
-    if let Some(&offset) = names.get(name) {
-        trace!("Mark jumping to: {}", offset);
-        LabelSeq::Jump(offset).to_writer(writer, ())?;
-        return Ok(());
+    name_write(writer, name, Some(names))
+}
+
+/// Write `name`, compressed unless `names` is `None`
+fn name_write<W: std::io::Write + std::io::Seek>(
+    writer: &mut Writer<W>,
+    name: &str,
+    mut names: Option<&mut HashMap<String, u16>>,
+) -> Result<(), DekuError> {
+    let mut rest = name.trim_end_matches('.');
+    while !rest.is_empty() {
+        if let Some(names) = names.as_deref_mut() {
+            if let Some(&offset) = names.get(rest) {
+                trace!("Pointing to: {}", offset);
+                let pointer = u16::from(POINTER) << 8 | offset;
+                return pointer.to_writer(writer, Endian::Big);
+            }
+            let offset = writer.stream_position().map_err(io_error)?;
+            if offset <= MAX_OFFSET {
+                names.insert(rest.to_string(), offset as u16);
+            }
+        }
+        let (label, tail) = rest.split_once('.').unwrap_or((rest, ""));
+        if label.is_empty() || label.len() > 63 {
+            return Err(DekuError::InvalidParam(
+                format!("invalid label in {:?}", name).into(),
+            ));
+        }
+        Label {
+            len: label.len() as u8,
+            data: label.as_bytes().to_vec(),
+        }
+        .to_writer(writer, ())?;
+        rest = tail;
     }
-    let offset = writer.stream_position().unwrap();
-    names.insert(name.to_string(), offset as u8); // FIXME: offset maybe larger than u8
-    let value: LabelSeq = name.parse().unwrap();
-    value.to_writer(writer, ())
+    0u8.to_writer(writer, ())
 }
 
 #[cfg(test)]
@@ -303,41 +1453,75 @@ mod tests {
         assert_eq!(label.len, 0);
     }
 
+    fn read_name(data: &[u8], at: u64) -> (String, usize) {
+        let mut cursor = Cursor::new(data);
+        cursor.seek(SeekFrom::Start(at)).unwrap();
+        let mut reader = Reader::new(&mut cursor);
+        let name = qname_read(&mut reader, &mut HashMap::new()).unwrap();
+        (name, reader.bits_read / 8)
+    }
+
     #[test]
     fn label_sequences() {
         let data: Vec<u8> = vec![
             3, 119, 119, 119, 6, 103, 111, 111, 103, 108, 101, 3, 99, 111, 109, 0,
         ];
-        let seq = LabelSeq::try_from(data.as_slice()).unwrap();
-
-        match seq {
-            LabelSeq::Jump(_) => unreachable!(),
-            LabelSeq::Lables(lables) => {
-                let names: Vec<String> = lables
-                    .0
-                    .into_iter()
-                    .map(|l| String::from_utf8(l.data).unwrap())
-                    .collect();
-                assert_eq!(names, vec!["www", "google", "com", ""]);
-            }
-        }
+        assert_eq!(read_name(&data, 0), ("www.google.com.".to_string(), 16));
+        assert_eq!(read_name(&[0], 0), (".".to_string(), 1));
     }
 
     #[test]
     fn label_sequences_converting() {
-        let target: LabelSeq = "www.google.com.".parse().unwrap();
-        assert_eq!(String::from(target), "www.google.com.".to_string());
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut cursor);
+        let mut names = HashMap::new();
+        qname_write(&mut writer, "www.google.com.", &mut names).unwrap();
+        qname_write(&mut writer, "mail.google.com.", &mut names).unwrap();
+        writer.finalize().unwrap();
+        let data = cursor.into_inner();
+        assert_eq!(&data[16..], &[4, b'm', b'a', b'i', b'l', 0xc0, 4]);
+        assert_eq!(read_name(&data, 0).0, "www.google.com.");
+        assert_eq!(read_name(&data, 16), ("mail.google.com.".to_string(), 7));
     }
 
     #[test]
     fn label_sequences_jump() {
-        let data: Vec<u8> = vec![192, 12];
-        let seq = LabelSeq::try_from(data.as_slice()).unwrap();
-        debug!("{:?}", seq);
-        match seq {
-            LabelSeq::Jump(offset) => assert_eq!(offset, 12),
-            LabelSeq::Lables(_) => unreachable!(),
-        }
+        let mut data: Vec<u8> = vec![0; 12];
+        data.extend([3, 99, 111, 109, 0, 1, 97, 192, 12, 192, 17]);
+        assert_eq!(read_name(&data, 17), ("a.com.".to_string(), 4));
+        assert_eq!(read_name(&data, 21), ("a.com.".to_string(), 2));
+
+        let looping: Vec<u8> = vec![192, 2, 192, 0];
+        let mut cursor = Cursor::new(looping.as_slice());
+        let mut reader = Reader::new(&mut cursor);
+        assert!(qname_read(&mut reader, &mut HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn label_sequences_invalid() {
+        let err = |data: &[u8]| {
+            let mut cursor = Cursor::new(data);
+            let mut reader = Reader::new(&mut cursor);
+            qname_read(&mut reader, &mut HashMap::new())
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err(&[0x41, 0]).contains("unsupported label type 0x40"));
+        assert!(err(&[0x81, 0]).contains("unsupported label type 0x80"));
+        assert!(err(&[3, b'a', b'.', b'b', 0]).contains("label containing a dot"));
+
+        // 4 labels of 63 bytes take 257 bytes with the root, one byte less is allowed
+        let name = |last: u8| {
+            let mut data = Vec::new();
+            for len in [63, 63, 63, last] {
+                data.push(len);
+                data.extend(std::iter::repeat_n(b'a', len as usize));
+            }
+            data.push(0);
+            data
+        };
+        assert!(err(&name(63)).contains("name longer than 255 bytes"));
+        assert_eq!(read_name(&name(61), 0).1, 255);
     }
 
     #[test]
@@ -372,6 +1556,193 @@ mod tests {
         debug!("{:?}", a);
     }
 
+    #[test]
+    fn answer_section_aaaa() {
+        let raw: Vec<u8> = vec![
+            3, 119, 119, 119, 0, // name
+            0x00, 0x1c, // type
+            0x00, 0x01, // class
+            0x00, 0x00, 0x01, 0x25, // ttl
+            0x00, 0x10, // len
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, // address
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        ];
+        let mut cursor = Cursor::new(raw);
+        let mut reader = Reader::new(&mut cursor);
+        let a = DnsRecord::from_reader_with_ctx(&mut reader, &mut HashMap::new()).unwrap();
+        assert_eq!(a.r#type, DnsType::AAAA);
+        match a.data.as_slice() {
+            [DnsRData::IPv6(ip)] => assert_eq!(*ip, "2001:db8::1".parse::<Ipv6Addr>().unwrap()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn soa_record() {
+        let mut packet = DnsPacket::query("nas.home.local.", DnsType::AAAA);
+        packet.authorities.push(DnsRecord::soa(300));
+        let raw = packet.encode().unwrap();
+        let packet = DnsPacket::parse(&raw).unwrap();
+        let soa = &packet.authorities[0];
+        assert_eq!(soa.name, "smart-hosts.");
+        assert_eq!(soa.ttl, 300);
+        assert_eq!(soa.len as usize, raw.len() - 12 - 20 - 13 - 10);
+        assert_eq!(
+            soa.data[0].to_string(),
+            "smart-hosts. hostmaster.smart-hosts. 1 3600 600 86400 300"
+        );
+    }
+
+    #[test]
+    fn txt_mx_srv_records() {
+        let long = "x".repeat(300);
+        let mut packet = DnsPacket {
+            answers: vec![
+                DnsRecord::new(
+                    "home.local.",
+                    DnsType::TXT,
+                    60,
+                    [
+                        DnsRData::txt(b"v=spf1 -all"),
+                        DnsRData::txt(long.as_bytes()),
+                    ]
+                    .concat(),
+                ),
+                DnsRecord::new(
+                    "home.local.",
+                    DnsType::MX,
+                    60,
+                    vec![DnsRData::Mx {
+                        preference: 10,
+                        exchange: "mail.home.local.".to_string(),
+                    }],
+                ),
+                DnsRecord::new(
+                    "_sip._tcp.home.local.",
+                    DnsType::SRV,
+                    60,
+                    vec![DnsRData::Srv {
+                        priority: 10,
+                        weight: 5,
+                        port: 5060,
+                        target: "sip.home.local.".to_string(),
+                    }],
+                ),
+            ],
+            ..Default::default()
+        };
+        let raw = packet.encode().unwrap();
+        let packet = DnsPacket::parse(&raw).unwrap();
+
+        let txt = &packet.answers[0];
+        assert_eq!(txt.len as usize, 12 + 256 + 46);
+        let strings: Vec<String> = txt.data.iter().map(|d| d.to_string()).collect();
+        assert_eq!(strings.len(), 3);
+        assert_eq!(strings[0], "\"v=spf1 -all\"");
+        assert_eq!(strings[1].len(), 257);
+
+        let mx = &packet.answers[1];
+        // the exchange points to the owner name
+        assert_eq!(mx.len, 2 + 5 + 2);
+        assert_eq!(mx.data[0].to_string(), "10 mail.home.local.");
+
+        let srv = &packet.answers[2];
+        // the target is written in full
+        assert_eq!(srv.len, 6 + 16);
+        assert_eq!(srv.data[0].to_string(), "10 5 5060 sip.home.local.");
+        assert_eq!(
+            DnsRData::Txt(b"say \"hi\"\n".to_vec()).to_string(),
+            r#""say \"hi\"\010""#
+        );
+    }
+
+    #[test]
+    fn https_record() {
+        // RFC 9460 appendix D.2, figure 6
+        let rdata = hexdump_to_bytes(
+            r#"
+        00 10 03 66 6f 6f 07 65  78 61 6d 70 6c 65 03 6f
+        72 67 00 00 00 00 04 00  01 00 04 00 01 00 09 02
+        68 32 05 68 33 2d 31 39  00 04 00 04 c0 00 02 01
+        "#,
+        );
+        let mut raw = vec![
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'o', b'r', b'g', 0,
+        ];
+        raw.extend([
+            0x00,
+            0x41,
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+            0x0e,
+            0x10,
+            0x00,
+            rdata.len() as u8,
+        ]);
+        raw.extend(&rdata);
+        let mut cursor = Cursor::new(raw.as_slice());
+        let mut reader = Reader::new(&mut cursor);
+        let record = DnsRecord::from_reader_with_ctx(&mut reader, &mut HashMap::new()).unwrap();
+        assert_eq!(record.r#type, DnsType::HTTPS);
+        assert_eq!(
+            record.data[0].to_string(),
+            r#"16 foo.example.org. mandatory="alpn,ipv4hint" alpn="h2,h3-19" ipv4hint=192.0.2.1"#
+        );
+
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = Writer::new(&mut cursor);
+        record.to_writer(&mut writer, &mut HashMap::new()).unwrap();
+        writer.finalize().unwrap();
+        assert_eq!(cursor.into_inner(), raw);
+
+        let params: Vec<SvcParam> = [
+            "port=8443",
+            "ech=AEX+DQBB",
+            "ipv6hint=2001:db8::1",
+            "key65=x",
+        ]
+        .iter()
+        .map(|p| p.parse().unwrap())
+        .collect();
+        assert_eq!(
+            params[1],
+            SvcParam::Ech(vec![0x00, 0x45, 0xfe, 0x0d, 0x00, 0x41])
+        );
+        let svcb = Svcb {
+            priority: 1,
+            target: ".".to_string(),
+            params,
+        };
+        let mut packet = DnsPacket {
+            answers: vec![DnsRecord::new(
+                "example.org.",
+                DnsType::SVCB,
+                60,
+                vec![DnsRData::Svcb(svcb.clone())],
+            )],
+            ..Default::default()
+        };
+        let packet = DnsPacket::parse(&packet.encode().unwrap()).unwrap();
+        assert_eq!(packet.answers[0].data, vec![DnsRData::Svcb(svcb)]);
+        assert_eq!(
+            packet.answers[0].data[0].to_string(),
+            "1 . port=8443 ech=AEX+DQBB ipv6hint=2001:db8::1 key65=x"
+        );
+
+        assert!("alpn=".parse::<SvcParam>().is_err());
+        assert!(r#"alpn="h2,,h3""#.parse::<SvcParam>().is_err());
+        assert!(format!("alpn={}", "h".repeat(256))
+            .parse::<SvcParam>()
+            .is_err());
+        assert!(format!("alpn={}", "h".repeat(255))
+            .parse::<SvcParam>()
+            .is_ok());
+        assert!("port=http".parse::<SvcParam>().is_err());
+        assert!("color=red".parse::<SvcParam>().is_err());
+    }
+
     #[test]
     fn parse_query() {
         let raw = hexdump_to_bytes(
@@ -384,11 +1755,11 @@ mod tests {
         let mut reader = Reader::new(&mut cursor);
 
         let header = DnsHeader::from_reader_with_ctx(&mut reader, ()).unwrap();
-        debug!("{:?}", header);
+        debug!("{}", header);
 
         reader.rewind().unwrap();
         let packet = DnsPacket::from_reader_with_ctx(&mut reader, &mut HashMap::new()).unwrap();
-        debug!("{:?}", packet);
+        debug!("{}", packet);
     }
 
     #[test]
@@ -403,11 +1774,159 @@ mod tests {
         let mut cursor = Cursor::new(raw);
         let mut reader = Reader::new(&mut cursor);
         let header = DnsHeader::from_reader_with_ctx(&mut reader, ()).unwrap();
-        debug!("{:?}", header);
+        debug!("{}", header);
 
         reader.rewind().unwrap();
         let packet = DnsPacket::from_reader_with_ctx(&mut reader, &mut HashMap::new()).unwrap();
-        debug!("{:?}", packet);
+        debug!("{}", packet);
+    }
+
+    #[test]
+    fn presentation() {
+        let raw = hexdump_to_bytes(
+            r#"
+        29 7e 81 80 00 01 00 01  00 00 00 00 06 67 6f 6f
+        67 6c 65 03 63 6f 6d 00  00 01 00 01 c0 0c 00 01
+        00 01 00 00 00 53 00 04  7f 00 00 01
+        "#,
+        );
+        let packet = DnsPacket::parse(&raw).unwrap();
+        assert_eq!(
+            packet.to_string(),
+            "\
+;; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 10622
+;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 0
+
+;; QUESTION SECTION:
+;google.com. IN A
+
+;; ANSWER SECTION:
+google.com. 83 IN A 127.0.0.1"
+        );
+
+        for line in [
+            "google.com. 83 IN A 127.0.0.1",
+            r#"home.local. 60 IN TXT "v=spf1 -all" "say \"hi\"""#,
+            "_sip._tcp.home.local. 60 IN SRV 10 5 5060 sip.home.local.",
+            r#"home.local. 300 IN HTTPS 1 . alpn="h2,h3" port=8443"#,
+        ] {
+            let record: DnsRecord = line.parse().unwrap();
+            assert_eq!(record.to_string(), line);
+        }
+        let record: DnsRecord = "Google.com.\t83\tIN\tAAAA\t::1".parse().unwrap();
+        assert_eq!(record.to_string(), "google.com. 83 IN AAAA ::1");
+        assert_eq!(
+            "google.com. IN A 127.0.0.1"
+                .parse::<DnsRecord>()
+                .unwrap_err(),
+            "missing ttl"
+        );
+    }
+
+    #[test]
+    fn unknown_types() {
+        // www.github.com CNAME github.com, with a compressed target, and a type 65280 record
+        let raw = hexdump_to_bytes(
+            r#"
+        00 01 81 80 00 01 00 02  00 00 00 00 03 77 77 77
+        06 67 69 74 68 75 62 03  63 6f 6d 00 00 01 00 01
+        c0 0c 00 05 00 01 00 00  0e 10 00 02 c0 10 c0 10
+        ff 00 00 01 00 00 00 3c  00 03 0a 00 01
+        "#,
+        );
+        let mut packet = DnsPacket::parse(&raw).unwrap();
+        assert_eq!(
+            packet.answers[0].to_string(),
+            "www.github.com. 3600 IN CNAME github.com."
+        );
+        assert_eq!(
+            packet.answers[1].to_string(),
+            "github.com. 60 IN TYPE65280 \\# 3 0a0001"
+        );
+        assert_eq!(packet.encode().unwrap(), raw);
+
+        assert_eq!(DnsType::from(12), DnsType::PTR);
+        assert_eq!(DnsType::from(257), DnsType::Other(257));
+        assert_eq!(u16::from(DnsType::Other(257)), 257);
+        assert_eq!(u16::from(DnsType::AAAA), 28);
+        assert_eq!("type1".parse(), Ok(DnsType::A));
+        assert_eq!("TYPE255".parse(), Ok(DnsType::Other(255)));
+        assert!("TYPE65536".parse::<DnsType>().is_err());
+
+        let record: DnsRecord = "1.1.168.192.in-addr.arpa. 60 IN PTR nas.home.local."
+            .parse()
+            .unwrap();
+        assert_eq!(
+            record.data,
+            vec![DnsRData::Ptr("nas.home.local.".to_string())]
+        );
+    }
+
+    #[test]
+    fn edns() {
+        // dig example.com with a cookie
+        let raw = hexdump_to_bytes(
+            r#"
+        12 34 01 20 00 01 00 00  00 00 00 01 07 65 78 61
+        6d 70 6c 65 03 63 6f 6d  00 00 01 00 01 00 00 29
+        04 d0 00 00 00 00 00 0c  00 0a 00 08 01 02 03 04
+        05 06 07 08
+        "#,
+        );
+        let mut packet = DnsPacket::parse(&raw).unwrap();
+        assert_eq!(packet.header.opcode, Opcode::Query);
+        assert_eq!(packet.edns_version(), Some(0));
+        let opt = packet.edns().unwrap();
+        assert_eq!(opt.class, DnsClass::Other(1232));
+        assert_eq!(
+            opt.data,
+            vec![DnsRData::Opt(EdnsOption::new(
+                10,
+                vec![1, 2, 3, 4, 5, 6, 7, 8]
+            ))]
+        );
+        assert_eq!(packet.encode().unwrap(), raw);
+
+        // BADVERS is 16, only its lower 4 bits fit in the header
+        packet.header.qr = true;
+        packet.header.rcode = Rcode::BadVers;
+        let encoded = packet.encode().unwrap();
+        assert_eq!(encoded[3] & 0x0f, 0);
+        assert_eq!(packet.edns().unwrap().ttl, 0x0100_0000);
+        let (_rest, header) = DnsHeader::from_bytes((&encoded, 0)).unwrap();
+        assert_eq!(header.rcode, Rcode::NoError);
+        let packet = DnsPacket::parse(&encoded).unwrap();
+        assert_eq!(packet.header.rcode, Rcode::BadVers);
+        assert_eq!(packet.rcode(), Rcode::BadVers);
+        assert!(packet.to_string().starts_with(
+            ";; ->>HEADER<<- opcode: QUERY, status: BADVERS, id: 4660\n\
+             ;; flags: qr rd; QUERY: 1, ANSWER: 0, AUTHORITY: 0, ADDITIONAL: 1\n\n\
+             ;; OPT PSEUDOSECTION:\n\
+             ; EDNS: version: 0, flags:; udp: 1232"
+        ));
+
+        // an OPT record is added for extended codes
+        let mut packet = DnsPacket {
+            header: DnsHeader {
+                rcode: Rcode::BadCookie,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let packet = DnsPacket::parse(&packet.encode().unwrap()).unwrap();
+        assert_eq!(packet.edns().unwrap().class, DnsClass::Other(EDNS_PAYLOAD));
+        assert_eq!(packet.rcode(), Rcode::BadCookie);
+
+        // NOTIFY, and opcodes without a name
+        for (flags, opcode) in [(0x20, Opcode::Notify), (0x78, Opcode::Other(15))] {
+            let raw = [0, 1, flags, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            let (_rest, header) = DnsHeader::from_bytes((&raw, 0)).unwrap();
+            assert_eq!(header.opcode, opcode);
+            assert_eq!(header.to_bytes().unwrap(), raw);
+        }
+        assert_eq!(Opcode::Other(15).to_string(), "OPCODE15");
+        assert_eq!(Rcode::from(23), Rcode::BadCookie);
+        assert_eq!(u16::from(Rcode::Other(3841)), 3841);
     }
 
     #[test]
@@ -416,13 +1935,13 @@ mod tests {
             header: DnsHeader {
                 id: 0x297e,
                 qr: true,
-                opcode: 0,
+                opcode: Opcode::Query,
                 aa: false,
                 tc: false,
                 rd: true,
                 ra: true,
                 z: 0,
-                rcode: 0,
+                rcode: Rcode::NoError,
                 qdcount: 1,
                 ancount: 1,
                 nscount: 0,
@@ -0,0 +1,584 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::{IpAddr, SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::Incoming, header, service::service_fn, HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use rustls::ServerConfig;
+use serde_json::{json, Value};
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::condition::Cidr;
+use crate::core::*;
+use crate::resolver::Resolver;
+
+/// ALPN protocol identifiers offered by the HTTPS listener
+pub const ALPN: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Serve DNS-over-HTTPS (RFC 8484) on every listener,
+/// listeners without TLS config speak plain HTTP.
+/// Requests from `trusted_proxies` are answered for the client they were forwarded for.
+/// Runs its own runtime and blocks the calling thread.
+pub fn serve(
+    listeners: Vec<(TcpListener, Option<Arc<ServerConfig>>)>,
+    trusted_proxies: Vec<Cidr>,
+    resolver: Arc<Resolver>,
+) -> io::Result<()> {
+    let trusted_proxies: Arc<[Cidr]> = trusted_proxies.into();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut tasks = Vec::new();
+        for (listener, tls) in listeners {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let tls = tls.map(TlsAcceptor::from);
+            tasks.push(tokio::spawn(accept(
+                listener,
+                tls,
+                trusted_proxies.clone(),
+                resolver.clone(),
+            )));
+        }
+        for task in tasks {
+            task.await.map_err(io::Error::other)?;
+        }
+        Ok(())
+    })
+}
+
+async fn accept(
+    listener: tokio::net::TcpListener,
+    tls: Option<TlsAcceptor>,
+    trusted_proxies: Arc<[Cidr]>,
+    resolver: Arc<Resolver>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!(%e, "failed to accept connection");
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let client = client_of(req.headers(), peer, &trusted_proxies);
+                handle(req, resolver.clone(), client)
+            });
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => {
+                        builder
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await
+                    }
+                    Err(e) => {
+                        debug!(%peer, %e, "TLS handshake failed");
+                        return;
+                    }
+                },
+                None => {
+                    builder
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                }
+            };
+            if let Err(e) = result {
+                debug!(%peer, %e, "connection closed");
+            }
+        });
+    }
+}
+
+/// Client a request was forwarded for, `peer` itself unless it is a trusted proxy.
+/// Hops are read from the last one, the first not trusted being the client.
+fn client_of(headers: &HeaderMap, peer: SocketAddr, trusted_proxies: &[Cidr]) -> SocketAddr {
+    let trusted = |addr: &SocketAddr| trusted_proxies.iter().any(|n| n.contains(&addr.ip()));
+    if !trusted(&peer) {
+        return peer;
+    }
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    // `for=` of RFC 7239, falling back to the de facto X-Forwarded-For
+    let forwarded: Vec<Option<SocketAddr>> = values("forwarded")
+        .into_iter()
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node.trim_matches('"')))
+        })
+        .collect();
+    let hops = if forwarded.is_empty() {
+        values("x-forwarded-for")
+            .into_iter()
+            .map(parse_node)
+            .collect()
+    } else {
+        forwarded
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(hop) if trusted(&client) => client = hop,
+            // an obfuscated or unknown hop hides those before it
+            _ => break,
+        }
+    }
+    client
+}
+
+/// Address of a forwarded hop, with port 0 unless it is given
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    node.parse().ok().or_else(|| {
+        let addr: IpAddr = node
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok()?;
+        Some(SocketAddr::new(addr, 0))
+    })
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    resolver: Arc<Resolver>,
+    peer: SocketAddr,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = respond(req, resolver, peer).await.unwrap_or_else(|status| {
+        Response::builder()
+            .status(status)
+            .body(Full::default())
+            .unwrap()
+    });
+    Ok(response)
+}
+
+async fn respond(
+    req: Request<Incoming>,
+    resolver: Arc<Resolver>,
+    peer: SocketAddr,
+) -> Result<Response<Full<Bytes>>, StatusCode> {
+    if !matches!(req.uri().path(), "/dns-query" | "/resolve") {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let params = query_params(req.uri().query().unwrap_or_default());
+    let param = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+
+    let query = match *req.method() {
+        Method::GET => {
+            if let Some(name) = param("name") {
+                return resolve_json(name, param("type").unwrap_or("A"), resolver, peer).await;
+            }
+            let dns = param("dns").ok_or(StatusCode::BAD_REQUEST)?;
+            URL_SAFE_NO_PAD
+                .decode(dns.trim_end_matches('='))
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+        }
+        Method::POST => {
+            let content_type = req.headers().get(header::CONTENT_TYPE);
+            if content_type.and_then(|v| v.to_str().ok()) != Some(DNS_MESSAGE) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            Limited::new(req.into_body(), MAX_MESSAGE_SIZE)
+                .collect()
+                .await
+                .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?
+                .to_bytes()
+                .to_vec()
+        }
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let response = resolve(query, resolver, peer).await?;
+    let mut builder = Response::builder().header(header::CONTENT_TYPE, DNS_MESSAGE);
+    if let Some(ttl) = DnsPacket::parse(&response)
+        .ok()
+        .and_then(|p| p.answers.iter().map(|r| r.ttl).min())
+    {
+        builder = builder.header(header::CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+    Ok(builder.body(Full::new(Bytes::from(response))).unwrap())
+}
+
+/// JSON API in the format popularized by Google and Cloudflare
+async fn resolve_json(
+    name: &str,
+    r#type: &str,
+    resolver: Arc<Resolver>,
+    peer: SocketAddr,
+) -> Result<Response<Full<Bytes>>, StatusCode> {
+    let name = if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    };
+    let r#type = parse_type(r#type).ok_or(StatusCode::BAD_REQUEST)?;
+    let query = DnsPacket {
+        header: DnsHeader {
+            rd: true,
+            ..Default::default()
+        },
+        questions: vec![DnsQuestion {
+            name: name.clone(),
+            r#type,
+            class: DnsClass::In,
+        }],
+        ..Default::default()
+    }
+    .encode()
+    .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let response = resolve(query, resolver, peer).await?;
+    // an upstream response that does not parse is no answer
+    let packet = DnsPacket::parse(&response).map_err(|_| StatusCode::BAD_GATEWAY)?;
+    let header = &packet.header;
+    let mut body = json!({
+        "Status": u16::from(packet.rcode()),
+        "TC": header.tc,
+        "RD": header.rd,
+        "RA": header.ra,
+        "AD": false,
+        "CD": false,
+        "Question": [{ "name": name, "type": u16::from(r#type) }],
+    });
+    if !packet.answers.is_empty() {
+        body["Answer"] = packet
+            .answers
+            .iter()
+            .map(|r| {
+                json!({
+                    "name": r.name,
+                    "type": u16::from(r.r#type),
+                    "TTL": r.ttl,
+                    "data": r.data.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(" "),
+                })
+            })
+            .collect::<Value>();
+    }
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, DNS_JSON)
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap())
+}
+
+async fn resolve(
+    query: Vec<u8>,
+    resolver: Arc<Resolver>,
+    peer: SocketAddr,
+) -> Result<Vec<u8>, StatusCode> {
+    tokio::task::spawn_blocking(move || resolver.handle(&query, peer))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// Type by mnemonic or number
+fn parse_type(s: &str) -> Option<DnsType> {
+    if let Ok(r#type) = s.parse() {
+        return Some(r#type);
+    }
+    s.parse::<u16>().ok().map(DnsType::from)
+}
+
+fn query_params(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (key, value) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let hex = bytes
+            .get(idx + 1..idx + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[idx], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                idx += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::RwLock,
+        thread,
+    };
+
+    use hyper::client::conn::http2;
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    fn start(tls: Option<Arc<ServerConfig>>) -> SocketAddr {
+        start_with("127.0.0.1 *.home.local\n::1 *.home.local", tls)
+    }
+
+    fn start_with(config: &str, tls: Option<Arc<ServerConfig>>) -> SocketAddr {
+        let config: crate::config::Config = config.parse().unwrap();
+        let trusted_proxies = config.doh.trusted_proxies.clone();
+        let resolver = Arc::new(Resolver::new(
+            config,
+            Arc::new(RwLock::new(Default::default())),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(vec![(listener, tls)], trusted_proxies, resolver));
+        addr
+    }
+
+    fn query(name: &str) -> Vec<u8> {
+        DnsPacket::query(name, DnsType::A).encode().unwrap()
+    }
+
+    /// Plain HTTP/1.1 request, returns the status line and body
+    fn request(addr: SocketAddr, head: &str, body: &[u8]) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let head = format!(
+            "{}\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+            head,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).unwrap();
+
+        let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(raw[..split].to_vec()).unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, raw[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn wire_format() {
+        let addr = start(None);
+
+        let dns = URL_SAFE_NO_PAD.encode(query("nas.home.local."));
+        let (status, body) = request(addr, &format!("GET /dns-query?dns={} HTTP/1.1", dns), &[]);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let packet = DnsPacket::parse(&body).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+
+        let head = format!("POST /dns-query HTTP/1.1\r\nContent-Type: {}", DNS_MESSAGE);
+        let (status, body) = request(addr, &head, &query("tv.home.local."));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let packet = DnsPacket::parse(&body).unwrap();
+        assert_eq!(packet.answers[0].name, "tv.home.local.");
+
+        let head = "POST /dns-query HTTP/1.1\r\nContent-Type: text/plain";
+        let (status, _) = request(addr, head, &query("tv.home.local."));
+        assert_eq!(status, "HTTP/1.1 415 Unsupported Media Type");
+
+        let (status, _) = request(addr, "GET /dns-query?dns=%%% HTTP/1.1", &[]);
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+
+        let (status, _) = request(addr, "GET / HTTP/1.1", &[]);
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn json() {
+        let addr = start(None);
+
+        let (status, body) = request(
+            addr,
+            "GET /resolve?name=nas.home.local&type=AAAA HTTP/1.1",
+            &[],
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["Status"], 0);
+        assert_eq!(body["Question"][0]["name"], "nas.home.local.");
+        assert_eq!(body["Answer"][0]["type"], 28);
+        assert_eq!(body["Answer"][0]["data"], "::1");
+
+        let (status, _) = request(
+            addr,
+            "GET /resolve?name=nas.home.local&type=NOPE HTTP/1.1",
+            &[],
+        );
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn json_cname_chain() {
+        // upstream answering www.example.com with a chain to a CDN
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let mut packet = DnsPacket::parse(&buf[..size]).unwrap();
+                packet.header.qr = true;
+                packet.answers = vec![
+                    "www.example.com. 300 IN CNAME www.example.com.cdn.example.net.",
+                    "www.example.com.cdn.example.net. 60 IN CNAME edge.example.net.",
+                    "edge.example.net. 20 IN A 192.0.2.1",
+                ]
+                .into_iter()
+                .map(|r| r.parse().unwrap())
+                .collect();
+                socket.send_to(&packet.encode().unwrap(), source).unwrap();
+            }
+        });
+        let addr = start_with(&format!("upstream {}", upstream), None);
+
+        let (status, body) = request(
+            addr,
+            "GET /resolve?name=www.example.com&type=A HTTP/1.1",
+            &[],
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["Status"], 0);
+        assert_eq!(body["Answer"].as_array().unwrap().len(), 3);
+        assert_eq!(body["Answer"][0]["type"], 5);
+        assert_eq!(
+            body["Answer"][0]["data"],
+            "www.example.com.cdn.example.net."
+        );
+        assert_eq!(body["Answer"][2]["data"], "192.0.2.1");
+    }
+
+    #[test]
+    fn forwarded() {
+        let addr = start_with(
+            "http-trusted-proxy 127.0.0.1\n\
+             10.0.0.1 nas.home.local, client=\"192.168.20.0/24\"\n\
+             127.0.0.1 nas.home.local",
+            None,
+        );
+        let answer = |headers: &str| {
+            let head = format!("GET /resolve?name=nas.home.local HTTP/1.1{}", headers);
+            let (_, body) = request(addr, &head, &[]);
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            body["Answer"][0]["data"].as_str().unwrap().to_string()
+        };
+        assert_eq!(answer(""), "127.0.0.1");
+        assert_eq!(answer("\r\nX-Forwarded-For: 192.168.20.5"), "10.0.0.1");
+        assert_eq!(
+            answer("\r\nForwarded: for=192.168.20.5;proto=http, for=127.0.0.1"),
+            "10.0.0.1"
+        );
+
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let headers = |name: &str, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+            headers
+        };
+        let client = |headers: &HeaderMap, peer: &str| {
+            client_of(headers, peer.parse().unwrap(), &trusted).to_string()
+        };
+        let spoofed = headers("x-forwarded-for", "192.0.2.1, 198.51.100.7, 10.0.0.2");
+        // the rightmost hop not trusted is the client, whatever it claims
+        assert_eq!(client(&spoofed, "10.0.0.1:4000"), "198.51.100.7:0");
+        // untrusted peers are not believed
+        assert_eq!(client(&spoofed, "203.0.113.9:4000"), "203.0.113.9:4000");
+        let forwarded = headers("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#);
+        assert_eq!(client(&forwarded, "10.0.0.1:4000"), "[2001:db8::1]:4711");
+        let unknown = headers("forwarded", "for=unknown");
+        assert_eq!(client(&unknown, "10.0.0.1:4000"), "10.0.0.1:4000");
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(
+            query_params("name=a%2Eb+c&dns"),
+            vec![
+                ("name".to_string(), "a.b c".to_string()),
+                ("dns".to_string(), "".to_string())
+            ]
+        );
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+
+    #[tokio::test]
+    async fn http2() {
+        let (tls, client) = crate::tls::tests::self_signed(ALPN);
+        let addr = start(Some(tls));
+
+        let mut config = (*client).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        let (mut sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::post("https://localhost/dns-query")
+            .header(header::CONTENT_TYPE, DNS_MESSAGE)
+            .body(Full::new(Bytes::from(query("nas.home.local."))))
+            .unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=3600");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(DnsPacket::parse(&body).unwrap().answers.len(), 1);
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use tracing::debug;
+
+use crate::resolver::Resolver;
+
+/// ALPN protocol identifier of DNS-over-TLS
+pub const ALPN: &[u8] = b"dot";
+
+/// Serve DNS over TCP (RFC 7766) on `listener`, as DNS-over-TLS (RFC 7858) when given `tls`,
+/// one thread per connection
+pub fn serve(
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    idle_timeout: Duration,
+    resolver: Arc<Resolver>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!(%e, "failed to accept connection");
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let resolver = resolver.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = handle_connection(stream, tls, idle_timeout, &resolver) {
+                debug!(?peer, %e, "connection closed");
+            }
+        });
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    idle_timeout: Duration,
+    resolver: &Resolver,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(idle_timeout))?;
+    stream.set_write_timeout(Some(idle_timeout))?;
+    match tls {
+        Some(tls) => {
+            let conn = ServerConnection::new(tls).map_err(io::Error::other)?;
+            exchange(StreamOwned::new(conn, stream), peer, resolver)
+        }
+        None => exchange(stream, peer, resolver),
+    }
+}
+
+fn exchange(
+    mut stream: impl Read + Write,
+    peer: SocketAddr,
+    resolver: &Resolver,
+) -> io::Result<()> {
+    // Queries are length prefixed, the connection is reused until the client is gone or idle
+    loop {
+        let mut len = [0; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+        let mut query = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut query)?;
+
+        if let Some(response) = resolver.handle(&query, peer) {
+            let mut buf = Vec::with_capacity(response.len() + 2);
+            buf.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&response);
+            stream.write_all(&buf)?;
+            stream.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use rustls::ClientConnection;
+
+    use super::*;
+    use crate::core::*;
+
+    fn query(name: &str) -> Vec<u8> {
+        let query = DnsPacket::query(name, DnsType::A).encode().unwrap();
+        let mut buf = (query.len() as u16).to_be_bytes().to_vec();
+        buf.extend(query);
+        buf
+    }
+
+    fn read_response(stream: &mut impl Read) -> io::Result<DnsPacket> {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf)?;
+        Ok(DnsPacket::parse(&buf).unwrap())
+    }
+
+    #[test]
+    fn self_signed() {
+        let (tls, client) = crate::tls::tests::self_signed(&[ALPN]);
+
+        let config = "127.0.0.1 *.home.local".parse().unwrap();
+        let resolver = Arc::new(Resolver::new(
+            config,
+            Arc::new(RwLock::new(Default::default())),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, Some(tls), Duration::from_millis(500), resolver));
+
+        let mut config = (*client).clone();
+        config.alpn_protocols = vec![ALPN.to_vec()];
+        let conn =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+
+        // both queries share the connection
+        for name in ["nas.home.local.", "tv.home.local."] {
+            stream.write_all(&query(name)).unwrap();
+            let response = read_response(&mut stream).unwrap();
+            assert_eq!(response.answers.len(), 1);
+            assert_eq!(response.answers[0].name, name);
+        }
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"dot"[..]));
+
+        // closed by the server once idle
+        thread::sleep(Duration::from_secs(1));
+        stream.write_all(&query("nas.home.local.")).ok();
+        assert!(read_response(&mut stream).is_err());
+    }
+
+    #[test]
+    fn plain() {
+        let config = "127.0.0.1 *.home.local".parse().unwrap();
+        let resolver = Arc::new(Resolver::new(
+            config,
+            Arc::new(RwLock::new(Default::default())),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, None, Duration::from_millis(500), resolver));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for name in ["nas.home.local.", "tv.home.local."] {
+            stream.write_all(&query(name)).unwrap();
+            let response = read_response(&mut stream).unwrap();
+            assert_eq!(response.answers[0].name, name);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::net::IpAddr;
+
+use tracing::debug;
+
+/// Entries of a file in the classic `/etc/hosts` format, an address followed by names.
+/// Lines with an address that does not parse, like scoped `fe80::1%lo0`, are skipped.
+pub fn entries(source: &str) -> Vec<(IpAddr, Vec<&str>)> {
+    let mut entries = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        let line = line.split_once('#').map_or(line, |(line, _)| line);
+        let mut words = line.split_whitespace();
+        let Some(address) = words.next() else {
+            continue;
+        };
+        let names: Vec<&str> = words.collect();
+        match address.parse() {
+            Ok(address) if !names.is_empty() => entries.push((address, names)),
+            _ => debug!(line = idx + 1, address, "skipped hosts entry"),
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classic_format() {
+        let source = "\
+# comment
+127.0.0.1\tlocalhost
+::1             localhost ip6-localhost  # trailing comment
+fe80::1%lo0     localhost
+192.168.1.10    nas.home.local nas
+
+10.0.0.1
+";
+        let entries = entries(source);
+        assert_eq!(
+            entries,
+            vec![
+                ("127.0.0.1".parse().unwrap(), vec!["localhost"]),
+                ("::1".parse().unwrap(), vec!["localhost", "ip6-localhost"]),
+                (
+                    "192.168.1.10".parse().unwrap(),
+                    vec!["nas.home.local", "nas"]
+                ),
+            ]
+        );
+    }
+}
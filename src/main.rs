@@ -1,13 +1,43 @@
+mod blocklist;
+mod cache;
+mod condition;
+mod config;
+mod control;
 mod core;
+mod doh;
+mod dot;
+mod hosts;
 mod logging;
+mod metrics;
 mod monitor;
+mod probe;
+mod query;
+mod querylog;
+mod remote;
+mod resolver;
+mod schedule;
+mod tls;
+mod upstream;
+mod zonefile;
 
-use std::{collections::HashMap, io::Cursor, net::UdpSocket};
+use std::{
+    net::{SocketAddr, TcpListener, UdpSocket},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
 
-use deku::prelude::*;
-use tracing::debug;
+use tracing::{debug, error, info, warn};
 
-use crate::core::*;
+use crate::config::Config;
+use crate::monitor::NetworkState;
+use crate::resolver::Resolver;
+
+/// Threads answering UDP queries, enough to wait on slow upstreams
+const UDP_WORKERS: usize = 32;
+/// Queries waiting for a worker, more are dropped and left to the client to retry
+const UDP_QUEUE: usize = 1024;
 
 #[cfg(test)]
 #[cfg(feature = "debug")]
@@ -19,54 +49,169 @@ fn init() {
 fn main() {
     crate::logging::setup_console_log();
 
-    let mut m = crate::monitor::Monitor::new();
+    let mut args = std::env::args().skip(1);
+    let path = args.next();
+    if path.as_deref() == Some("ctl") {
+        std::process::exit(control::cli(args));
+    }
+    if path.as_deref() == Some("query") {
+        std::process::exit(query::cli(args));
+    }
+    let path = path.unwrap_or_else(|| "smart_hosts.conf".to_string());
+    let config = match Config::load(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(path, %e, "failed to load config");
+            std::process::exit(1);
+        }
+    };
+
+    let state = Arc::new(RwLock::new(NetworkState::default()));
+    let mut m = crate::monitor::Monitor::new(state.clone());
     m.start();
 
+    let tls = config.tls.clone();
+    let doh = config.doh.clone();
+    let metrics_listen = config.metrics_listen;
+    let control_socket = config.control_socket.clone();
+    let upstream_tls = match crate::tls::client_config(config.upstream_ca.as_deref()) {
+        Ok(upstream_tls) => upstream_tls,
+        Err(e) => {
+            error!(ca = ?config.upstream_ca, %e, "failed to load upstream CA");
+            std::process::exit(1);
+        }
+    };
+    let query_log = config.query_log.dir.is_some().then(|| {
+        match crate::querylog::QueryLog::open(&config.query_log) {
+            Ok(query_log) => query_log,
+            Err(e) => {
+                error!(dir = ?config.query_log.dir, %e, "failed to open query log");
+                std::process::exit(1);
+            }
+        }
+    });
+    let mut resolver = Resolver::new(config, state).with_upstream_tls(upstream_tls);
+    if let Some(query_log) = query_log {
+        resolver = resolver.with_query_log(query_log);
+    }
+    let resolver = Arc::new(resolver);
+
+    let path = PathBuf::from(path);
+    match control::bind(&control_socket) {
+        Ok(listener) => {
+            let resolver = resolver.clone();
+            let path = path.clone();
+            thread::spawn(move || control::serve(listener, resolver, path));
+        }
+        Err(e) => error!(socket = ?control_socket, %e, "failed to start control API"),
+    }
+
+    {
+        let resolver = resolver.clone();
+        thread::spawn(move || {
+            crate::config::watch(&path, Duration::from_secs(2), |config| {
+                info!(?path, "config reloaded");
+                resolver.reload(config);
+            })
+        });
+    }
+
+    {
+        let resolver = resolver.clone();
+        thread::spawn(move || probe::run(resolver));
+    }
+
+    {
+        let resolver = resolver.clone();
+        thread::spawn(move || remote::run(resolver));
+    }
+
+    let load_certificate = |alpn: &[&[u8]]| {
+        let (Some(cert), Some(key)) = (&tls.cert, &tls.key) else {
+            error!("tls-cert and tls-key are required by tls-listen and https-listen");
+            std::process::exit(1);
+        };
+        match crate::tls::server_config(cert, key, alpn) {
+            Ok(server_config) => server_config,
+            Err(e) => {
+                error!(?cert, ?key, %e, "failed to load certificate");
+                std::process::exit(1);
+            }
+        }
+    };
+
+    if let Some(listen) = tls.listen {
+        let server_config = load_certificate(&[dot::ALPN]);
+        let listener = TcpListener::bind(listen).expect("Failed to bind to address");
+        let resolver = resolver.clone();
+        thread::spawn(move || {
+            dot::serve(listener, Some(server_config), tls.idle_timeout, resolver)
+        });
+    }
+
+    let mut doh_listeners = Vec::new();
+    if let Some(listen) = doh.https {
+        let server_config = load_certificate(doh::ALPN);
+        let listener = TcpListener::bind(listen).expect("Failed to bind to address");
+        doh_listeners.push((listener, Some(server_config)));
+    }
+    if let Some(listen) = doh.http {
+        let listener = TcpListener::bind(listen).expect("Failed to bind to address");
+        doh_listeners.push((listener, None));
+    }
+    if !doh_listeners.is_empty() {
+        let resolver = resolver.clone();
+        thread::spawn(move || {
+            if let Err(e) = doh::serve(doh_listeners, doh.trusted_proxies, resolver) {
+                error!(%e, "DNS-over-HTTPS server stopped");
+            }
+        });
+    }
+
+    if let Some(listen) = metrics_listen {
+        let listener = TcpListener::bind(listen).expect("Failed to bind to address");
+        let resolver = resolver.clone();
+        thread::spawn(move || metrics::serve(listener, resolver));
+    }
+
+    // truncated UDP responses are retried over TCP on the same port
+    {
+        let listener = TcpListener::bind("127.0.0.1:2053").expect("Failed to bind to address");
+        let resolver = resolver.clone();
+        let idle_timeout = tls.idle_timeout;
+        thread::spawn(move || dot::serve(listener, None, idle_timeout, resolver));
+    }
+
     let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; 512];
+    let mut buf = [0; crate::core::EDNS_PAYLOAD as usize];
+
+    let (queue, queries) = mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(UDP_QUEUE);
+    let queries = Arc::new(Mutex::new(queries));
+    for _ in 0..UDP_WORKERS {
+        let queries = queries.clone();
+        let resolver = resolver.clone();
+        let socket = udp_socket.try_clone().expect("Failed to clone socket");
+        thread::spawn(move || loop {
+            let Ok((query, source)) = queries.lock().unwrap().recv() else {
+                return;
+            };
+            if let Some(response) = resolver.handle_udp(&query, source) {
+                debug!("Sending response...");
+                if let Err(e) = socket.send_to(&response, source) {
+                    warn!(%source, %e, "failed to send response");
+                }
+            }
+        });
+    }
 
     loop {
         match udp_socket.recv_from(&mut buf) {
             Ok((size, source)) => {
                 debug!("Received {} bytes from {}", size, source);
 
-                let (_res, mut hdr) = DnsHeader::from_bytes((&buf, 0)).unwrap();
-
-                debug!("Received DNS Header: {:?}", hdr);
-
-                hdr.qr = true;
-
-                debug!("Sending response...");
-                let mut packet = DnsPacket {
-                    header: hdr,
-                    questions: vec![DnsQuestion {
-                        name: "www.google.com.".parse().unwrap(),
-                        r#type: DnsType::A,
-                        class: DnsClass::In,
-                    }],
-                    answers: vec![DnsRecord {
-                        name: "www.google.com.".parse().unwrap(),
-                        r#type: DnsType::A,
-                        class: DnsClass::In,
-                        ttl: 3600,
-                        len: 4,
-                        data: vec![DnsRData::IP("127.0.0.1".parse().unwrap())],
-                    }],
-                    ..Default::default()
-                };
-                packet.header.qdcount = 1;
-                packet.header.ancount = 1;
-
-                debug!("Response: {:?}", packet);
-
-                let mut buf = Vec::new();
-                let cursor = Cursor::new(&mut buf);
-                let mut writer = Writer::new(cursor);
-
-                packet.to_writer(&mut writer, &mut HashMap::new()).unwrap();
-                udp_socket
-                    .send_to(&buf, source)
-                    .expect("Failed to send response");
+                if queue.try_send((buf[..size].to_vec(), source)).is_err() {
+                    warn!(%source, "too many queries waiting, dropped one");
+                }
             }
             Err(e) => {
                 debug!("Error receiving data: {}", e);
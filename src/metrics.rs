@@ -0,0 +1,344 @@
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tracing::debug;
+
+use crate::core::{rcode_of, DnsType};
+use crate::monitor::{InterfaceType, NetworkState};
+use crate::probe::Target;
+use crate::resolver::{Resolver, Source};
+use crate::upstream::Upstream;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Prometheus metrics of the resolver
+pub struct Metrics {
+    registry: Registry,
+    queries: IntCounterVec,
+    answers: IntCounterVec,
+    cache: IntCounterVec,
+    upstream_latency: HistogramVec,
+    upstream_failures: IntCounterVec,
+    parse_errors: IntCounter,
+    reloads: IntCounter,
+    interface: IntGaugeVec,
+    ssid_hash: IntGauge,
+    probes: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let queries = IntCounterVec::new(
+            Opts::new("smart_hosts_queries_total", "Handled queries"),
+            &["type", "rcode"],
+        )
+        .unwrap();
+        let answers = IntCounterVec::new(
+            Opts::new(
+                "smart_hosts_answers_total",
+                "Queries by what answered them, a rule, an upstream, a blocklist or the cache",
+            ),
+            &["source"],
+        )
+        .unwrap();
+        let cache = IntCounterVec::new(
+            Opts::new(
+                "smart_hosts_cache_lookups_total",
+                "Cache lookups of forwarded queries",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "smart_hosts_upstream_duration_seconds",
+                "Time taken by upstreams to answer",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+        let upstream_failures = IntCounterVec::new(
+            Opts::new(
+                "smart_hosts_upstream_failures_total",
+                "Failed upstream exchanges",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+        let parse_errors = IntCounter::new(
+            "smart_hosts_parse_errors_total",
+            "Queries the codec failed to parse",
+        )
+        .unwrap();
+        let reloads =
+            IntCounter::new("smart_hosts_config_reloads_total", "Config reloads").unwrap();
+        let interface = IntGaugeVec::new(
+            Opts::new(
+                "smart_hosts_network_interface",
+                "Interface type of the current network path",
+            ),
+            &["type"],
+        )
+        .unwrap();
+        let ssid_hash = IntGauge::new(
+            "smart_hosts_network_ssid_hash",
+            "FNV-1a hash of the current SSID, 0 without Wi-Fi",
+        )
+        .unwrap();
+
+        let probes = IntGaugeVec::new(
+            Opts::new(
+                "smart_hosts_probe_reachable",
+                "Whether a target of the reachable conditions answers probes",
+            ),
+            &["target"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(queries.clone())).unwrap();
+        registry.register(Box::new(answers.clone())).unwrap();
+        registry.register(Box::new(cache.clone())).unwrap();
+        registry
+            .register(Box::new(upstream_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(parse_errors.clone())).unwrap();
+        registry.register(Box::new(reloads.clone())).unwrap();
+        registry.register(Box::new(interface.clone())).unwrap();
+        registry.register(Box::new(ssid_hash.clone())).unwrap();
+        registry.register(Box::new(probes.clone())).unwrap();
+        Self {
+            registry,
+            queries,
+            answers,
+            cache,
+            upstream_latency,
+            upstream_failures,
+            parse_errors,
+            reloads,
+            interface,
+            ssid_hash,
+            probes,
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a handled query, `r#type` is `None` if it could not be parsed. Types without
+    /// a mnemonic share the `other` label, so clients cannot add a series per number.
+    pub fn query(&self, r#type: Option<DnsType>, response: Option<&[u8]>, source: &Source) {
+        let r#type = match r#type {
+            None => "unknown".to_string(),
+            Some(DnsType::Other(_)) => "other".to_string(),
+            Some(r#type) => r#type.to_string(),
+        };
+        let rcode = response
+            .and_then(rcode_of)
+            .map(|rcode| rcode.to_string())
+            .unwrap_or_else(|| "none".to_string());
+        self.queries.with_label_values(&[&r#type, &rcode]).inc();
+        let source = match source {
+            Source::Rule(_) | Source::Record(_) => "rule",
+            Source::Upstream(_) => "upstream",
+            Source::Blocked(_) => "blocklist",
+            Source::Zone(_) => "zone",
+            Source::Cache => "cache",
+            Source::Nothing => "none",
+        };
+        self.answers.with_label_values(&[source]).inc();
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache.with_label_values(&[result]).inc();
+    }
+
+    pub fn upstream_exchange(&self, upstream: &Upstream, latency: Duration, ok: bool) {
+        let upstream = upstream.to_string();
+        if ok {
+            self.upstream_latency
+                .with_label_values(&[&upstream])
+                .observe(latency.as_secs_f64());
+        } else {
+            self.upstream_failures.with_label_values(&[&upstream]).inc();
+        }
+    }
+
+    pub fn parse_error(&self) {
+        self.parse_errors.inc();
+    }
+
+    pub fn reload(&self) {
+        self.reloads.inc();
+    }
+
+    /// Replace the probe results, targets no longer probed are dropped
+    pub fn probes(&self, results: &[(Target, bool)]) {
+        self.probes.reset();
+        for (target, reachable) in results {
+            self.probes
+                .with_label_values(&[&target.to_string()])
+                .set(*reachable as i64);
+        }
+    }
+
+    /// Render in the Prometheus text format, network gauges are taken from `state`
+    pub fn render(&self, state: &NetworkState) -> String {
+        for r#type in [
+            InterfaceType::Other,
+            InterfaceType::Wifi,
+            InterfaceType::Cellular,
+            InterfaceType::Wired,
+        ] {
+            self.interface
+                .with_label_values(&[r#type.as_str()])
+                .set((state.interface == r#type) as i64);
+        }
+        self.ssid_hash
+            .set(state.ssid.as_deref().map(fnv1a).unwrap_or_default() as i64);
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+}
+
+/// Stable across restarts unlike the std hasher, so dashboards can tell networks apart
+fn fnv1a(s: &str) -> u32 {
+    s.bytes().fold(0x811c9dc5, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Serve the metrics of `resolver` on `GET /metrics`
+pub fn serve(listener: TcpListener, resolver: Arc<Resolver>) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| handle_connection(stream, &resolver));
+        if let Err(e) = result {
+            debug!(%e, "metrics request failed");
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, resolver: &Resolver) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    // only the request line matters, the rest of the head is read and ignored
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 || head.len() > 8192 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", resolver.render_metrics()),
+        (Some("GET"), _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        prometheus::TEXT_FORMAT,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::RwLock, thread};
+
+    use super::*;
+    use crate::core::*;
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn scrape() {
+        let config = "127.0.0.1 *.home.local".parse().unwrap();
+        let state = Arc::new(RwLock::new(NetworkState {
+            ssid: Some("home".to_string()),
+            interface: InterfaceType::Wifi,
+            ..Default::default()
+        }));
+        let resolver = Arc::new(Resolver::new(config, state));
+        let query = DnsPacket::query("nas.home.local.", DnsType::A)
+            .encode()
+            .unwrap();
+        let client = "127.0.0.1:5353".parse().unwrap();
+        resolver.handle(&query, client).unwrap();
+        resolver.handle(&query[..13], client).unwrap();
+        // BADVERS only fits in the header along with the OPT record
+        let mut edns = DnsPacket::parse(&query).unwrap();
+        let mut opt = DnsRecord::opt();
+        opt.ttl = 1 << 16;
+        edns.additional.push(opt);
+        resolver.handle(&edns.encode().unwrap(), client).unwrap();
+        let mut other = DnsPacket::parse(&query).unwrap();
+        other.questions[0].r#type = DnsType::Other(65280);
+        resolver.handle(&other.encode().unwrap(), client).unwrap();
+        resolver.set_reachability(vec![("tcp://192.168.1.10:445".parse().unwrap(), true)]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, resolver));
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            r#"smart_hosts_queries_total{rcode="NOERROR",type="A"} 1"#,
+            r#"smart_hosts_queries_total{rcode="FORMERR",type="unknown"} 1"#,
+            r#"smart_hosts_queries_total{rcode="BADVERS",type="A"} 1"#,
+            r#"smart_hosts_queries_total{rcode="NOERROR",type="other"} 1"#,
+            r#"smart_hosts_answers_total{source="rule"} 2"#,
+            r#"smart_hosts_answers_total{source="none"} 2"#,
+            "smart_hosts_parse_errors_total 1",
+            "smart_hosts_config_reloads_total 0",
+            r#"smart_hosts_network_interface{type="wifi"} 1"#,
+            r#"smart_hosts_network_interface{type="cellular"} 0"#,
+            &format!("smart_hosts_network_ssid_hash {}", fnv1a("home")),
+            r#"smart_hosts_probe_reachable{target="tcp://192.168.1.10:445"} 1"#,
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "{} not in {}",
+                line,
+                response
+            );
+        }
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn ssid_hash() {
+        assert_eq!(fnv1a(""), 0x811c9dc5);
+        assert_eq!(fnv1a("a"), 0xe40c292c);
+    }
+}
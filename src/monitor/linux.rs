@@ -0,0 +1,362 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use futures::{StreamExt, TryStreamExt};
+use netlink_packet_route::{
+    nlas::{
+        address::Nla as AddressNla,
+        link::{Info, InfoKind, Nla as LinkNla},
+        neighbour::Nla as NeighbourNla,
+        route::Nla as RouteNla,
+    },
+    IFF_LOOPBACK, IFF_UP, RT_TABLE_LOCAL,
+};
+use netlink_sys::{AsyncSocket, SocketAddr};
+use rtnetlink::{
+    constants::{
+        RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE, RTMGRP_LINK,
+        RTMGRP_NEIGH,
+    },
+    Handle, IpVersion,
+};
+use tracing::{debug, error, warn};
+
+use super::{InterfaceType, MacAddr, Monitor, NetworkState};
+
+/// Changes tend to come in bursts, e.g. link up then addresses then routes
+const SETTLE: Duration = Duration::from_millis(200);
+
+/// Interface name prefixes of VPN tunnels
+const TUNNEL_PREFIXES: &[&str] = &["tun", "tap", "wg", "ppp", "utun", "ipsec", "tailscale"];
+
+impl Monitor {
+    pub fn new(state: Arc<RwLock<NetworkState>>) -> Self {
+        Self { state }
+    }
+
+    /// Follow link, address, route and neighbour changes through rtnetlink
+    pub fn start(&mut self) {
+        let state = self.state.clone();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            if let Err(e) = runtime.block_on(watch(state)) {
+                error!(%e, "network monitor stopped");
+            }
+        });
+    }
+}
+
+async fn watch(state: Arc<RwLock<NetworkState>>) -> io::Result<()> {
+    let (mut connection, handle, mut messages) = rtnetlink::new_connection()?;
+    let groups = RTMGRP_LINK
+        | RTMGRP_NEIGH
+        | RTMGRP_IPV4_IFADDR
+        | RTMGRP_IPV6_IFADDR
+        | RTMGRP_IPV4_ROUTE
+        | RTMGRP_IPV6_ROUTE;
+    connection
+        .socket_mut()
+        .socket_mut()
+        .bind(&SocketAddr::new(0, groups))?;
+    tokio::spawn(connection);
+
+    loop {
+        match snapshot(&handle).await {
+            Ok(current) => {
+                let mut state = state.write().unwrap();
+                if *state != current {
+                    debug!(?current, "network state changed");
+                    *state = current;
+                }
+            }
+            Err(e) => warn!(%e, "failed to read network state"),
+        }
+
+        // the messages only tell something changed, everything is read again once they settle
+        if messages.next().await.is_none() {
+            return Ok(());
+        }
+        tokio::time::sleep(SETTLE).await;
+        while messages.try_recv().is_ok() {}
+    }
+}
+
+#[derive(Debug, Default)]
+struct Link {
+    index: u32,
+    name: String,
+    up: bool,
+    loopback: bool,
+    tunnel: bool,
+    wireless: bool,
+}
+
+#[derive(Debug)]
+struct DefaultRoute {
+    oif: Option<u32>,
+    gateway: Option<IpAddr>,
+    priority: u32,
+}
+
+async fn snapshot(handle: &Handle) -> Result<NetworkState, rtnetlink::Error> {
+    let links: Vec<Link> = handle
+        .link()
+        .get()
+        .execute()
+        .map_ok(|msg| {
+            let mut link = Link {
+                index: msg.header.index,
+                up: msg.header.flags & IFF_UP != 0,
+                loopback: msg.header.flags & IFF_LOOPBACK != 0,
+                ..Default::default()
+            };
+            for nla in msg.nlas {
+                match nla {
+                    LinkNla::IfName(name) => link.name = name,
+                    LinkNla::Info(infos) => {
+                        link.tunnel |= infos
+                            .iter()
+                            .any(|i| matches!(i, Info::Kind(InfoKind::Wireguard | InfoKind::Tun)))
+                    }
+                    _ => {}
+                }
+            }
+            link.tunnel |= TUNNEL_PREFIXES.iter().any(|p| link.name.starts_with(p));
+            link.wireless = Path::new("/sys/class/net")
+                .join(&link.name)
+                .join("wireless")
+                .exists();
+            link
+        })
+        .try_collect()
+        .await?;
+
+    let addresses: Vec<(u32, IpAddr)> = handle
+        .address()
+        .get()
+        .execute()
+        .try_filter_map(|msg| async move {
+            let address = msg.nlas.iter().find_map(|nla| match nla {
+                AddressNla::Local(bytes) | AddressNla::Address(bytes) => ip_addr(bytes),
+                _ => None,
+            });
+            Ok(address.map(|a| (msg.header.index, a)))
+        })
+        .try_collect()
+        .await?;
+
+    let mut routes = Vec::new();
+    for version in [IpVersion::V4, IpVersion::V6] {
+        let mut stream = handle.route().get(version).execute();
+        while let Some(msg) = stream.try_next().await? {
+            if msg.header.destination_prefix_length != 0 || msg.header.table == RT_TABLE_LOCAL {
+                continue;
+            }
+            let priority = msg
+                .nlas
+                .iter()
+                .find_map(|nla| match nla {
+                    RouteNla::Priority(priority) => Some(*priority),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            routes.push(DefaultRoute {
+                oif: msg.output_interface(),
+                gateway: msg.gateway(),
+                priority,
+            });
+        }
+    }
+
+    let neighbours: Vec<(IpAddr, MacAddr)> = handle
+        .neighbours()
+        .get()
+        .execute()
+        .try_filter_map(|msg| async move {
+            let destination = msg.nlas.iter().find_map(|nla| match nla {
+                NeighbourNla::Destination(bytes) => ip_addr(bytes),
+                _ => None,
+            });
+            let mac = msg.nlas.iter().find_map(|nla| match nla {
+                NeighbourNla::LinkLocalAddress(bytes) => MacAddr::try_from(&bytes[..]).ok(),
+                _ => None,
+            });
+            Ok(destination.zip(mac))
+        })
+        .try_collect()
+        .await?;
+
+    Ok(network_state(links, &addresses, routes, &neighbours))
+}
+
+fn ip_addr(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).into()),
+        _ => None,
+    }
+}
+
+fn network_state(
+    links: Vec<Link>,
+    addresses: &[(u32, IpAddr)],
+    mut routes: Vec<DefaultRoute>,
+    neighbours: &[(IpAddr, MacAddr)],
+) -> NetworkState {
+    let links: Vec<Link> = links.into_iter().filter(|l| l.up && !l.loopback).collect();
+    let link = |index: Option<u32>| links.iter().find(|l| Some(l.index) == index);
+    routes.retain(|r| link(r.oif).is_some());
+    routes.sort_by_key(|r| r.priority);
+
+    // the type of the interface carrying traffic, looking through VPN tunnels
+    let primary = routes
+        .iter()
+        .filter_map(|r| link(r.oif))
+        .find(|l| !l.tunnel);
+    let interface = match primary {
+        None => InterfaceType::Other,
+        Some(l) if l.name.starts_with("wwan") => InterfaceType::Cellular,
+        Some(l) if l.wireless => InterfaceType::Wifi,
+        Some(l) if l.name.starts_with("en") || l.name.starts_with("eth") => InterfaceType::Wired,
+        Some(_) => InterfaceType::Other,
+    };
+
+    let mut gateways: Vec<IpAddr> = Vec::new();
+    for gateway in routes.iter().filter_map(|r| r.gateway) {
+        if !gateways.contains(&gateway) {
+            gateways.push(gateway);
+        }
+    }
+    let gateway_macs = gateways
+        .iter()
+        .filter_map(|g| {
+            neighbours
+                .iter()
+                .find(|(ip, _)| ip == g)
+                .map(|(_, mac)| *mac)
+        })
+        .collect();
+
+    NetworkState {
+        ssid: None,
+        cellular: interface == InterfaceType::Cellular,
+        interface,
+        addresses: addresses
+            .iter()
+            .filter(|(index, _)| link(Some(*index)).is_some())
+            .map(|(_, addr)| *addr)
+            .collect(),
+        gateways,
+        gateway_macs,
+        vpn: links.iter().any(|l| l.tunnel),
+        interfaces: links.into_iter().map(|l| l.name).collect(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(index: u32, name: &str) -> Link {
+        Link {
+            index,
+            name: name.to_string(),
+            up: true,
+            tunnel: TUNNEL_PREFIXES.iter().any(|p| name.starts_with(p)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn wired_with_vpn() {
+        let links = vec![
+            Link {
+                loopback: true,
+                ..link(1, "lo")
+            },
+            link(2, "enp3s0"),
+            link(3, "wg0"),
+            Link {
+                up: false,
+                wireless: true,
+                ..link(4, "wlan0")
+            },
+        ];
+        let addresses = [
+            (1, "127.0.0.1".parse().unwrap()),
+            (2, "10.1.2.3".parse().unwrap()),
+            (3, "10.8.0.2".parse().unwrap()),
+        ];
+        let routes = vec![
+            DefaultRoute {
+                oif: Some(3),
+                gateway: None,
+                priority: 0,
+            },
+            DefaultRoute {
+                oif: Some(2),
+                gateway: Some("10.1.0.1".parse().unwrap()),
+                priority: 100,
+            },
+            DefaultRoute {
+                oif: Some(4),
+                gateway: Some("192.168.1.1".parse().unwrap()),
+                priority: 600,
+            },
+        ];
+        let mac = "a4:91:b1:05:0e:01".parse().unwrap();
+        let neighbours = [("10.1.0.1".parse().unwrap(), mac)];
+
+        let state = network_state(links, &addresses, routes, &neighbours);
+        assert_eq!(state.interface, InterfaceType::Wired);
+        assert_eq!(state.interfaces, vec!["enp3s0", "wg0"]);
+        assert_eq!(
+            state.addresses,
+            vec![
+                "10.1.2.3".parse::<IpAddr>().unwrap(),
+                "10.8.0.2".parse().unwrap()
+            ]
+        );
+        assert_eq!(state.gateways, vec!["10.1.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(state.gateway_macs, vec![mac]);
+        assert!(state.vpn);
+        assert!(!state.cellular);
+    }
+
+    #[test]
+    fn wireless() {
+        let links = vec![Link {
+            wireless: true,
+            ..link(2, "wlp2s0")
+        }];
+        let routes = vec![DefaultRoute {
+            oif: Some(2),
+            gateway: Some("192.168.1.1".parse().unwrap()),
+            priority: 600,
+        }];
+        let state = network_state(links, &[], routes, &[]);
+        assert_eq!(state.interface, InterfaceType::Wifi);
+        assert!(state.gateway_macs.is_empty());
+        assert!(!state.vpn);
+    }
+
+    #[test]
+    fn monitoring() {
+        let state = Arc::new(RwLock::new(NetworkState::default()));
+        let mut m = Monitor::new(state.clone());
+        m.start();
+
+        thread::sleep(Duration::from_secs(1));
+        debug!(state = ?state.read().unwrap(), "network state");
+    }
+}
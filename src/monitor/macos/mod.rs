@@ -1,7 +1,10 @@
 #![allow(non_camel_case_types)]
 
+use std::net::IpAddr;
 use std::os::raw::c_void;
-use std::sync::mpsc;
+use std::process::Command;
+use std::sync::{mpsc, Arc, RwLock};
+use std::{ffi::CStr, ptr, thread};
 
 use dispatch::{Queue, QueuePriority};
 use objc2_core_location::CLLocationManager;
@@ -14,76 +17,183 @@ extern "C" {
     fn nw_release(obj: *mut c_void) -> *mut c_void;
 }
 
+mod nw_endpoint;
 mod nw_interface;
 mod nw_path;
 mod nw_path_monitor;
 
-use super::Monitor;
+use super::{InterfaceType, MacAddr, Monitor, NetworkState};
+pub use nw_endpoint::*;
 pub use nw_interface::*;
 pub use nw_path::*;
 pub use nw_path_monitor::*;
 
 impl Monitor {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(state: Arc<RwLock<NetworkState>>) -> Self {
+        Self { state }
     }
     pub fn start(&mut self) {
-        let mut monitor = NWPathMonitor::create();
+        let state = self.state.clone();
+        thread::spawn(move || {
+            let mut monitor = NWPathMonitor::create();
 
-        let (tx, rx) = mpsc::channel();
-        monitor.set_update_handler(move |path| {
-            debug!(?path, "received path");
-            tx.send(path).unwrap();
-        });
+            let (tx, rx) = mpsc::channel();
+            monitor.set_update_handler(move |path| {
+                debug!(?path, "received path");
+                tx.send(path).unwrap();
+            });
 
-        monitor.set_queue(Queue::global(QueuePriority::Low));
+            monitor.set_queue(Queue::global(QueuePriority::Low));
 
-        monitor.start();
+            monitor.start();
 
-        for mut path in rx {
-            if path.uses_wifi() {
-                debug!("wifi connected");
-            } else if path.uses_cellular() {
-                debug!("cellular connected");
-            } else if path.uses_wired() {
-                debug!("wired connected");
-            } else {
-                debug!("unknown connection");
+            for mut path in rx {
+                let interface = interface_type(&mut path);
+                let mut interfaces = interfaces(&mut path);
+                let ssid = match interfaces.iter_mut().position(|i| i.is_wifi()) {
+                    Some(idx) if interface == InterfaceType::Wifi => {
+                        current_ssid(&mut interfaces[idx])
+                    }
+                    _ => None,
+                };
+                let vpn = interfaces.iter_mut().any(is_tunnel);
+                let names: Vec<String> = interfaces
+                    .iter_mut()
+                    .filter_map(|i| {
+                        (i.get_type() != NWInterfaceType::LOOPBACK).then(|| i.get_name())
+                    })
+                    .collect();
+                let gateways = gateways(&mut path);
+                let current = NetworkState {
+                    ssid,
+                    cellular: path.uses_cellular(),
+                    interface,
+                    addresses: addresses(&names),
+                    gateway_macs: gateways.iter().filter_map(gateway_mac).collect(),
+                    gateways,
+                    interfaces: names,
+                    vpn,
+                    ..Default::default()
+                };
+                let mut state = state.write().unwrap();
+                if *state != current {
+                    debug!(?current, "network state changed");
+                    *state = current;
+                }
             }
-            let (tx, rx) = mpsc::channel();
+        });
+    }
+}
 
-            path.enumerate_interfaces(move |interface| {
-                debug!(?interface, "interface");
-                tx.send(interface).unwrap();
-                true
-            });
+fn interface_type(path: &mut NWPath) -> InterfaceType {
+    let interface = if path.uses_wifi() {
+        InterfaceType::Wifi
+    } else if path.uses_cellular() {
+        InterfaceType::Cellular
+    } else if path.uses_wired() {
+        InterfaceType::Wired
+    } else {
+        InterfaceType::Other
+    };
+    debug!(?interface, "connected");
+    interface
+}
 
-            // tx will be dropped after enumerate_interfaces done, so rx is safe to iterate
-            for mut interface in rx {
-                if interface.is_wifi() {
-                    let name = interface.get_name();
-                    debug!(?name, "wifi interface");
-
-                    unsafe {
-                        let manager = CLLocationManager::new();
-                        manager.startUpdatingLocation();
-                        let status = manager.authorizationStatus();
-                        debug!(?status, "location status");
-
-                        let cli = CWWiFiClient::sharedWiFiClient();
-                        if let Some(interface) =
-                            cli.interfaceWithName(Some(&NSString::from_str(&name)))
-                        {
-                            let ssid = interface.ssid();
-                            println!("ssid: {:?}", ssid);
-                            debug!(?ssid, "ssid");
-                        } else {
-                            debug!("failed to get interface");
-                        }
-                    }
-                    return;
-                }
+/// Interface name prefixes of VPN tunnels, they have the "other" type
+const TUNNEL_PREFIXES: &[&str] = &["utun", "ipsec", "ppp"];
+
+fn is_tunnel(interface: &mut NWInterface) -> bool {
+    let name = interface.get_name();
+    interface.get_type() == NWInterfaceType::OTHER
+        && TUNNEL_PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+fn interfaces(path: &mut NWPath) -> Vec<NWInterface> {
+    let (tx, rx) = mpsc::channel();
+
+    path.enumerate_interfaces(move |interface| {
+        debug!(?interface, "interface");
+        tx.send(interface).unwrap();
+        true
+    });
+
+    // tx will be dropped after enumerate_interfaces done, so rx is safe to iterate
+    rx.into_iter().collect()
+}
+
+fn gateways(path: &mut NWPath) -> Vec<IpAddr> {
+    let (tx, rx) = mpsc::channel();
+
+    path.enumerate_gateways(move |mut gateway| {
+        if let Some(addr) = gateway.get_address() {
+            tx.send(addr).unwrap();
+        }
+        true
+    });
+
+    let mut gateways: Vec<IpAddr> = Vec::new();
+    for gateway in rx {
+        if !gateways.contains(&gateway) {
+            gateways.push(gateway);
+        }
+    }
+    debug!(?gateways, "gateways");
+    gateways
+}
+
+/// Addresses of the interfaces named `names`
+fn addresses(names: &[String]) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    unsafe {
+        let mut ifaddrs = ptr::null_mut();
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            debug!(e = %std::io::Error::last_os_error(), "failed to list addresses");
+            return addresses;
+        }
+        let mut ifaddr = ifaddrs;
+        while let Some(ifa) = ifaddr.as_ref() {
+            let name = CStr::from_ptr(ifa.ifa_name).to_string_lossy();
+            if names.iter().any(|n| *n == name) {
+                addresses.extend(sockaddr_ip(ifa.ifa_addr));
             }
+            ifaddr = ifa.ifa_next;
+        }
+        libc::freeifaddrs(ifaddrs);
+    }
+    addresses
+}
+
+/// Look the gateway up in the ARP cache, it is there once anything went through it
+fn gateway_mac(gateway: &IpAddr) -> Option<MacAddr> {
+    let output = Command::new("/usr/sbin/arp")
+        .args(["-n", &gateway.to_string()])
+        .output()
+        .ok()?;
+    // ? (192.168.1.1) at a4:91:b1:5:e:1 on en0 ifscope [ethernet]
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut words = output.split_whitespace();
+    words.find(|w| *w == "at")?;
+    words.next()?.parse().ok()
+}
+
+fn current_ssid(interface: &mut NWInterface) -> Option<String> {
+    let name = interface.get_name();
+    debug!(?name, "wifi interface");
+
+    unsafe {
+        let manager = CLLocationManager::new();
+        manager.startUpdatingLocation();
+        let status = manager.authorizationStatus();
+        debug!(?status, "location status");
+
+        let cli = CWWiFiClient::sharedWiFiClient();
+        if let Some(interface) = cli.interfaceWithName(Some(&NSString::from_str(&name))) {
+            let ssid = interface.ssid();
+            debug!(?ssid, "ssid");
+            ssid.map(|ssid| ssid.to_string())
+        } else {
+            debug!("failed to get interface");
+            None
         }
     }
 }
@@ -94,7 +204,11 @@ mod tests {
 
     #[test]
     fn monitoring() {
-        let mut m = Monitor::new();
+        let state = Arc::new(RwLock::new(NetworkState::default()));
+        let mut m = Monitor::new(state.clone());
         m.start();
+
+        std::thread::sleep(std::time::Duration::from_secs(10));
+        debug!(state = ?state.read().unwrap(), "network state");
     }
 }
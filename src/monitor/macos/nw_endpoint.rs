@@ -0,0 +1,64 @@
+#![allow(non_camel_case_types)]
+#![allow(clippy::missing_safety_doc)]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use objc2::runtime::ProtocolObject;
+use objc2::{extern_protocol, ProtocolType};
+use objc2_foundation::NSObjectProtocol;
+
+use super::{nw_release, nw_retain};
+
+extern_protocol!(
+    pub(crate) unsafe trait OS_nw_endpoint: NSObjectProtocol {}
+    unsafe impl ProtocolType for dyn OS_nw_endpoint {}
+);
+pub(crate) type nw_endpoint_t = ProtocolObject<dyn OS_nw_endpoint>;
+
+extern "C" {
+    fn nw_endpoint_get_address(endpoint: *mut nw_endpoint_t) -> *const libc::sockaddr;
+}
+
+#[derive(Debug)]
+pub struct NWEndpoint {
+    raw: *mut nw_endpoint_t,
+}
+
+impl NWEndpoint {
+    pub(crate) fn new(raw: *mut nw_endpoint_t) -> Self {
+        unsafe {
+            nw_retain(raw.cast());
+        }
+        Self { raw }
+    }
+
+    /// Address of an address endpoint, `None` for host, service or URL endpoints
+    pub fn get_address(&mut self) -> Option<IpAddr> {
+        unsafe { sockaddr_ip(nw_endpoint_get_address(self.raw)) }
+    }
+}
+
+impl Drop for NWEndpoint {
+    fn drop(&mut self) {
+        unsafe {
+            nw_release(self.raw.cast());
+        }
+    }
+}
+
+pub(crate) unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match (*addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(Ipv6Addr::from(addr.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
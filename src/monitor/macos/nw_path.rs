@@ -8,7 +8,9 @@ use objc2::runtime::{Bool, ProtocolObject};
 use objc2::{extern_protocol, ProtocolType};
 use objc2_foundation::NSObjectProtocol;
 
-use super::{nw_interface_t, nw_release, nw_retain, NWInterface, NWInterfaceType};
+use super::{
+    nw_endpoint_t, nw_interface_t, nw_release, nw_retain, NWEndpoint, NWInterface, NWInterfaceType,
+};
 
 extern_protocol!(
     pub(crate) unsafe trait OS_nw_path: NSObjectProtocol {}
@@ -16,6 +18,7 @@ extern_protocol!(
 );
 pub(crate) type nw_path_t = ProtocolObject<dyn OS_nw_path>;
 pub(crate) type nw_path_enumerate_interfaces_block_t = Block<dyn Fn(*mut nw_interface_t) -> Bool>;
+pub(crate) type nw_path_enumerate_gateways_block_t = Block<dyn Fn(*mut nw_endpoint_t) -> Bool>;
 
 extern "C" {
     fn nw_path_uses_interface_type(path: *mut nw_path_t, interface_type: NWInterfaceType) -> bool;
@@ -23,6 +26,10 @@ extern "C" {
         path: *mut nw_path_t,
         enumerate_block: &nw_path_enumerate_interfaces_block_t,
     ) -> c_void;
+    fn nw_path_enumerate_gateways(
+        path: *mut nw_path_t,
+        enumerate_block: &nw_path_enumerate_gateways_block_t,
+    ) -> c_void;
 }
 
 #[derive(Debug)]
@@ -66,6 +73,19 @@ impl NWPath {
             nw_path_enumerate_interfaces(self.raw, &block);
         }
     }
+
+    pub fn enumerate_gateways(
+        &mut self,
+        enumerate_block: impl Fn(NWEndpoint) -> bool + std::clone::Clone + 'static,
+    ) {
+        unsafe {
+            let block = StackBlock::new(move |e| {
+                let e = NWEndpoint::new(e);
+                Bool::from(enumerate_block(e))
+            });
+            nw_path_enumerate_gateways(self.raw, &block);
+        }
+    }
 }
 
 impl Drop for NWPath {
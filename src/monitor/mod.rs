@@ -1,7 +1,139 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
-pub struct Monitor {}
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
+use chrono::{DateTime, Utc};
+
+use crate::probe::Target;
+
+/// Kind of interface the current path goes through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InterfaceType {
+    #[default]
+    Other,
+    Wifi,
+    Cellular,
+    Wired,
+}
+
+impl InterfaceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InterfaceType::Other => "other",
+            InterfaceType::Wifi => "wifi",
+            InterfaceType::Cellular => "cellular",
+            InterfaceType::Wired => "wired",
+        }
+    }
+}
+
+/// Network conditions observed by the monitor, rules are evaluated against it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkState {
+    /// SSID of the connected Wi-Fi network
+    pub ssid: Option<String>,
+    /// Whether the current path uses a cellular interface
+    pub cellular: bool,
+    /// Interface type of the current path
+    pub interface: InterfaceType,
+    /// Names of the interfaces that are up, loopback excluded
+    pub interfaces: Vec<String>,
+    /// Addresses of the local interfaces
+    pub addresses: Vec<IpAddr>,
+    /// Default gateways
+    pub gateways: Vec<IpAddr>,
+    /// Hardware addresses of the default gateways, when known
+    pub gateway_macs: Vec<MacAddr>,
+    /// Whether a VPN tunnel interface is up
+    pub vpn: bool,
+    /// Probe targets found reachable, filled in by the resolver rather than the monitor
+    pub reachable: Vec<Target>,
+    /// Source address of the query being handled, filled in by the resolver
+    pub client: Option<IpAddr>,
+    /// Client groups the source address belongs to
+    pub client_groups: Vec<String>,
+    /// Time schedules are evaluated at, the system clock when `None`
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl NetworkState {
+    pub fn now(&self) -> DateTime<Utc> {
+        self.time.unwrap_or_else(Utc::now)
+    }
+}
+
+/// Hardware address, `aa:bb:cc:dd:ee:ff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    /// Separated by `:` or `-`, leading zeros may be omitted as `arp` does on macOS
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid MAC address {:?}", s);
+        let mut mac = [0; 6];
+        let mut octets = s.split([':', '-']);
+        for octet in mac.iter_mut() {
+            let hex = octets.next().ok_or_else(err)?;
+            if hex.is_empty() || hex.len() > 2 {
+                return Err(err());
+            }
+            *octet = u8::from_str_radix(hex, 16).map_err(|_| err())?;
+        }
+        if octets.next().is_some() {
+            return Err(err());
+        }
+        Ok(Self(mac))
+    }
+}
+
+impl TryFrom<&[u8]> for MacAddr {
+    type Error = ();
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        value.try_into().map(Self).map_err(|_| ())
+    }
+}
+
+pub struct Monitor {
+    state: Arc<RwLock<NetworkState>>,
+}
+
+#[cfg(target_os = "linux")]
+mod linux;
 #[cfg(target_os = "macos")]
 mod macos;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_addr() {
+        let mac: MacAddr = "a4:91:b1:5:e:1".parse().unwrap();
+        assert_eq!(mac, MacAddr([0xa4, 0x91, 0xb1, 0x05, 0x0e, 0x01]));
+        assert_eq!(mac.to_string(), "a4:91:b1:05:0e:01");
+        assert_eq!("A4-91-B1-05-0E-01".parse::<MacAddr>(), Ok(mac));
+        assert!("a4:91:b1:05:0e".parse::<MacAddr>().is_err());
+        assert!("a4:91:b1:05:0e:01:02".parse::<MacAddr>().is_err());
+        assert!("a4:91:b1:05:0e:1g".parse::<MacAddr>().is_err());
+    }
+}
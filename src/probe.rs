@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::{debug, info};
+
+use crate::config::ProbeConfig;
+use crate::resolver::Resolver;
+
+/// Endpoint checked by `reachable="..."` conditions and health checks
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// `tcp://192.168.1.10:445`, a connection can be established
+    Tcp(SocketAddr),
+    /// `udp-dns://10.0.0.53`, a DNS server answers, port 53 unless given
+    UdpDns(SocketAddr),
+    /// `http://10.0.0.2:8080/healthz`, a GET succeeds without an error status, port 80 unless given
+    Http { addr: SocketAddr, path: String },
+}
+
+impl Target {
+    /// Whether the target answers within `timeout`
+    pub fn probe(&self, timeout: Duration) -> bool {
+        let result = match self {
+            Target::Tcp(addr) => TcpStream::connect_timeout(addr, timeout).map(|_| ()),
+            Target::UdpDns(addr) => dns_exchange(addr, timeout),
+            Target::Http { .. } => http_get(&self.to_string(), timeout),
+        };
+        if let Err(e) = &result {
+            debug!(target = %self, %e, "probe failed");
+        }
+        result.is_ok()
+    }
+}
+
+/// Ask `addr` for the root name servers, any response with the same ID counts
+fn dns_exchange(addr: &SocketAddr, timeout: Duration) -> std::io::Result<()> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(addr)?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let id = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u16;
+    let [id_hi, id_lo] = id.to_be_bytes();
+    // header with RD and one question, then `. IN NS`
+    let query = [
+        id_hi, id_lo, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1,
+    ];
+    socket.send(&query)?;
+
+    let deadline = Instant::now()
+        .checked_add(timeout)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid timeout"))?;
+    let mut buf = [0; 512];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        if len >= 12 && buf[..2] == query[..2] && buf[2] & 0x80 != 0 {
+            return Ok(());
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+    }
+}
+
+/// Redirects count as success, only error statuses and failures to connect do not
+fn http_get(url: &str, timeout: Duration) -> std::io::Result<()> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .redirects(0)
+        .build();
+    match agent.get(url).call() {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, _)) => {
+            Err(std::io::Error::other(format!("responded {}", status)))
+        }
+        Err(e) => Err(std::io::Error::other(e)),
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Target::UdpDns(addr) => write!(f, "udp-dns://{}", addr),
+            Target::Http { addr, path } => write!(f, "http://{}{}", addr, path),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid probe target {:?}", s);
+        let (scheme, rest) = s.split_once("://").ok_or_else(err)?;
+        let (addr, path) = match rest.find('/') {
+            Some(idx) if scheme == "http" => rest.split_at(idx),
+            _ => (rest, "/"),
+        };
+        let addr = |default_port: Option<u16>| -> Result<SocketAddr, String> {
+            match (addr.parse(), default_port) {
+                (Ok(addr), _) => Ok(addr),
+                (Err(_), Some(port)) => addr
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .map_err(|_| err()),
+                (Err(_), None) => Err(err()),
+            }
+        };
+        match scheme {
+            "tcp" => addr(None).map(Target::Tcp),
+            "udp-dns" => addr(Some(53)).map(Target::UdpDns),
+            "http" => addr(Some(80)).map(|addr| Target::Http {
+                addr,
+                path: path.to_string(),
+            }),
+            _ => Err(format!(
+                "unknown probe scheme {:?}, expected tcp, udp-dns or http",
+                scheme
+            )),
+        }
+    }
+}
+
+/// Health check of every address of a rule, a target without its address:
+/// `tcp:443`, `udp-dns` with port 53 unless given, or `http:8080/healthz` with port 80 unless given
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    Tcp(u16),
+    UdpDns(u16),
+    Http { port: u16, path: String },
+}
+
+impl Check {
+    /// What to probe for `address`
+    pub fn target(&self, address: IpAddr) -> Target {
+        match self {
+            Check::Tcp(port) => Target::Tcp(SocketAddr::new(address, *port)),
+            Check::UdpDns(port) => Target::UdpDns(SocketAddr::new(address, *port)),
+            Check::Http { port, path } => Target::Http {
+                addr: SocketAddr::new(address, *port),
+                path: path.clone(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Tcp(port) => write!(f, "tcp:{}", port),
+            Check::UdpDns(port) => write!(f, "udp-dns:{}", port),
+            Check::Http { port, path } => write!(f, "http:{}{}", port, path),
+        }
+    }
+}
+
+impl FromStr for Check {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once(':').unwrap_or((s, ""));
+        let (port, path) = match rest.find('/') {
+            Some(idx) if scheme == "http" => rest.split_at(idx),
+            _ => (rest, "/"),
+        };
+        let port = |default: Option<u16>| match (port, default) {
+            ("", Some(default)) => Ok(default),
+            _ => port
+                .parse()
+                .map_err(|_| format!("invalid health check {:?}", s)),
+        };
+        match scheme {
+            "tcp" => port(None).map(Check::Tcp),
+            "udp-dns" => port(Some(53)).map(Check::UdpDns),
+            "http" => port(Some(80)).map(|port| Check::Http {
+                port,
+                path: path.to_string(),
+            }),
+            _ => Err(format!(
+                "unknown health check {:?}, expected tcp, udp-dns or http",
+                scheme
+            )),
+        }
+    }
+}
+
+/// Reachability of a target, only flipped after several probes agree
+#[derive(Debug, Default)]
+struct Status {
+    /// `None` until probed once
+    reachable: Option<bool>,
+    /// Consecutive probes disagreeing with `reachable`
+    streak: u32,
+}
+
+impl Status {
+    /// Record a probe result, returns whether the reachability changed
+    fn update(&mut self, ok: bool, config: &ProbeConfig) -> bool {
+        let Some(reachable) = self.reachable else {
+            self.reachable = Some(ok);
+            return true;
+        };
+        if ok == reachable {
+            self.streak = 0;
+            return false;
+        }
+        self.streak += 1;
+        let needed = if ok { config.rise } else { config.fall };
+        if self.streak < needed {
+            return false;
+        }
+        self.reachable = Some(ok);
+        self.streak = 0;
+        true
+    }
+}
+
+/// Probe the targets of the current config forever, feeding the results to `resolver`
+pub fn run(resolver: Arc<Resolver>) {
+    let mut statuses: HashMap<Target, Status> = HashMap::new();
+    loop {
+        let config = resolver.config();
+        let targets = config.probe_targets();
+        statuses.retain(|target, _| targets.contains(target));
+
+        let results: Vec<(Target, bool)> = thread::scope(|scope| {
+            let probes: Vec<_> = targets
+                .iter()
+                .map(|target| scope.spawn(|| (target.clone(), target.probe(config.probe.timeout))))
+                .collect();
+            probes.into_iter().map(|p| p.join().unwrap()).collect()
+        });
+        for (target, ok) in results {
+            let status = statuses.entry(target.clone()).or_default();
+            if status.update(ok, &config.probe) {
+                info!(%target, reachable = ok, "reachability changed");
+            }
+        }
+
+        resolver.set_reachability(
+            statuses
+                .iter()
+                .map(|(target, status)| (target.clone(), status.reachable == Some(true)))
+                .collect(),
+        );
+        thread::sleep(config.probe.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn targets() {
+        let target: Target = "udp-dns://10.0.0.53".parse().unwrap();
+        assert_eq!(target.to_string(), "udp-dns://10.0.0.53:53");
+        let target: Target = "udp-dns://[fd00::53]".parse().unwrap();
+        assert_eq!(target.to_string(), "udp-dns://[fd00::53]:53");
+        let target: Target = "tcp://192.168.1.10:445".parse().unwrap();
+        assert_eq!(target, Target::Tcp("192.168.1.10:445".parse().unwrap()));
+
+        assert_eq!(
+            "tcp://192.168.1.10".parse::<Target>().unwrap_err(),
+            r#"invalid probe target "tcp://192.168.1.10""#
+        );
+        assert_eq!(
+            "icmp://192.168.1.10".parse::<Target>().unwrap_err(),
+            r#"unknown probe scheme "icmp", expected tcp, udp-dns or http"#
+        );
+        let target: Target = "http://10.0.0.2/healthz".parse().unwrap();
+        assert_eq!(target.to_string(), "http://10.0.0.2:80/healthz");
+        let target: Target = "http://[fd00::2]:8080".parse().unwrap();
+        assert_eq!(target.to_string(), "http://[fd00::2]:8080/");
+    }
+
+    #[test]
+    fn checks() {
+        let address = "10.0.0.2".parse().unwrap();
+        let check: Check = "http:8080/healthz".parse().unwrap();
+        assert_eq!(
+            check.target(address).to_string(),
+            "http://10.0.0.2:8080/healthz"
+        );
+        let check: Check = "udp-dns".parse().unwrap();
+        assert_eq!(check.to_string(), "udp-dns:53");
+        let check: Check = "tcp:443".parse().unwrap();
+        assert_eq!(
+            check.target(address),
+            Target::Tcp("10.0.0.2:443".parse().unwrap())
+        );
+
+        assert_eq!(
+            "tcp".parse::<Check>().unwrap_err(),
+            r#"invalid health check "tcp""#
+        );
+        assert_eq!(
+            "icmp".parse::<Check>().unwrap_err(),
+            r#"unknown health check "icmp", expected tcp, udp-dns or http"#
+        );
+    }
+
+    #[test]
+    fn probing() {
+        let timeout = Duration::from_millis(200);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = Target::Tcp(listener.local_addr().unwrap());
+        assert!(tcp.probe(timeout));
+        drop(listener);
+        assert!(!tcp.probe(timeout));
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dns = Target::UdpDns(server.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            buf[2] |= 0x80;
+            server.send_to(&buf[..len], peer).unwrap();
+            // silent afterwards
+            server.recv_from(&mut buf).unwrap();
+        });
+        assert!(dns.probe(timeout));
+        assert!(!dns.probe(timeout));
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            for status in ["200 OK", "302 Found", "503 Service Unavailable"] {
+                let (mut stream, _) = server.accept().unwrap();
+                let mut buf = [0; 1024];
+                std::io::Read::read(&mut stream, &mut buf).unwrap();
+                let response = format!(
+                    "HTTP/1.1 {}\r\nLocation: /\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                std::io::Write::write_all(&mut stream, response.as_bytes()).unwrap();
+            }
+        });
+        let http = Target::Http {
+            addr,
+            path: "/healthz".to_string(),
+        };
+        assert!(http.probe(timeout));
+        assert!(http.probe(timeout));
+        assert!(!http.probe(timeout));
+    }
+
+    #[test]
+    fn hysteresis() {
+        let config = ProbeConfig {
+            rise: 2,
+            fall: 3,
+            ..Default::default()
+        };
+        let mut status = Status::default();
+        assert!(status.update(true, &config));
+        let flips: Vec<bool> = [false, false, true, false, false, false, true, true]
+            .into_iter()
+            .map(|ok| status.update(ok, &config))
+            .collect();
+        assert_eq!(
+            flips,
+            [false, false, false, false, false, true, false, true]
+        );
+        assert_eq!(status.reachable, Some(true));
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::core::{DnsClass, DnsHeader, DnsPacket, DnsQuestion, DnsType};
+
+/// Where smart hosts listens by default
+const DEFAULT_SERVER: &str = "127.0.0.1:2053";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "\
+usage: smart_hosts query [@SERVER[:PORT]] NAME [TYPE]
+
+Ask SERVER over UDP, 127.0.0.1:2053 by default and port 53 unless given,
+for the records of NAME, A by default, and print the response like dig.";
+
+/// `smart_hosts query`, returns the exit code
+pub fn cli(args: impl Iterator<Item = String>) -> i32 {
+    let mut server: SocketAddr = DEFAULT_SERVER.parse().unwrap();
+    let mut name = None;
+    let mut r#type = DnsType::A;
+    for arg in args {
+        let parsed = match arg.as_str() {
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return 0;
+            }
+            _ if arg.starts_with('@') => parse_server(&arg[1..]).map(|s| server = s),
+            _ if name.is_none() => {
+                name = Some(arg);
+                Ok(())
+            }
+            _ => arg.parse().map(|t| r#type = t),
+        };
+        if let Err(e) = parsed {
+            eprintln!("{}\n{}", e, USAGE);
+            return 2;
+        }
+    }
+    let Some(name) = name else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+
+    let start = Instant::now();
+    match exchange(server, &name, r#type) {
+        Ok(response) => {
+            println!("{}\n", response);
+            println!(";; Query time: {} msec", start.elapsed().as_millis());
+            println!(";; SERVER: {}", server);
+            0
+        }
+        Err(e) => {
+            eprintln!("failed to query {}: {}", server, e);
+            1
+        }
+    }
+}
+
+fn parse_server(s: &str) -> Result<SocketAddr, String> {
+    s.parse()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid server {:?}", s))
+}
+
+/// Ask `server` for the records of `name` of `r#type`
+pub fn exchange(server: SocketAddr, name: &str, r#type: DnsType) -> io::Result<DnsPacket> {
+    let id = RandomState::new().hash_one(name) as u16;
+    let name = if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    };
+    let query = DnsPacket {
+        header: DnsHeader {
+            id,
+            rd: true,
+            ..Default::default()
+        },
+        questions: vec![DnsQuestion {
+            name,
+            r#type,
+            class: DnsClass::In,
+        }],
+        ..Default::default()
+    }
+    .encode()
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    let local: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(&query)?;
+    let mut buf = [0; 4096];
+    loop {
+        let size = socket.recv(&mut buf)?;
+        // late responses to earlier queries from the same port are ignored
+        if size >= 2 && u16::from_be_bytes([buf[0], buf[1]]) != id {
+            continue;
+        }
+        return DnsPacket::parse(&buf[..size])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+    use crate::resolver::Resolver;
+
+    #[test]
+    fn querying() {
+        let config = "192.168.1.10 nas.home.local".parse().unwrap();
+        let resolver = Arc::new(Resolver::new(config, Default::default()));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let response = resolver.handle(&buf[..size], source).unwrap();
+                socket.send_to(&response, source).unwrap();
+            }
+        });
+
+        let response = exchange(server, "nas.home.local", DnsType::A).unwrap();
+        assert_eq!(
+            response.answers[0].to_string(),
+            "nas.home.local. 3600 IN A 192.168.1.10"
+        );
+        assert!(response
+            .to_string()
+            .contains(";; QUESTION SECTION:\n;nas.home.local. IN A"));
+
+        assert_eq!(
+            parse_server("10.0.0.53"),
+            Ok("10.0.0.53:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_server("[::1]:2053"),
+            Ok("[::1]:2053".parse().unwrap())
+        );
+        assert!(parse_server("localhost").is_err());
+    }
+
+    #[test]
+    fn unknown_records() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let mut packet = DnsPacket::parse(&buf[..size]).unwrap();
+                packet.header.qr = true;
+                packet.answers = vec![
+                    "www.github.com. 3600 IN CNAME github.com.".parse().unwrap(),
+                    "github.com. 60 IN TYPE65280 \\# 3 0a0001".parse().unwrap(),
+                ];
+                socket.send_to(&packet.encode().unwrap(), source).unwrap();
+            }
+        });
+
+        let response = exchange(server, "www.github.com", DnsType::Other(65280)).unwrap();
+        let output = response.to_string();
+        assert!(output.contains(";www.github.com. IN TYPE65280"));
+        assert!(output.contains(
+            ";; ANSWER SECTION:\n\
+             www.github.com. 3600 IN CNAME github.com.\n\
+             github.com. 60 IN TYPE65280 \\# 3 0a0001"
+        ));
+    }
+}
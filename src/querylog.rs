@@ -0,0 +1,207 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::json;
+use tracing::warn;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::RollingFileAppender,
+};
+
+use crate::config::QueryLogConfig;
+use crate::core::*;
+use crate::resolver::Source;
+
+/// Audit log of handled queries, one JSON object per line
+pub struct QueryLog {
+    writer: NonBlocking,
+    _guard: WorkerGuard,
+}
+
+impl QueryLog {
+    /// Open the log in `config.dir`, rotated files are named like `queries.2024-08-01.log`
+    pub fn open(config: &QueryLogConfig) -> io::Result<Self> {
+        let dir = config
+            .dir
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no query log directory"))?;
+        let mut builder = RollingFileAppender::builder()
+            .rotation(config.rotation.clone())
+            .filename_prefix("queries")
+            .filename_suffix("log");
+        if let Some(keep) = config.keep {
+            builder = builder.max_log_files(keep);
+        }
+        let appender = builder.build(dir).map_err(io::Error::other)?;
+        let (writer, _guard) = tracing_appender::non_blocking(appender);
+        Ok(Self { writer, _guard })
+    }
+
+    pub fn record(
+        &self,
+        client: SocketAddr,
+        question: Option<&DnsQuestion>,
+        source: Source,
+        response: Option<&[u8]>,
+        latency: Duration,
+    ) {
+        let (rule, upstream) = match source {
+            Source::Rule(rule) => (Some(rule.to_string()), None),
+            Source::Record(record) => (Some(record.to_string()), None),
+            Source::Zone(zone) => (Some(zone.to_string()), None),
+            Source::Upstream(upstream) => (None, Some(upstream.to_string())),
+            Source::Blocked(_) | Source::Cache | Source::Nothing => (None, None),
+        };
+        let blocklist = match source {
+            Source::Blocked(blocklist) => Some(blocklist.path.display().to_string()),
+            _ => None,
+        };
+        let rcode = response.and_then(rcode_of);
+        // null when there is no response or it does not parse
+        let answers = response
+            .and_then(|r| DnsPacket::parse(r).ok())
+            .map(|packet| {
+                packet
+                    .answers
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<_>>()
+            });
+
+        let entry = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "client": client.to_string(),
+            "name": question.map(|q| q.name.as_str()),
+            "type": question.map(|q| q.r#type.to_string()),
+            "rule": rule,
+            "upstream": upstream,
+            "cached": matches!(source, Source::Cache),
+            "blocklist": blocklist,
+            "rcode": rcode.map(u16::from),
+            "answers": answers,
+            "latency_ms": latency.as_secs_f64() * 1000.0,
+        });
+        let mut line = entry.to_string().into_bytes();
+        line.push(b'\n');
+        if let Err(e) = self.writer.clone().write_all(&line) {
+            warn!(%e, "failed to write query log");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use serde_json::Value;
+    use tracing_appender::rolling::Rotation;
+
+    use super::*;
+    use crate::resolver::Resolver;
+
+    #[test]
+    fn json_lines() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-querylog-{}", std::process::id()));
+        let query_log = QueryLog::open(&QueryLogConfig {
+            dir: Some(dir.clone()),
+            rotation: Rotation::NEVER,
+            keep: None,
+        })
+        .unwrap();
+        let config = "127.0.0.1 *.home.local, ssid=\"home\"".parse().unwrap();
+        let state = Arc::new(RwLock::new(crate::monitor::NetworkState {
+            ssid: Some("home".to_string()),
+            ..Default::default()
+        }));
+        let resolver = Resolver::new(config, state).with_query_log(query_log);
+
+        let client = "192.168.1.20:53000".parse().unwrap();
+        for name in ["nas.home.local.", "example.com."] {
+            let query = DnsPacket::query(name, DnsType::A).encode().unwrap();
+            resolver.handle(&query, client).unwrap();
+        }
+        // flushes the writer
+        drop(resolver);
+
+        let content = std::fs::read_to_string(dir.join("queries.log")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let entries: Vec<Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0]["client"], "192.168.1.20:53000");
+        assert_eq!(entries[0]["name"], "nas.home.local.");
+        assert_eq!(entries[0]["type"], "A");
+        assert_eq!(entries[0]["rule"], r#"127.0.0.1 *.home.local, ssid="home""#);
+        assert_eq!(entries[0]["upstream"], Value::Null);
+        assert_eq!(entries[0]["cached"], false);
+        assert_eq!(entries[0]["rcode"], 0);
+        assert_eq!(
+            entries[0]["answers"],
+            json!(["nas.home.local. 60 IN A 127.0.0.1"])
+        );
+        assert!(entries[0]["latency_ms"].is_f64());
+        assert!(entries[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+
+        // refused without any upstream
+        assert_eq!(entries[1]["rule"], Value::Null);
+        assert_eq!(entries[1]["rcode"], 5);
+        assert_eq!(entries[1]["answers"], json!([]));
+    }
+
+    #[test]
+    fn forwarded_answers() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-answers-{}", std::process::id()));
+        let query_log = QueryLog::open(&QueryLogConfig {
+            dir: Some(dir.clone()),
+            rotation: Rotation::NEVER,
+            keep: None,
+        })
+        .unwrap();
+        let question = DnsQuestion {
+            name: "www.github.com.".to_string(),
+            r#type: DnsType::A,
+            class: DnsClass::In,
+        };
+        let answers = [
+            "www.github.com. 3600 IN CNAME github.com.",
+            "github.com. 60 IN A 140.82.121.4",
+        ];
+        let mut response = DnsPacket {
+            header: DnsHeader {
+                qr: true,
+                ..Default::default()
+            },
+            answers: answers.iter().map(|r| r.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        let response = response.encode().unwrap();
+        let client = "192.168.1.20:53000".parse().unwrap();
+        for response in [&response[..], &response[..20]] {
+            query_log.record(
+                client,
+                Some(&question),
+                Source::Cache,
+                Some(response),
+                Duration::ZERO,
+            );
+        }
+        drop(query_log);
+
+        let content = std::fs::read_to_string(dir.join("queries.log")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let entries: Vec<Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(entries[0]["answers"], json!(answers));
+        assert_eq!(entries[1]["rcode"], 0);
+        assert_eq!(entries[1]["answers"], Value::Null);
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use ring::digest::{digest, SHA256};
+use tracing::{debug, info, warn};
+
+use crate::config::parse_duration;
+use crate::resolver::Resolver;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Larger downloads are rejected
+const MAX_SIZE: u64 = 64 << 20;
+/// Upper bound of the sleep between checks, so remotes added by a reload are picked up
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// File published at `url`, kept at `path` for includes, hosts files and blocklists.
+///
+/// ```plain
+/// remote https://example.com/team.hosts cache/team.hosts interval 1h checksum https://example.com/team.hosts.sha256
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Remote {
+    pub url: String,
+    pub path: PathBuf,
+    pub interval: Duration,
+    /// File in the `sha256sum` format the download must match
+    pub checksum: Option<String>,
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "remote {} {}", self.url, self.path.display())
+    }
+}
+
+impl FromStr for Remote {
+    type Err = String;
+
+    /// `URL PATH [interval DURATION] [checksum URL]`, the path as written
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let (Some(url), Some(path)) = (words.next(), words.next()) else {
+            return Err("expected remote URL PATH".to_string());
+        };
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("invalid remote URL {:?}", url));
+        }
+        let mut remote = Remote {
+            url: url.to_string(),
+            path: path.into(),
+            interval: Duration::from_secs(3600),
+            checksum: None,
+        };
+        while let Some(option) = words.next() {
+            let value = words
+                .next()
+                .ok_or_else(|| format!("missing value for {}", option))?;
+            match option {
+                "interval" => {
+                    remote.interval = parse_duration(value)?;
+                    // refreshes are scheduled at `now + interval`
+                    if Instant::now().checked_add(remote.interval).is_none() {
+                        return Err(format!("invalid duration {:?}", value));
+                    }
+                }
+                "checksum" => remote.checksum = Some(value.to_string()),
+                _ => return Err(format!("unknown remote option {:?}", option)),
+            }
+        }
+        if remote.interval.is_zero() {
+            return Err("remote interval must not be zero".to_string());
+        }
+        Ok(remote)
+    }
+}
+
+/// Validators of the cached copy, kept next to it
+#[derive(Debug, Default, PartialEq)]
+struct Meta {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Meta {
+    fn path(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".meta");
+        path.with_file_name(name)
+    }
+
+    fn read(path: &Path) -> Self {
+        let Ok(source) = fs::read_to_string(Self::path(path)) else {
+            return Self::default();
+        };
+        let meta: serde_json::Value = serde_json::from_str(&source).unwrap_or_default();
+        let field = |name: &str| meta[name].as_str().map(str::to_string);
+        Self {
+            etag: field("etag"),
+            last_modified: field("last_modified"),
+        }
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let meta = serde_json::json!({
+            "etag": self.etag,
+            "last_modified": self.last_modified,
+        });
+        replace(&Self::path(path), meta.to_string().as_bytes())
+    }
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over `path`,
+/// so readers never see a partial file
+fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Refresh {
+    Updated,
+    NotModified,
+}
+
+pub fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(TIMEOUT).build()
+}
+
+fn get(agent: &ureq::Agent, url: &str, meta: &Meta) -> Result<Option<ureq::Response>, String> {
+    let mut request = agent.get(url);
+    if let Some(etag) = &meta.etag {
+        request = request.set("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &meta.last_modified {
+        request = request.set("If-Modified-Since", last_modified);
+    }
+    match request.call() {
+        Ok(response) if response.status() == 304 => Ok(None),
+        Ok(response) => Ok(Some(response)),
+        Err(ureq::Error::Status(status, _)) => Err(format!("{} responded {}", url, status)),
+        Err(e) => Err(format!("failed to fetch {}: {}", url, e)),
+    }
+}
+
+fn read_body(url: &str, response: ureq::Response) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_SIZE + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| format!("failed to fetch {}: {}", url, e))?;
+    if body.len() as u64 > MAX_SIZE {
+        return Err(format!("{} is larger than {} bytes", url, MAX_SIZE));
+    }
+    Ok(body)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Download `remote` unless the cached copy is still current.
+/// Only complete downloads matching the checksum replace the cached copy.
+pub fn refresh(agent: &ureq::Agent, remote: &Remote) -> Result<Refresh, String> {
+    let meta = if remote.path.exists() {
+        Meta::read(&remote.path)
+    } else {
+        Meta::default()
+    };
+    let Some(response) = get(agent, &remote.url, &meta)? else {
+        return Ok(Refresh::NotModified);
+    };
+    let header = |name: &str| response.header(name).map(str::to_string);
+    let meta = Meta {
+        etag: header("ETag"),
+        last_modified: header("Last-Modified"),
+    };
+    let body = read_body(&remote.url, response)?;
+    if body.is_empty() {
+        return Err(format!("{} is empty", remote.url));
+    }
+
+    if let Some(url) = &remote.checksum {
+        let response =
+            get(agent, url, &Meta::default())?.ok_or_else(|| format!("{} responded 304", url))?;
+        let checksum = String::from_utf8_lossy(&read_body(url, response)?).into_owned();
+        let expected = checksum.split_whitespace().next().unwrap_or_default();
+        let actual = hex(digest(&SHA256, &body).as_ref());
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(format!(
+                "checksum mismatch for {}, expected {:?}, got {:?}",
+                remote.url, expected, actual
+            ));
+        }
+    }
+
+    let failed = |e: io::Error| format!("failed to write {}: {}", remote.path.display(), e);
+    replace(&remote.path, &body).map_err(failed)?;
+    meta.write(&remote.path).map_err(failed)?;
+    Ok(Refresh::Updated)
+}
+
+/// Make sure `remote` has a cached copy to read, downloading it when there is none.
+/// An existing copy is used as is and refreshed later by [`run`].
+pub fn ensure(remote: &Remote) -> Result<(), String> {
+    if remote.path.exists() {
+        return Ok(());
+    }
+    refresh(&agent(), remote)?;
+    info!(url = remote.url, path = ?remote.path, "remote downloaded");
+    Ok(())
+}
+
+/// Refresh the remotes of the current config forever, each on its own interval.
+/// Updated copies are picked up by the config watcher, failures keep the last good copy.
+pub fn run(resolver: Arc<Resolver>) {
+    let agent = agent();
+    let mut due: HashMap<Remote, Instant> = HashMap::new();
+    loop {
+        let config = resolver.config();
+        due.retain(|remote, _| config.remotes.contains(remote));
+
+        let now = Instant::now();
+        for remote in &config.remotes {
+            // intervals are checked when parsed
+            let Some(after) = now.checked_add(remote.interval) else {
+                continue;
+            };
+            let next = due.entry(remote.clone()).or_insert(after);
+            if *next > now {
+                continue;
+            }
+            *next = after;
+            match refresh(&agent, remote) {
+                Ok(Refresh::Updated) => info!(%remote, "remote updated"),
+                Ok(Refresh::NotModified) => debug!(%remote, "remote not modified"),
+                Err(e) => warn!(%remote, %e, "failed to refresh remote, keeping the cached copy"),
+            }
+        }
+
+        let sleep = due
+            .values()
+            .map(|next| next.saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or(MAX_SLEEP)
+            .min(MAX_SLEEP);
+        thread::sleep(sleep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// HTTP stub answering each connection with the next of `responses`,
+    /// returns its address and the received request heads
+    fn stub_server(responses: Vec<String>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                tx.send(head).unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn options() {
+        let remote: Remote = "https://example.com/team.hosts team.hosts interval 6h checksum https://example.com/team.hosts.sha256"
+            .parse()
+            .unwrap();
+        assert_eq!(remote.interval, Duration::from_secs(6 * 3600));
+        assert_eq!(
+            remote.checksum.as_deref(),
+            Some("https://example.com/team.hosts.sha256")
+        );
+
+        let err = |s: &str| s.parse::<Remote>().unwrap_err();
+        assert_eq!(
+            err("ftp://example.com/x x"),
+            r#"invalid remote URL "ftp://example.com/x""#
+        );
+        assert_eq!(
+            err("https://example.com/x x interval 1y"),
+            r#"invalid duration "1y""#
+        );
+        assert_eq!(
+            err("https://example.com/x x interval 18446744073709551615"),
+            r#"invalid duration "18446744073709551615""#
+        );
+        assert_eq!(
+            err("https://example.com/x x sha256 00"),
+            r#"unknown remote option "sha256""#
+        );
+    }
+
+    #[test]
+    fn refreshing() {
+        let dir = std::env::temp_dir().join(format!("smart_hosts-remote-{}", std::process::id()));
+        let body = "10.0.0.1 wiki.corp.example\n";
+        let checksum = format!(
+            "{}  team.hosts\n",
+            hex(digest(&SHA256, body.as_bytes()).as_ref())
+        );
+        let (addr, requests) = stub_server(vec![
+            response("200 OK", "ETag: \"v1\"\r\n", body),
+            response("200 OK", "", &checksum),
+            response("304 Not Modified", "", ""),
+            response("200 OK", "ETag: \"v2\"\r\n", "10.0.0.2 wiki.corp.example\n"),
+            response("200 OK", "", &checksum),
+            response("503 Service Unavailable", "", ""),
+        ]);
+        let remote: Remote = format!(
+            "http://{}/team.hosts {} checksum http://{}/team.hosts.sha256",
+            addr,
+            dir.join("team.hosts").display(),
+            addr
+        )
+        .parse()
+        .unwrap();
+        let agent = agent();
+
+        ensure(&remote).unwrap();
+        assert_eq!(fs::read_to_string(&remote.path).unwrap(), body);
+        assert!(requests.recv().unwrap().starts_with("GET /team.hosts "));
+        assert!(requests
+            .recv()
+            .unwrap()
+            .starts_with("GET /team.hosts.sha256 "));
+        // the cached copy is not downloaded again
+        ensure(&remote).unwrap();
+
+        assert_eq!(refresh(&agent, &remote), Ok(Refresh::NotModified));
+        assert!(requests.recv().unwrap().contains("If-None-Match: \"v1\""));
+
+        // neither a mismatching checksum nor a failure replace the last good copy
+        let err = refresh(&agent, &remote).unwrap_err();
+        assert!(err.starts_with(&format!("checksum mismatch for http://{}/team.hosts", addr)));
+        let err = refresh(&agent, &remote).unwrap_err();
+        assert_eq!(err, format!("http://{}/team.hosts responded 503", addr));
+        assert_eq!(fs::read_to_string(&remote.path).unwrap(), body);
+        assert_eq!(Meta::read(&remote.path).etag.as_deref(), Some("\"v1\""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1446 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use deku::prelude::*;
+use rustls::ClientConfig;
+use tracing::{debug, warn};
+
+use crate::blocklist::{BlockResponse, Blocklist};
+use crate::cache::Cache;
+use crate::condition::Condition;
+use crate::config::{Config, Order, RecordRule, Rule, Zone};
+use crate::core::*;
+use crate::metrics::Metrics;
+use crate::monitor::NetworkState;
+use crate::probe::Target;
+use crate::querylog::QueryLog;
+use crate::upstream::{Client, Upstream};
+
+/// Aliases followed before giving up on a chain, which most likely loops
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// What a query was answered by
+#[derive(Debug, Clone, Copy)]
+pub enum Source<'a> {
+    Rule(&'a Rule),
+    Record(&'a RecordRule),
+    /// Start of authority or name servers of a zone, or a name not in it
+    Zone(&'a Zone),
+    Upstream(&'a Upstream),
+    Blocked(&'a Blocklist),
+    /// Answered from the cache of earlier upstream responses
+    Cache,
+    /// Refused or failed without any rule or upstream involved
+    Nothing,
+}
+
+impl fmt::Display for Source<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Rule(rule) => write!(f, "rule {}", rule),
+            Source::Record(record) => write!(f, "rule {}", record),
+            Source::Zone(zone) => write!(f, "{}", zone),
+            Source::Upstream(upstream) => write!(f, "upstream {}", upstream),
+            Source::Blocked(blocklist) => write!(f, "{}", blocklist),
+            Source::Cache => write!(f, "cache"),
+            Source::Nothing => write!(f, "nothing"),
+        }
+    }
+}
+
+/// Network state forced through the control API on top of what the monitor observes
+#[derive(Debug, Clone)]
+pub struct Override {
+    pub conditions: Vec<Condition>,
+    pub until: Instant,
+}
+
+/// Answers queries from the smart hosts rules, forwarding the rest to the selected upstream
+pub struct Resolver {
+    config: RwLock<Arc<Config>>,
+    state: Arc<RwLock<NetworkState>>,
+    state_override: Mutex<Option<Override>>,
+    /// Probe targets found reachable, see `probe::run`
+    reachable: RwLock<Vec<Target>>,
+    /// Results of all probes, including the health checks of rules
+    health: RwLock<HashMap<Target, bool>>,
+    client: Client,
+    cache: Cache,
+    /// Outcome of the time conditions when last evaluated, see `Config::schedule`
+    schedule: Mutex<Vec<bool>>,
+    /// Answers given so far by round-robin rules, by their index
+    rotations: Mutex<HashMap<usize, usize>>,
+    metrics: Metrics,
+    query_log: Option<QueryLog>,
+}
+
+impl Resolver {
+    pub fn new(config: Config, state: Arc<RwLock<NetworkState>>) -> Self {
+        let client = Client::new(crate::tls::client_config(None).unwrap());
+        Self {
+            cache: Cache::new(config.cache_size),
+            config: RwLock::new(Arc::new(config)),
+            state,
+            state_override: Mutex::new(None),
+            reachable: RwLock::new(Vec::new()),
+            health: RwLock::new(HashMap::new()),
+            client,
+            schedule: Mutex::new(Vec::new()),
+            rotations: Mutex::new(HashMap::new()),
+            metrics: Metrics::default(),
+            query_log: None,
+        }
+    }
+
+    /// Verify encrypted upstreams with `tls` instead of the bundled web PKI roots
+    pub fn with_upstream_tls(mut self, tls: Arc<ClientConfig>) -> Self {
+        self.client = Client::new(tls);
+        self
+    }
+
+    /// Record every handled query to `query_log`
+    pub fn with_query_log(mut self, query_log: QueryLog) -> Self {
+        self.query_log = Some(query_log);
+        self
+    }
+
+    /// Answer with `config` from now on, queries being handled keep the previous one.
+    /// Listener settings only take effect on restart.
+    pub fn reload(&self, config: Config) {
+        self.cache.reset(config.cache_size);
+        *self.config.write().unwrap() = Arc::new(config);
+        self.rotations.lock().unwrap().clear();
+        self.metrics.reload();
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn flush_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Network state rules are evaluated against, the monitored one unless overridden
+    pub fn network_state(&self) -> NetworkState {
+        let mut state = self.state.read().unwrap().clone();
+        state.reachable = self.reachable.read().unwrap().clone();
+        if let Some(state_override) = self.state_override() {
+            for condition in &state_override.conditions {
+                // validated by `override_state`
+                condition.apply(&mut state).ok();
+            }
+        }
+        state
+    }
+
+    /// Publish the latest probe results, rules depending on them take effect right away
+    pub fn set_reachability(&self, mut results: Vec<(Target, bool)>) {
+        // sorted so that the network state only changes along with the results
+        results.sort_by_key(|(target, _)| target.to_string());
+        self.metrics.probes(&results);
+        *self.reachable.write().unwrap() = results
+            .iter()
+            .filter(|(_, reachable)| *reachable)
+            .map(|(target, _)| target.clone())
+            .collect();
+        *self.health.write().unwrap() = results.into_iter().collect();
+    }
+
+    /// Force `conditions` to hold for `duration`, replacing any previous override
+    pub fn override_state(
+        &self,
+        conditions: Vec<Condition>,
+        duration: Duration,
+    ) -> Result<(), String> {
+        let mut state = NetworkState::default();
+        for condition in &conditions {
+            condition.apply(&mut state)?;
+        }
+        let until = Instant::now()
+            .checked_add(duration)
+            .ok_or_else(|| "override duration too long".to_string())?;
+        *self.state_override.lock().unwrap() = Some(Override { conditions, until });
+        Ok(())
+    }
+
+    pub fn clear_override(&self) {
+        *self.state_override.lock().unwrap() = None;
+    }
+
+    /// The active override, expired ones are dropped
+    pub fn state_override(&self) -> Option<Override> {
+        let mut state_override = self.state_override.lock().unwrap();
+        if state_override
+            .as_ref()
+            .is_some_and(|o| o.until <= Instant::now())
+        {
+            *state_override = None;
+        }
+        state_override.clone()
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(&self.network_state())
+    }
+
+    /// Handle a raw query from `client`, returns the raw response.
+    /// Nothing is returned if the query is too short to even reply an error.
+    pub fn handle(&self, query: &[u8], client: SocketAddr) -> Option<Vec<u8>> {
+        let start = Instant::now();
+        let config = self.config();
+        let mut state = self.network_state();
+        let client_ip = client.ip().to_canonical();
+        state.client = Some(client_ip);
+        state.client_groups = config.client_groups_of(client_ip);
+        self.check_schedule(&config, &state);
+        let opcode = DnsHeader::from_bytes((query, 0)).map(|(_rest, header)| header.opcode);
+        let (question, (response, source)) = match DnsPacket::parse(query) {
+            // whatever their sections hold, other kinds of messages are not supported
+            _ if opcode.as_ref().is_ok_and(|&opcode| opcode != Opcode::Query) => {
+                debug!(?opcode, "unsupported opcode");
+                (
+                    None,
+                    (error_response(query, Rcode::NotImp), Source::Nothing),
+                )
+            }
+            Ok(mut packet) => {
+                let question = (packet.questions.len() == 1).then(|| packet.questions.remove(0));
+                let result = match &question {
+                    _ if packet.edns_version() > Some(0) => {
+                        debug!(version = packet.edns_version(), "unsupported EDNS version");
+                        (bad_version(&packet), Source::Nothing)
+                    }
+                    Some(question) if question.class == DnsClass::In => {
+                        self.resolve(&config, &packet, question, query, &state)
+                    }
+                    Some(question) => {
+                        debug!(class = %question.class, "unsupported class, forwarding as is");
+                        self.forward(&config, None, query, &state)
+                    }
+                    None => {
+                        debug!(count = packet.header.qdcount, "not a single question");
+                        (error_response(query, Rcode::FormErr), Source::Nothing)
+                    }
+                };
+                (question, result)
+            }
+            // every type parses, what is left is truncated or otherwise broken
+            Err(e) => {
+                debug!(?e, "malformed query");
+                self.metrics.parse_error();
+                (
+                    None,
+                    (error_response(query, Rcode::FormErr), Source::Nothing),
+                )
+            }
+        };
+        self.metrics.query(
+            question.as_ref().map(|q| q.r#type),
+            response.as_deref(),
+            &source,
+        );
+        if let Some(query_log) = &self.query_log {
+            query_log.record(
+                client,
+                question.as_ref(),
+                source,
+                response.as_deref(),
+                start.elapsed(),
+            );
+        }
+        response
+    }
+
+    /// Like `handle`, for queries over UDP: responses larger than the client accepts are
+    /// truncated, so it retries over TCP
+    pub fn handle_udp(&self, query: &[u8], client: SocketAddr) -> Option<Vec<u8>> {
+        let response = self.handle(query, client)?;
+        Some(truncate(response, udp_limit(query)))
+    }
+
+    /// Rules and upstreams may have become active or inactive as time went by,
+    /// answers cached under the previous schedule are dropped.
+    fn check_schedule(&self, config: &Config, state: &NetworkState) {
+        let schedule = config.schedule(state);
+        let mut previous = self.schedule.lock().unwrap();
+        if *previous != schedule {
+            debug!(?schedule, "schedule changed");
+            self.cache.clear();
+            *previous = schedule;
+        }
+    }
+
+    fn resolve<'a>(
+        &self,
+        config: &'a Config,
+        packet: &DnsPacket,
+        question: &DnsQuestion,
+        query: &[u8],
+        state: &NetworkState,
+    ) -> (Option<Vec<u8>>, Source<'a>) {
+        let zone = config.zone_of(&question.name);
+        if let Some(zone) = zone {
+            let apex = format!("{}.", zone.apex);
+            let ttl = zone.ttl.unwrap_or(config.ttl.default);
+            let data = match question.r#type {
+                DnsType::SOA => vec![DnsRData::Soa(zone.soa(config.ttl.negative))],
+                DnsType::NS => zone.nameservers.iter().cloned().map(DnsRData::Ns).collect(),
+                _ => Vec::new(),
+            };
+            if question.name.eq_ignore_ascii_case(&apex) && !data.is_empty() {
+                debug!(name = question.name, %zone, "answered by zone");
+                let answers = data
+                    .into_iter()
+                    .map(|d| DnsRecord::new(&question.name, question.r#type, ttl, vec![d]))
+                    .collect();
+                let response = respond(
+                    packet,
+                    question,
+                    answers,
+                    Rcode::NoError,
+                    Some(zone),
+                    config.ttl.negative,
+                );
+                return (response, Source::Zone(zone));
+            }
+        }
+
+        let records: Vec<&RecordRule> = config
+            .records
+            .iter()
+            .filter(|r| r.matches(&question.name, state))
+            .collect();
+        let answered: Vec<&RecordRule> = records
+            .iter()
+            .copied()
+            .filter(|r| r.r#type == question.r#type)
+            .collect();
+        if let Some(&first) = answered.first() {
+            debug!(name = question.name, record = %first, "answered by records");
+            let answers = answered
+                .iter()
+                .map(|r| {
+                    let ttl = config.record_ttl(r);
+                    DnsRecord::new(&question.name, r.r#type, ttl, r.data.clone())
+                })
+                .collect();
+            let response = respond(
+                packet,
+                question,
+                answers,
+                Rcode::NoError,
+                zone,
+                config.ttl.negative,
+            );
+            return (response, Source::Record(first));
+        }
+        if let Some(&alias) = records.iter().find(|r| r.r#type == DnsType::CNAME) {
+            return (
+                self.follow(config, packet, question, alias, zone, state),
+                Source::Record(alias),
+            );
+        }
+
+        let mut matched = config
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.matches(&question.name, state))
+            .peekable();
+        let Some(&(_, first)) = matched.peek() else {
+            if let Some(&record) = records.first() {
+                debug!(name = question.name, %record, "no record of the type");
+                let response = respond(
+                    packet,
+                    question,
+                    Vec::new(),
+                    Rcode::NoError,
+                    zone,
+                    config.ttl.negative,
+                );
+                return (response, Source::Record(record));
+            }
+            if let Some(blocklist) = config.blocked(&question.name, state) {
+                debug!(name = question.name, %blocklist, "blocked");
+                let negative_ttl = config.ttl.negative;
+                let response = match config.block_response {
+                    BlockResponse::Null => {
+                        let address: Option<IpAddr> = match question.r#type {
+                            DnsType::A => Some(Ipv4Addr::UNSPECIFIED.into()),
+                            DnsType::AAAA => Some(Ipv6Addr::UNSPECIFIED.into()),
+                            _ => None,
+                        };
+                        let ttl = config.ttl.of(&blocklist.conditions);
+                        let answers = address
+                            .map(|address| DnsRecord::address(&question.name, ttl, address))
+                            .into_iter()
+                            .collect();
+                        respond(
+                            packet,
+                            question,
+                            answers,
+                            Rcode::NoError,
+                            zone,
+                            negative_ttl,
+                        )
+                    }
+                    BlockResponse::NxDomain => respond(
+                        packet,
+                        question,
+                        Vec::new(),
+                        Rcode::NXDomain,
+                        zone,
+                        negative_ttl,
+                    ),
+                    BlockResponse::Refused => respond(
+                        packet,
+                        question,
+                        Vec::new(),
+                        Rcode::Refused,
+                        zone,
+                        negative_ttl,
+                    ),
+                };
+                return (response, Source::Blocked(blocklist));
+            }
+            if let Some(zone) = zone {
+                // the apex exists, names within the zone only if rules answer them
+                let apex = question
+                    .name
+                    .trim_end_matches('.')
+                    .eq_ignore_ascii_case(&zone.apex);
+                let rcode = if apex {
+                    Rcode::NoError
+                } else {
+                    Rcode::NXDomain
+                };
+                debug!(name = question.name, %zone, %rcode, "not in zone");
+                let response = respond(
+                    packet,
+                    question,
+                    Vec::new(),
+                    rcode,
+                    Some(zone),
+                    config.ttl.negative,
+                );
+                return (response, Source::Zone(zone));
+            }
+            return self.forward(config, Some(question), query, state);
+        };
+
+        let family = |address: &IpAddr| match question.r#type {
+            DnsType::A => address.is_ipv4(),
+            DnsType::AAAA => address.is_ipv6(),
+            _ => false,
+        };
+        let found = matched.find_map(|(idx, rule)| {
+            let addresses: Vec<IpAddr> = rule.addresses.iter().copied().filter(family).collect();
+            if addresses.is_empty() {
+                return None;
+            }
+            let healthy: Vec<IpAddr> = addresses
+                .iter()
+                .copied()
+                .filter(|a| self.is_healthy(rule, *a))
+                .collect();
+            if !healthy.is_empty() {
+                return Some((idx, rule, healthy));
+            }
+            if config.health_fail_open {
+                debug!(name = question.name, %rule, "no healthy address, answering all of them");
+                return Some((idx, rule, addresses));
+            }
+            debug!(name = question.name, %rule, "no healthy address, skipping the rule");
+            None
+        });
+        let Some((idx, rule, mut addresses)) = found else {
+            debug!(name = question.name, "no address of the family");
+            let response = respond(
+                packet,
+                question,
+                Vec::new(),
+                Rcode::NoError,
+                zone,
+                config.ttl.negative,
+            );
+            return (response, Source::Rule(first));
+        };
+        debug!(name = question.name, %rule, "answered by rule");
+        self.arrange(config, idx, rule, &mut addresses);
+        let ttl = config.answer_ttl(rule);
+        let answers = addresses
+            .into_iter()
+            .map(|address| DnsRecord::address(&question.name, ttl, address))
+            .collect();
+        let response = respond(
+            packet,
+            question,
+            answers,
+            Rcode::NoError,
+            zone,
+            config.ttl.negative,
+        );
+        (response, Source::Rule(rule))
+    }
+
+    /// Answer `question` with the CNAME `alias` matching its name, followed by
+    /// the answers to the same question about the target, as RFC 1034 asks.
+    fn follow(
+        &self,
+        config: &Config,
+        packet: &DnsPacket,
+        question: &DnsQuestion,
+        alias: &RecordRule,
+        zone: Option<&Zone>,
+        state: &NetworkState,
+    ) -> Option<Vec<u8>> {
+        let mut answers = Vec::new();
+        let mut name = question.name.clone();
+        let mut alias = Some(alias);
+        while let Some(rule) = alias {
+            let [DnsRData::Cname(target)] = &rule.data[..] else {
+                break;
+            };
+            if answers.len() == MAX_CNAME_CHAIN {
+                debug!(name = question.name, "CNAME chain too long");
+                return respond(
+                    packet,
+                    question,
+                    answers,
+                    Rcode::ServFail,
+                    zone,
+                    config.ttl.negative,
+                );
+            }
+            let ttl = config.record_ttl(rule);
+            answers.push(DnsRecord::new(
+                &name,
+                DnsType::CNAME,
+                ttl,
+                rule.data.clone(),
+            ));
+            name = target.clone();
+            alias = config
+                .records
+                .iter()
+                .find(|r| r.r#type == DnsType::CNAME && r.matches(&name, state));
+        }
+        debug!(name = question.name, target = name, "following CNAME");
+
+        // the target has no alias left, so it is answered by rules or upstreams
+        let mut target = DnsPacket {
+            header: packet.header.clone(),
+            questions: vec![DnsQuestion {
+                name,
+                r#type: question.r#type,
+                class: DnsClass::In,
+            }],
+            ..Default::default()
+        };
+        let query = target.encode().ok()?;
+        let (response, _) = self.resolve(config, &target, &target.questions[0], &query, state);
+        let rcode = match response.as_deref().map(DnsPacket::parse) {
+            Some(Ok(response)) => {
+                let rcode = response.rcode();
+                answers.extend(response.answers);
+                rcode
+            }
+            _ => Rcode::ServFail,
+        };
+        respond(packet, question, answers, rcode, zone, config.ttl.negative)
+    }
+
+    /// Addresses of rules without health check are always healthy, so are those not probed yet
+    fn is_healthy(&self, rule: &Rule, address: IpAddr) -> bool {
+        match &rule.health {
+            Some(check) => self.health(&check.target(address)) != Some(false),
+            None => true,
+        }
+    }
+
+    /// Latest result of probing `target`, `None` until probed
+    pub fn health(&self, target: &Target) -> Option<bool> {
+        self.health.read().unwrap().get(target).copied()
+    }
+
+    /// Put the `addresses` of `rule`, the `idx`th of `config`, in its order
+    fn arrange(&self, config: &Config, idx: usize, rule: &Rule, addresses: &mut [IpAddr]) {
+        match config.address_order(rule) {
+            Order::Fixed => {}
+            Order::RoundRobin => {
+                let mut rotations = self.rotations.lock().unwrap();
+                let answered = rotations.entry(idx).or_default();
+                addresses.rotate_left(*answered % addresses.len());
+                *answered = answered.wrapping_add(1);
+            }
+            Order::Shuffle => shuffle(addresses),
+        }
+    }
+
+    fn forward<'a>(
+        &self,
+        config: &'a Config,
+        question: Option<&DnsQuestion>,
+        query: &[u8],
+        state: &NetworkState,
+    ) -> (Option<Vec<u8>>, Source<'a>) {
+        let name = question.map(|q| q.name.as_str());
+        if !config.forwarding {
+            debug!(?name, "forwarding disabled");
+            return (error_response(query, Rcode::Refused), Source::Nothing);
+        }
+        let Some((idx, upstream)) = config
+            .upstreams
+            .iter()
+            .enumerate()
+            .find(|(_, u)| u.matches(name, state))
+        else {
+            debug!(?name, "no upstream available");
+            return (error_response(query, Rcode::Refused), Source::Nothing);
+        };
+
+        if let Some(question) = question {
+            let id = u16::from_be_bytes([query[0], query[1]]);
+            let cached = self.cache.get(state, idx, question, id);
+            self.metrics.cache_lookup(cached.is_some());
+            if cached.is_some() {
+                debug!(?name, "answered from cache");
+                return (cached, Source::Cache);
+            }
+        }
+        for server in &upstream.servers {
+            debug!(?name, %server, "forwarding");
+            let start = Instant::now();
+            let result = self.client.exchange(server, query);
+            self.metrics
+                .upstream_exchange(server, start.elapsed(), result.is_ok());
+            match result {
+                Ok(response) => {
+                    if let Some(question) = question {
+                        self.cache.insert(state, idx, question, &response);
+                    }
+                    return (Some(response), Source::Upstream(server));
+                }
+                Err(e) => warn!(?name, %server, %e, "upstream failed"),
+            }
+        }
+        let last = upstream.servers.last().unwrap();
+        (
+            error_response(query, Rcode::ServFail),
+            Source::Upstream(last),
+        )
+    }
+}
+
+/// Response to `question` of `query` with `answers`, authoritative within `zone`.
+/// Negative answers carry a start of authority record, of the zone if any or else of the
+/// `smart-hosts.` pseudo-zone, telling resolvers to remember them for `negative_ttl`.
+/// Queries with an OPT record get one back, as required by RFC 6891.
+fn respond(
+    query: &DnsPacket,
+    question: &DnsQuestion,
+    answers: Vec<DnsRecord>,
+    rcode: Rcode,
+    zone: Option<&Zone>,
+    negative_ttl: u32,
+) -> Option<Vec<u8>> {
+    let negative = answers.is_empty() && matches!(rcode, Rcode::NoError | Rcode::NXDomain);
+    let soa = || match zone {
+        Some(zone) => DnsRecord::new(
+            &format!("{}.", zone.apex),
+            DnsType::SOA,
+            negative_ttl,
+            vec![DnsRData::Soa(zone.soa(negative_ttl))],
+        ),
+        None => DnsRecord::soa(negative_ttl),
+    };
+    let mut response = DnsPacket {
+        header: query.header.clone(),
+        questions: vec![DnsQuestion {
+            name: question.name.clone(),
+            r#type: question.r#type,
+            class: DnsClass::In,
+        }],
+        answers,
+        authorities: negative.then(soa).into_iter().collect(),
+        additional: query.edns().map(|_| DnsRecord::opt()).into_iter().collect(),
+    };
+    response.header.qr = true;
+    response.header.aa = zone.is_some();
+    response.header.ra = true;
+    response.header.rcode = rcode;
+    response.encode().ok()
+}
+
+/// Fisher-Yates shuffle, randomly keyed hashers are random enough to spread load
+fn shuffle<T>(items: &mut [T]) {
+    let random = RandomState::new();
+    for i in (1..items.len()).rev() {
+        let j = random.hash_one(i) as usize % (i + 1);
+        items.swap(i, j);
+    }
+}
+
+/// Response to queries of an EDNS version above 0, telling them the version supported
+fn bad_version(query: &DnsPacket) -> Option<Vec<u8>> {
+    let mut response = DnsPacket {
+        header: query.header.clone(),
+        additional: vec![DnsRecord::opt()],
+        ..Default::default()
+    };
+    response.header.qr = true;
+    response.header.ra = true;
+    response.header.rcode = Rcode::BadVers;
+    response.encode().ok()
+}
+
+/// Response with only the header of `query`, for queries not worth parsing further
+fn error_response(query: &[u8], rcode: Rcode) -> Option<Vec<u8>> {
+    let (_rest, mut header) = DnsHeader::from_bytes((query, 0)).ok()?;
+    header.qr = true;
+    header.ra = true;
+    header.rcode = rcode;
+    header.qdcount = 0;
+    header.ancount = 0;
+    header.nscount = 0;
+    header.arcount = 0;
+    header.to_bytes().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, thread};
+
+    use super::*;
+
+    fn client() -> SocketAddr {
+        "127.0.0.1:5353".parse().unwrap()
+    }
+
+    fn query(name: &str, r#type: DnsType) -> Vec<u8> {
+        let mut packet = DnsPacket::query(name, r#type);
+        packet.header.id = 0x297e;
+        packet.header.rd = true;
+        packet.encode().unwrap()
+    }
+
+    /// Upstream stub answering every query with `addr`
+    fn stub_upstream(addr: &str) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = socket.local_addr().unwrap().to_string();
+        let addr = addr.parse().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let mut packet = DnsPacket::parse(&buf[..size]).unwrap();
+                let name = packet.questions[0].name.clone();
+                packet.header.qr = true;
+                packet.answers = vec![DnsRecord::address(&name, 60, addr)];
+                socket.send_to(&packet.encode().unwrap(), source).unwrap();
+            }
+        });
+        local
+    }
+
+    fn answers(response: &[u8]) -> Vec<String> {
+        DnsPacket::parse(response)
+            .unwrap()
+            .answers
+            .into_iter()
+            .flat_map(|r| r.data)
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn local_rules() {
+        let config = r#"
+127.0.0.1   *.home.local, ssid="home"
+127.0.0.2   *.home.local, cellular="on"
+::2         *.home.local, cellular="on"
+        "#
+        .parse()
+        .unwrap();
+        let state = Arc::new(RwLock::new(NetworkState {
+            ssid: Some("home".to_string()),
+            ..Default::default()
+        }));
+        let resolver = Resolver::new(config, state.clone());
+
+        let response = resolver
+            .handle(&query("nas.home.local.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["127.0.0.1"]);
+
+        // no IPv6 address while at home
+        let response = resolver
+            .handle(&query("nas.home.local.", DnsType::AAAA), client())
+            .unwrap();
+        assert_eq!(
+            DnsPacket::parse(&response).unwrap().header.rcode,
+            Rcode::NoError
+        );
+        assert!(answers(&response).is_empty());
+
+        *state.write().unwrap() = NetworkState {
+            cellular: true,
+            ..Default::default()
+        };
+        let response = resolver
+            .handle(&query("nas.home.local.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["127.0.0.2"]);
+        let response = resolver
+            .handle(&query("nas.home.local.", DnsType::AAAA), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["::2"]);
+    }
+
+    #[test]
+    fn upstream_selection() {
+        let corp = stub_upstream("10.0.0.1");
+        let public = stub_upstream("1.1.1.1");
+        let config = format!(
+            "upstream {}  *.corp.example, ssid=\"work\"\nupstream {}",
+            corp, public
+        )
+        .parse()
+        .unwrap();
+        let state = Arc::new(RwLock::new(NetworkState {
+            ssid: Some("work".to_string()),
+            ..Default::default()
+        }));
+        let resolver = Resolver::new(config, state.clone());
+
+        let response = resolver
+            .handle(&query("git.corp.example.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["10.0.0.1"]);
+        // whatever the type, internal names never leak to the public upstream
+        for r#type in [DnsType::PTR, DnsType::CNAME, DnsType::Other(257)] {
+            let response = resolver
+                .handle(&query("git.corp.example.", r#type), client())
+                .unwrap();
+            assert_eq!(answers(&response), vec!["10.0.0.1"]);
+        }
+        let response = resolver
+            .handle(&query("example.com.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["1.1.1.1"]);
+
+        state.write().unwrap().ssid = Some("home".to_string());
+        let response = resolver
+            .handle(&query("git.corp.example.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["1.1.1.1"]);
+    }
+
+    #[test]
+    fn upstream_fallback() {
+        // nothing listens on the first one
+        let dead = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let alive = stub_upstream("1.1.1.1");
+        let config = format!("upstream {} {}", dead, alive).parse().unwrap();
+        let resolver = Resolver::new(config, Default::default());
+
+        let response = resolver
+            .handle(&query("example.com.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["1.1.1.1"]);
+
+        let config = format!("upstream {}", dead).parse().unwrap();
+        let resolver = Resolver::new(config, Default::default());
+        let response = resolver
+            .handle(&query("example.com.", DnsType::A), client())
+            .unwrap();
+        let (_rest, header) = DnsHeader::from_bytes((&response, 0)).unwrap();
+        assert_eq!(header.rcode, Rcode::ServFail);
+    }
+
+    #[test]
+    fn cached() {
+        let upstream = stub_upstream("1.1.1.1");
+        let config = format!("upstream {}", upstream).parse().unwrap();
+        let resolver = Resolver::new(config, Default::default());
+
+        for _ in 0..2 {
+            let response = resolver
+                .handle(&query("example.com.", DnsType::A), client())
+                .unwrap();
+            assert_eq!(answers(&response), vec!["1.1.1.1"]);
+        }
+        let metrics = resolver.render_metrics();
+        for line in [
+            r#"smart_hosts_cache_lookups_total{result="hit"} 1"#,
+            r#"smart_hosts_cache_lookups_total{result="miss"} 1"#,
+            r#"smart_hosts_answers_total{source="cache"} 1"#,
+            r#"smart_hosts_answers_total{source="upstream"} 1"#,
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == line),
+                "{} not in {}",
+                line,
+                metrics
+            );
+        }
+    }
+
+    #[test]
+    fn schedule() {
+        let upstream = stub_upstream("1.1.1.1");
+        let config = format!("upstream {}, time=\"09:00-18:00 UTC\"", upstream)
+            .parse()
+            .unwrap();
+        let at = |time: &str| NetworkState {
+            time: Some(time.parse().unwrap()),
+            ..Default::default()
+        };
+        let state = Arc::new(RwLock::new(at("2024-08-01T10:00:00Z")));
+        let resolver = Resolver::new(config, state);
+        let lookup = || {
+            resolver
+                .handle(&query("example.com.", DnsType::A), client())
+                .unwrap();
+        };
+        lookup();
+        lookup();
+        // the upstream went inactive and back
+        resolver.check_schedule(&resolver.config(), &at("2024-08-01T18:00:00Z"));
+        lookup();
+
+        let metrics = resolver.render_metrics();
+        for line in [
+            r#"smart_hosts_cache_lookups_total{result="hit"} 1"#,
+            r#"smart_hosts_cache_lookups_total{result="miss"} 2"#,
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == line),
+                "{} not in {}",
+                line,
+                metrics
+            );
+        }
+    }
+
+    #[test]
+    fn reachability() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = format!("tcp://{}", listener.local_addr().unwrap());
+        let config = format!(
+            "192.168.1.10 nas.home.local, reachable=\"{}\"\n\
+             10.8.0.10 nas.home.local, not reachable=\"{}\"",
+            target, target
+        )
+        .parse()
+        .unwrap();
+        let resolver = Resolver::new(config, Default::default());
+        let lookup = || {
+            let response = resolver
+                .handle(&query("nas.home.local.", DnsType::A), client())
+                .unwrap();
+            answers(&response)
+        };
+        assert_eq!(lookup(), vec!["10.8.0.10"]);
+
+        let target: Target = target.parse().unwrap();
+        resolver.set_reachability(vec![(target.clone(), target.probe(Duration::from_secs(1)))]);
+        assert_eq!(lookup(), vec!["192.168.1.10"]);
+        assert_eq!(resolver.network_state().reachable, vec![target]);
+    }
+
+    #[test]
+    fn split_horizon() {
+        let upstream = stub_upstream("1.1.1.1");
+        let config = format!(
+            "client-group trusted 127.0.0.0/8\n\
+             192.168.1.10 nas.home.local, client=\"trusted\"\n\
+             203.0.113.10 nas.home.local, client=\"10.0.0.0/8\"\n\
+             upstream {}",
+            upstream
+        )
+        .parse()
+        .unwrap();
+        let resolver = Resolver::new(config, Default::default());
+        let lookup = |client: &str| {
+            let response = resolver
+                .handle(
+                    &query("nas.home.local.", DnsType::A),
+                    client.parse().unwrap(),
+                )
+                .unwrap();
+            answers(&response)
+        };
+        assert_eq!(lookup("127.0.0.1:5353"), vec!["192.168.1.10"]);
+        assert_eq!(lookup("[::ffff:127.0.0.1]:5353"), vec!["192.168.1.10"]);
+        assert_eq!(lookup("10.1.2.3:5353"), vec!["203.0.113.10"]);
+        assert_eq!(lookup("192.168.1.2:5353"), vec!["1.1.1.1"]);
+
+        // clients asking in turn share the cache
+        resolver.handle(
+            &query("example.com.", DnsType::A),
+            "10.1.2.3:53".parse().unwrap(),
+        );
+        resolver.handle(&query("example.com.", DnsType::A), client());
+        let metrics = resolver.render_metrics();
+        assert!(metrics
+            .lines()
+            .any(|l| l == r#"smart_hosts_cache_lookups_total{result="hit"} 1"#));
+    }
+
+    #[test]
+    fn blocked() {
+        let path =
+            std::env::temp_dir().join(format!("smart_hosts-blocked-{}.txt", std::process::id()));
+        std::fs::write(&path, "||ads.example^").unwrap();
+        let upstream = stub_upstream("1.1.1.1");
+        let resolver = |response: &str| {
+            let config = format!(
+                "127.0.0.1 local.ads.example\nblocklist {}\nblock-response {}\nupstream {}",
+                path.display(),
+                response,
+                upstream
+            );
+            Resolver::new(config.parse().unwrap(), Default::default())
+        };
+
+        let null = resolver("null");
+        let response = null
+            .handle(&query("x.ads.example.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["0.0.0.0"]);
+        let response = null
+            .handle(&query("x.ads.example.", DnsType::AAAA), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["::"]);
+        // rules take precedence over blocklists
+        let response = null
+            .handle(&query("local.ads.example.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["127.0.0.1"]);
+        let response = null
+            .handle(&query("example.com.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["1.1.1.1"]);
+        assert!(null
+            .render_metrics()
+            .lines()
+            .any(|l| l == r#"smart_hosts_answers_total{source="blocklist"} 2"#));
+
+        for (response, rcode) in [("nxdomain", Rcode::NXDomain), ("refused", Rcode::Refused)] {
+            let response = resolver(response)
+                .handle(&query("x.ads.example.", DnsType::A), client())
+                .unwrap();
+            let packet = DnsPacket::parse(&response).unwrap();
+            assert_eq!(packet.header.rcode, rcode);
+            assert_eq!(packet.questions[0].name, "x.ads.example.");
+            assert!(packet.answers.is_empty());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ttls() {
+        let config = r#"
+ttl 1h
+negative-ttl 5m
+192.168.1.10 nas.home.local
+127.0.0.1    *.home.local, ssid="home"
+        "#
+        .parse()
+        .unwrap();
+        let state = Arc::new(RwLock::new(NetworkState {
+            ssid: Some("home".to_string()),
+            ..Default::default()
+        }));
+        let resolver = Resolver::new(config, state);
+        let lookup = |name: &str, r#type| {
+            let response = resolver.handle(&query(name, r#type), client()).unwrap();
+            DnsPacket::parse(&response).unwrap()
+        };
+
+        assert_eq!(lookup("nas.home.local.", DnsType::A).answers[0].ttl, 3600);
+        assert_eq!(lookup("tv.home.local.", DnsType::A).answers[0].ttl, 60);
+
+        // no address of the family, the start of authority tells how long to remember that
+        let packet = lookup("tv.home.local.", DnsType::AAAA);
+        assert!(packet.answers.is_empty());
+        let soa = &packet.authorities[0];
+        assert_eq!((soa.r#type, soa.ttl), (DnsType::SOA, 300));
+        assert_eq!(soa.name, "smart-hosts.");
+    }
+
+    #[test]
+    fn records() {
+        let config = r#"
+192.168.1.10 home.local
+txt home.local "at home", ssid="home"
+txt home.local "away", not ssid="home"
+mx  home.local 20 backup.home.local
+mx  home.local 10 mail.home.local
+srv _sip._tcp.home.local 10 5 5060 sip.home.local
+https svc.home.local 1 . alpn=h2
+        "#
+        .parse()
+        .unwrap();
+        let state = Arc::new(RwLock::new(NetworkState {
+            ssid: Some("home".to_string()),
+            ..Default::default()
+        }));
+        let resolver = Resolver::new(config, state);
+        let lookup = |name: &str, r#type| {
+            let response = resolver.handle(&query(name, r#type), client()).unwrap();
+            answers(&response)
+        };
+
+        assert_eq!(lookup("home.local.", DnsType::TXT), vec![r#""at home""#]);
+        assert_eq!(
+            lookup("home.local.", DnsType::MX),
+            vec!["20 backup.home.local.", "10 mail.home.local."]
+        );
+        assert_eq!(lookup("home.local.", DnsType::A), vec!["192.168.1.10"]);
+        assert_eq!(
+            lookup("_sip._tcp.home.local.", DnsType::SRV),
+            vec!["10 5 5060 sip.home.local."]
+        );
+
+        assert_eq!(
+            lookup("svc.home.local.", DnsType::HTTPS),
+            vec!["1 . alpn=h2"]
+        );
+
+        // browsers ask for HTTPS records of every name
+        let response = resolver
+            .handle(&query("home.local.", DnsType::HTTPS), client())
+            .unwrap();
+        let packet = DnsPacket::parse(&response).unwrap();
+        assert_eq!(packet.header.rcode, Rcode::NoError);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].r#type, DnsType::SOA);
+
+        // names with records of other types only are not forwarded
+        let response = resolver
+            .handle(&query("_sip._tcp.home.local.", DnsType::A), client())
+            .unwrap();
+        let packet = DnsPacket::parse(&response).unwrap();
+        assert_eq!(packet.header.rcode, Rcode::NoError);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].r#type, DnsType::SOA);
+    }
+
+    #[test]
+    fn aliases() {
+        let mut config: Config = "zone home.example.com ns1.example.com serial 7\n\
+                                  192.168.1.10 nas.home.example.com"
+            .parse()
+            .unwrap();
+        for (name, target) in [
+            ("tv.home.example.com", "nas.home.example.com."),
+            ("den.home.example.com", "tv.home.example.com."),
+            ("loop.home.example.com", "loop.home.example.com."),
+        ] {
+            config.records.push(RecordRule {
+                pattern: name.parse().unwrap(),
+                r#type: DnsType::CNAME,
+                data: vec![DnsRData::Cname(target.to_string())],
+                conditions: Vec::new(),
+                ttl: None,
+            });
+        }
+        let resolver = Resolver::new(config, Arc::new(RwLock::new(NetworkState::default())));
+        let lookup = |name: &str, r#type| resolver.handle(&query(name, r#type), client()).unwrap();
+
+        let response = lookup("den.home.example.com.", DnsType::A);
+        let packet = DnsPacket::parse(&response).unwrap();
+        assert_eq!(packet.header.rcode, Rcode::NoError);
+        assert!(packet.header.aa);
+        assert_eq!(
+            answers(&response),
+            [
+                "tv.home.example.com.",
+                "nas.home.example.com.",
+                "192.168.1.10"
+            ]
+        );
+        assert_eq!(packet.answers[1].name, "tv.home.example.com.");
+        assert_eq!(
+            answers(&lookup("tv.home.example.com.", DnsType::CNAME)),
+            ["nas.home.example.com."]
+        );
+        // the target has no record of the type
+        let packet = DnsPacket::parse(&lookup("tv.home.example.com.", DnsType::TXT)).unwrap();
+        assert_eq!(packet.header.rcode, Rcode::NoError);
+        assert_eq!(packet.answers.len(), 1);
+
+        let packet = DnsPacket::parse(&lookup("loop.home.example.com.", DnsType::A)).unwrap();
+        assert_eq!(packet.header.rcode, Rcode::ServFail);
+        assert_eq!(packet.answers.len(), MAX_CNAME_CHAIN);
+    }
+
+    #[test]
+    fn zones() {
+        let config = r#"
+zone home.example.com ns1.example.com ns2.example.com serial 7 ttl 1d
+negative-ttl 5m
+forwarding off
+192.168.1.10 nas.home.example.com
+192.168.1.20 printer.local
+        "#
+        .parse()
+        .unwrap();
+        let resolver = Resolver::new(config, Default::default());
+        let lookup = |name: &str, r#type| {
+            let response = resolver.handle(&query(name, r#type), client()).unwrap();
+            DnsPacket::parse(&response).unwrap()
+        };
+
+        let packet = lookup("home.example.com.", DnsType::SOA);
+        assert!(packet.header.aa);
+        assert_eq!(
+            packet.answers[0].data[0].to_string(),
+            "ns1.example.com. hostmaster.home.example.com. 7 3600 600 86400 300"
+        );
+        assert_eq!(packet.answers[0].ttl, 86400);
+        let packet = lookup("home.example.com.", DnsType::NS);
+        assert!(packet.header.aa);
+        assert!(packet.answers.iter().all(|r| r.ttl == 86400));
+        let nameservers: Vec<String> = packet
+            .answers
+            .iter()
+            .map(|r| r.data[0].to_string())
+            .collect();
+        assert_eq!(nameservers, vec!["ns1.example.com.", "ns2.example.com."]);
+
+        let packet = lookup("nas.home.example.com.", DnsType::A);
+        assert!(packet.header.aa);
+        assert_eq!(packet.answers.len(), 1);
+
+        // names of the zone without rules do not exist, the apex does
+        let packet = lookup("tv.home.example.com.", DnsType::A);
+        assert!(packet.header.aa);
+        assert_eq!(packet.header.rcode, Rcode::NXDomain);
+        let soa = &packet.authorities[0];
+        assert_eq!((soa.name.as_str(), soa.ttl), ("home.example.com.", 300));
+        let packet = lookup("home.example.com.", DnsType::A);
+        assert_eq!(packet.header.rcode, Rcode::NoError);
+        assert!(packet.answers.is_empty());
+
+        // outside of the zone, rules still answer but nothing is forwarded
+        let packet = lookup("printer.local.", DnsType::A);
+        assert!(!packet.header.aa);
+        assert_eq!(packet.answers.len(), 1);
+        let packet = lookup("example.org.", DnsType::A);
+        assert_eq!(packet.header.rcode, Rcode::Refused);
+    }
+
+    #[test]
+    fn address_order() {
+        let config = "\
+10.0.0.1,10.0.0.2,10.0.0.3,fd00::1 rr.svc.local, order=round-robin
+10.0.0.1,10.0.0.2,10.0.0.3 fixed.svc.local
+10.0.0.1,10.0.0.2,10.0.0.3 shuffled.svc.local, order=shuffle"
+            .parse()
+            .unwrap();
+        let resolver = Resolver::new(config, Default::default());
+        let lookup = |name: &str, r#type| {
+            let response = resolver.handle(&query(name, r#type), client()).unwrap();
+            answers(&response)
+        };
+
+        let rotations: Vec<Vec<String>> = (0..4)
+            .map(|_| lookup("rr.svc.local.", DnsType::A))
+            .collect();
+        assert_eq!(
+            rotations,
+            vec![
+                vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"],
+                vec!["10.0.0.2", "10.0.0.3", "10.0.0.1"],
+                vec!["10.0.0.3", "10.0.0.1", "10.0.0.2"],
+                vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"],
+            ]
+        );
+        assert_eq!(lookup("rr.svc.local.", DnsType::AAAA), vec!["fd00::1"]);
+        assert_eq!(
+            lookup("fixed.svc.local.", DnsType::A),
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        let mut shuffled = lookup("shuffled.svc.local.", DnsType::A);
+        shuffled.sort();
+        assert_eq!(shuffled, vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn health_checks() {
+        let config = "\
+10.0.0.1,10.0.0.2 api.svc.local, health=tcp:443
+10.0.0.3,10.0.0.4 db.svc.local, health=tcp:5432
+10.0.0.5          db.svc.local";
+        let resolver = Resolver::new(config.parse().unwrap(), Default::default());
+        let lookup = |resolver: &Resolver, name: &str| {
+            let response = resolver.handle(&query(name, DnsType::A), client()).unwrap();
+            answers(&response)
+        };
+        let target = |s: &str| s.parse::<Target>().unwrap();
+        assert_eq!(
+            resolver.config().probe_targets(),
+            vec![
+                target("tcp://10.0.0.1:443"),
+                target("tcp://10.0.0.2:443"),
+                target("tcp://10.0.0.3:5432"),
+                target("tcp://10.0.0.4:5432"),
+            ]
+        );
+
+        // not probed yet
+        assert_eq!(
+            lookup(&resolver, "api.svc.local."),
+            vec!["10.0.0.1", "10.0.0.2"]
+        );
+        resolver.set_reachability(vec![
+            (target("tcp://10.0.0.1:443"), false),
+            (target("tcp://10.0.0.2:443"), true),
+            (target("tcp://10.0.0.3:5432"), false),
+            (target("tcp://10.0.0.4:5432"), false),
+        ]);
+        assert_eq!(lookup(&resolver, "api.svc.local."), vec!["10.0.0.2"]);
+        // all down, answered anyway
+        assert_eq!(
+            lookup(&resolver, "db.svc.local."),
+            vec!["10.0.0.3", "10.0.0.4"]
+        );
+
+        let config = format!("health-fail-open off\n{}", config);
+        resolver.reload(config.parse().unwrap());
+        assert_eq!(lookup(&resolver, "db.svc.local."), vec!["10.0.0.5"]);
+    }
+
+    #[test]
+    fn reload() {
+        let config = "127.0.0.1 nas.home.local".parse().unwrap();
+        let resolver = Resolver::new(config, Default::default());
+        resolver.reload("127.0.0.2 nas.home.local".parse().unwrap());
+
+        let response = resolver
+            .handle(&query("nas.home.local.", DnsType::A), client())
+            .unwrap();
+        assert_eq!(answers(&response), vec!["127.0.0.2"]);
+        assert!(resolver
+            .render_metrics()
+            .lines()
+            .any(|l| l == "smart_hosts_config_reloads_total 1"));
+    }
+
+    #[test]
+    fn udp_truncation() {
+        let addresses: Vec<String> = (1..=40).map(|i| format!("fd00::{}", i)).collect();
+        let config = format!("{} nas.home.local", addresses.join(","));
+        let resolver = Resolver::new(config.parse().unwrap(), Default::default());
+        let raw = query("nas.home.local.", DnsType::AAAA);
+
+        // 40 records take more than 512 bytes
+        let response = resolver.handle_udp(&raw, client()).unwrap();
+        assert!(response.len() <= 512);
+        let packet = DnsPacket::parse(&response).unwrap();
+        assert!(packet.header.tc);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.questions[0].name, "nas.home.local.");
+        assert_eq!(
+            resolver.handle(&raw, client()).unwrap().len(),
+            response.len() + 40 * 28
+        );
+
+        // unless the client accepts more
+        let mut edns = DnsPacket::parse(&raw).unwrap();
+        edns.additional.push(DnsRecord::opt());
+        let response = resolver
+            .handle_udp(&edns.encode().unwrap(), client())
+            .unwrap();
+        let packet = DnsPacket::parse(&response).unwrap();
+        assert!(!packet.header.tc);
+        assert_eq!(packet.answers.len(), 40);
+
+        // upstream responses that do not parse keep their header
+        let mut garbage = response[..12].to_vec();
+        garbage.extend([0xff; 600]);
+        let truncated = truncate(garbage, 512);
+        let (_rest, header) = DnsHeader::from_bytes((&truncated, 0)).unwrap();
+        assert!(header.tc);
+        assert_eq!(truncated.len(), 12);
+    }
+
+    #[test]
+    fn message_errors() {
+        let resolver = Resolver::new(
+            "127.0.0.1 nas.home.local".parse().unwrap(),
+            Default::default(),
+        );
+        let handle = |query: &[u8]| {
+            let response = resolver.handle(query, client()).unwrap();
+            DnsPacket::parse(&response).unwrap()
+        };
+        let raw = query("nas.home.local.", DnsType::A);
+        let local = || DnsPacket::parse(&raw).unwrap();
+
+        let mut notify = local();
+        notify.header.opcode = Opcode::Notify;
+        let response = handle(&notify.encode().unwrap());
+        assert_eq!(response.header.rcode, Rcode::NotImp);
+        assert_eq!(response.header.opcode, Opcode::Notify);
+
+        assert_eq!(handle(&raw[..20]).header.rcode, Rcode::FormErr);
+        // a label running past the name, and a compression pointer to itself
+        let mut label = raw.clone();
+        label[12] = 0x3f;
+        assert_eq!(handle(&label).header.rcode, Rcode::FormErr);
+        let mut pointer = raw[..12].to_vec();
+        pointer.extend([0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        assert_eq!(handle(&pointer).header.rcode, Rcode::FormErr);
+        let mut questions = local();
+        questions.questions.extend(local().questions);
+        assert_eq!(
+            handle(&questions.encode().unwrap()).header.rcode,
+            Rcode::FormErr
+        );
+
+        // answered with the highest version supported
+        let mut edns = local();
+        let mut opt = DnsRecord::opt();
+        opt.ttl = 1 << 16;
+        edns.additional.push(opt);
+        let response = handle(&edns.encode().unwrap());
+        assert_eq!(response.rcode(), Rcode::BadVers);
+        assert_eq!(response.edns_version(), Some(0));
+        assert!(response.answers.is_empty());
+
+        edns.additional = vec![DnsRecord::opt()];
+        let response = handle(&edns.encode().unwrap());
+        assert_eq!(response.rcode(), Rcode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.edns_version(), Some(0));
+        assert!(handle(&raw).edns().is_none());
+    }
+
+    #[test]
+    fn without_upstream() {
+        let resolver = Resolver::new(Config::default(), Default::default());
+        let response = resolver
+            .handle(&query("example.com.", DnsType::A), client())
+            .unwrap();
+        let (_rest, header) = DnsHeader::from_bytes((&response, 0)).unwrap();
+        assert!(header.qr);
+        assert_eq!(header.rcode, Rcode::Refused);
+        assert_eq!(header.qdcount, 0);
+
+        assert!(resolver.handle(&[0x29], client()).is_none());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+
+/// Local time of `now` in `tz`, or in the system time zone
+fn local(now: &DateTime<Utc>, tz: Option<Tz>) -> NaiveDateTime {
+    match tz {
+        Some(tz) => now.with_timezone(&tz).naive_local(),
+        None => now.with_timezone(&Local).naive_local(),
+    }
+}
+
+fn parse_tz(tz: Option<&str>) -> Result<Option<Tz>, String> {
+    tz.map(|tz| {
+        tz.parse()
+            .map_err(|_| format!("unknown time zone {:?}", tz))
+    })
+    .transpose()
+}
+
+/// Time of day, `09:00-18:00` optionally followed by a time zone like `Europe/Berlin`.
+/// The end is exclusive, a range ending before it starts goes past midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub tz: Option<Tz>,
+}
+
+impl TimeRange {
+    pub fn contains(&self, now: &DateTime<Utc>) -> bool {
+        let time = local(now, self.tz).time();
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )?;
+        if let Some(tz) = self.tz {
+            write!(f, " {}", tz)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TimeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let range = words.next().unwrap_or_default();
+        let tz = parse_tz(words.next())?;
+        if words.next().is_some() {
+            return Err(format!("expected time range and time zone, found {:?}", s));
+        }
+
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| format!("expected time range like \"09:00-18:00\", found {:?}", s))?;
+        let time = |t: &str| {
+            NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| format!("invalid time {:?}", t))
+        };
+        let start = time(start)?;
+        // the end of the day is the midnight of the next one
+        let end = if end == "24:00" {
+            NaiveTime::MIN
+        } else {
+            time(end)?
+        };
+        if start == end {
+            return Err(format!("empty time range {:?}", range));
+        }
+        Ok(Self { start, end, tz })
+    }
+}
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Days of the week, `mon-fri` or `sat,sun` optionally followed by a time zone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Weekdays {
+    /// Bit `n` is set for the `n`th day from Monday
+    pub days: u8,
+    pub tz: Option<Tz>,
+}
+
+impl Weekdays {
+    pub fn contains(&self, now: &DateTime<Utc>) -> bool {
+        let day = local(now, self.tz).weekday().num_days_from_monday();
+        self.days & (1 << day) != 0
+    }
+}
+
+impl fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut runs = Vec::new();
+        let mut day = 0;
+        while day < 7 {
+            if self.days & (1 << day) == 0 {
+                day += 1;
+                continue;
+            }
+            let start = day;
+            while day < 7 && self.days & (1 << day) != 0 {
+                day += 1;
+            }
+            runs.push(match day - start {
+                1 => DAYS[start].to_string(),
+                2 => format!("{},{}", DAYS[start], DAYS[start + 1]),
+                _ => format!("{}-{}", DAYS[start], DAYS[day - 1]),
+            });
+        }
+        write!(f, "{}", runs.join(","))?;
+        if let Some(tz) = self.tz {
+            write!(f, " {}", tz)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Weekdays {
+    type Err = String;
+
+    /// Ranges may wrap around the week, `fri-mon`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let list = words.next().unwrap_or_default();
+        let tz = parse_tz(words.next())?;
+        if words.next().is_some() {
+            return Err(format!("expected days and time zone, found {:?}", s));
+        }
+
+        let day = |d: &str| {
+            DAYS.iter()
+                .position(|name| d.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("invalid day {:?}, expected one of {}", d, DAYS.join(" ")))
+        };
+        let mut days = 0;
+        for item in list.split(',') {
+            let (first, last) = match item.split_once('-') {
+                Some((first, last)) => (day(first)?, day(last)?),
+                None => (day(item)?, day(item)?),
+            };
+            let mut d = first;
+            loop {
+                days |= 1 << d;
+                if d == last {
+                    break;
+                }
+                d = (d + 1) % 7;
+            }
+        }
+        Ok(Self { days, tz })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn time_range() {
+        let range: TimeRange = "09:00-18:00 Asia/Shanghai".parse().unwrap();
+        assert_eq!(range.to_string(), "09:00-18:00 Asia/Shanghai");
+        assert!(range.contains(&at("2024-08-01T01:00:00Z")));
+        assert!(range.contains(&at("2024-08-01T09:59:59Z")));
+        assert!(!range.contains(&at("2024-08-01T10:00:00Z")));
+        assert!(!range.contains(&at("2024-08-01T00:59:00Z")));
+
+        let night: TimeRange = "22:00-06:00 UTC".parse().unwrap();
+        assert!(night.contains(&at("2024-08-01T23:00:00Z")));
+        assert!(night.contains(&at("2024-08-01T05:59:00Z")));
+        assert!(!night.contains(&at("2024-08-01T12:00:00Z")));
+
+        let evening: TimeRange = "18:00-24:00 UTC".parse().unwrap();
+        assert!(evening.contains(&at("2024-08-01T23:59:00Z")));
+        assert!(!evening.contains(&at("2024-08-01T00:00:00Z")));
+
+        let err = |s: &str| s.parse::<TimeRange>().unwrap_err();
+        assert_eq!(
+            err("09:00"),
+            r#"expected time range like "09:00-18:00", found "09:00""#
+        );
+        assert_eq!(err("9-18"), r#"invalid time "9""#);
+        assert_eq!(err("09:00-25:00"), r#"invalid time "25:00""#);
+        assert_eq!(err("09:00-09:00"), r#"empty time range "09:00-09:00""#);
+        assert_eq!(
+            err("09:00-18:00 Mars/Base"),
+            r#"unknown time zone "Mars/Base""#
+        );
+    }
+
+    #[test]
+    fn weekdays() {
+        let days: Weekdays = "mon-fri".parse().unwrap();
+        assert_eq!(days.days, 0b0011111);
+        let days: Weekdays = "Fri-Mon Europe/Berlin".parse().unwrap();
+        assert_eq!(days.to_string(), "mon,fri-sun Europe/Berlin");
+        // Sunday 23:30 in UTC is already Monday in Berlin
+        assert!(days.contains(&at("2024-08-04T23:30:00Z")));
+        assert!(!days.contains(&at("2024-08-06T12:00:00Z")));
+        assert_eq!(
+            "sat,sun,tue".parse::<Weekdays>().unwrap().to_string(),
+            "tue,sat,sun"
+        );
+
+        assert_eq!(
+            "mon-fry".parse::<Weekdays>().unwrap_err(),
+            r#"invalid day "fry", expected one of mon tue wed thu fri sat sun"#
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+/// Load the certificate chain and private key, both in PEM format,
+/// advertising `alpn` protocols in order of preference
+pub fn server_config(cert: &Path, key: &Path, alpn: &[&[u8]]) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/// Trust the bundled web PKI roots, plus every certificate in `ca` if given
+pub fn client_config(ca: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(ca) = ca {
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca)?)) {
+            roots.add(cert?).map_err(io::Error::other)?;
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Self-signed certificate for `localhost` in PEM format, loaded as server config,
+    /// and a client config trusting it
+    pub fn self_signed(alpn: &[&[u8]]) -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "smart_hosts-tls-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        let server = server_config(&dir.join("cert.pem"), &dir.join("key.pem"), alpn).unwrap();
+        let client = client_config(Some(&dir.join("cert.pem"))).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        (server, client)
+    }
+}
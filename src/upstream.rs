@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
+use tracing::trace;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Idle connections kept per upstream
+const POOL_SIZE: usize = 4;
+
+/// Upstream DNS server
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Upstream {
+    /// `1.1.1.1`, `udp://[2606:4700::1111]:53`
+    Udp(SocketAddr),
+    /// DNS-over-TLS, `tls://dns.example` or `tls://1.1.1.1:853#cloudflare-dns.com`
+    /// to connect by address while verifying the certificate against the name after `#`
+    Tls {
+        host: String,
+        port: u16,
+        server_name: String,
+    },
+    /// DNS-over-HTTPS, `https://dns.example/dns-query`
+    Https(String),
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid upstream server {:?}", s);
+        if let Some(rest) = s.strip_prefix("tls://") {
+            let (addr, name) = match rest.split_once('#') {
+                Some((addr, name)) => (addr, Some(name)),
+                None => (rest, None),
+            };
+            let (host, port) = split_host_port(addr, 853).ok_or_else(err)?;
+            let server_name = name.unwrap_or(&host).to_string();
+            ServerName::try_from(server_name.as_str()).map_err(|_| err())?;
+            Ok(Upstream::Tls {
+                host,
+                port,
+                server_name,
+            })
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            let authority = rest.split('/').next().unwrap_or_default();
+            split_host_port(authority, 443).ok_or_else(err)?;
+            Ok(Upstream::Https(s.to_string()))
+        } else {
+            let addr = s.strip_prefix("udp://").unwrap_or(s);
+            let (host, port) = split_host_port(addr, 53).ok_or_else(err)?;
+            let ip: IpAddr = host.parse().map_err(|_| err())?;
+            Ok(Upstream::Udp(SocketAddr::new(ip, port)))
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Udp(addr) => write!(f, "{}", addr),
+            Upstream::Tls {
+                host,
+                port,
+                server_name,
+            } if host == server_name => write!(f, "tls://{}:{}", host, port),
+            Upstream::Tls {
+                host,
+                port,
+                server_name,
+            } => write!(f, "tls://{}:{}#{}", host, port, server_name),
+            Upstream::Https(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Split `host[:port]`, IPv6 addresses may be bracketed with a port or bare without
+fn split_host_port(s: &str, default_port: u16) -> Option<(String, u16)> {
+    if let Ok(ip) = s.parse::<Ipv6Addr>() {
+        return Some((ip.to_string(), default_port));
+    }
+    let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        host.parse::<Ipv6Addr>().ok()?;
+        match rest.strip_prefix(':') {
+            Some(port) => (host, port.parse().ok()?),
+            None if rest.is_empty() => (host, default_port),
+            None => return None,
+        }
+    } else {
+        match s.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (s, default_port),
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Exchanges queries with upstreams, reusing encrypted connections
+pub struct Client {
+    tls: Arc<ClientConfig>,
+    agent: ureq::Agent,
+    idle: Mutex<HashMap<Upstream, Vec<TlsStream>>>,
+}
+
+impl Client {
+    pub fn new(tls: Arc<ClientConfig>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .tls_config(tls.clone())
+            .timeout(TIMEOUT)
+            .max_idle_connections_per_host(POOL_SIZE)
+            .build();
+        Self {
+            tls,
+            agent,
+            idle: Default::default(),
+        }
+    }
+
+    /// Forward a raw query to `upstream` and wait for the matching response
+    pub fn exchange(&self, upstream: &Upstream, query: &[u8]) -> io::Result<Vec<u8>> {
+        match upstream {
+            Upstream::Udp(server) => exchange_udp(*server, query),
+            Upstream::Tls { .. } => self.exchange_tls(upstream, query),
+            Upstream::Https(url) => self.exchange_https(url, query),
+        }
+    }
+
+    fn exchange_tls(&self, upstream: &Upstream, query: &[u8]) -> io::Result<Vec<u8>> {
+        let Upstream::Tls {
+            host,
+            port,
+            server_name,
+        } = upstream
+        else {
+            unreachable!()
+        };
+
+        let pooled = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(upstream)
+            .and_then(Vec::pop);
+        if let Some(mut stream) = pooled {
+            match exchange_stream(&mut stream, query) {
+                Ok(response) => {
+                    self.release(upstream, stream);
+                    return Ok(response);
+                }
+                // the server may have closed it while idle
+                Err(e) => trace!(%upstream, %e, "pooled connection failed, reconnecting"),
+            }
+        }
+
+        let addr = (host.as_str(), *port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for host"))?;
+        let tcp = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let server_name = ServerName::try_from(server_name.clone()).map_err(io::Error::other)?;
+        let conn =
+            ClientConnection::new(self.tls.clone(), server_name).map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(conn, tcp);
+
+        let response = exchange_stream(&mut stream, query)?;
+        self.release(upstream, stream);
+        Ok(response)
+    }
+
+    fn release(&self, upstream: &Upstream, stream: TlsStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(upstream.clone()).or_default();
+        if streams.len() < POOL_SIZE {
+            streams.push(stream);
+        }
+    }
+
+    fn exchange_https(&self, url: &str, query: &[u8]) -> io::Result<Vec<u8>> {
+        let response = self
+            .agent
+            .post(url)
+            .set("content-type", "application/dns-message")
+            .set("accept", "application/dns-message")
+            .send_bytes(query)
+            .map_err(io::Error::other)?;
+        // anything but a DNS message is a failure of this server, not an answer
+        if response.status() != 200 {
+            return Err(io::Error::other(format!(
+                "unexpected status {}",
+                response.status()
+            )));
+        }
+        if response.content_type() != "application/dns-message" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected content type {:?}", response.content_type()),
+            ));
+        }
+        let mut buf = Vec::new();
+        response
+            .into_reader()
+            .take(u16::MAX as u64)
+            .read_to_end(&mut buf)?;
+        if buf.len() < 2 || query.len() < 2 || buf[..2] != query[..2] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response ID mismatch",
+            ));
+        }
+        Ok(buf)
+    }
+}
+
+fn exchange_udp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.send(query)?;
+
+    let mut buf = [0; 4096];
+    loop {
+        let size = socket.recv(&mut buf)?;
+        // responses must echo the query ID, drop stray datagrams
+        if size >= 2 && query.len() >= 2 && buf[..2] == query[..2] {
+            trace!(size, %server, "received upstream response");
+            return Ok(buf[..size].to_vec());
+        }
+    }
+}
+
+/// Length prefixed exchange over a stream transport
+fn exchange_stream(stream: &mut (impl Read + Write), query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(query.len() + 2);
+    buf.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    buf.extend_from_slice(query);
+    stream.write_all(&buf)?;
+    stream.flush()?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    if response.len() < 2 || query.len() < 2 || response[..2] != query[..2] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response ID mismatch",
+        ));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            RwLock,
+        },
+        thread,
+    };
+
+    use rustls::{ServerConfig, ServerConnection};
+
+    use super::*;
+    use crate::core::*;
+    use crate::resolver::Resolver;
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut packet = DnsPacket::query(name, DnsType::A);
+        packet.header.id = id;
+        packet.encode().unwrap()
+    }
+
+    /// DNS-over-TLS stub echoing queries back as responses, counts accepted connections
+    fn stub_tls(tls: Arc<ServerConfig>) -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for tcp in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let conn = ServerConnection::new(tls.clone()).unwrap();
+                let mut stream = StreamOwned::new(conn, tcp.unwrap());
+                thread::spawn(move || loop {
+                    let mut len = [0; 2];
+                    if stream.read_exact(&mut len).is_err() {
+                        return;
+                    }
+                    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut buf).unwrap();
+                    buf[2] |= 0x80;
+                    stream.write_all(&len).unwrap();
+                    stream.write_all(&buf).unwrap();
+                });
+            }
+        });
+        (port, accepted)
+    }
+
+    /// Status line, content type and body of a stub response to a query
+    type Respond = fn(Vec<u8>) -> (&'static str, &'static str, Vec<u8>);
+
+    /// HTTPS stub answering each POSTed query with the response made of it by `respond`
+    fn stub_https(tls: Arc<ServerConfig>, respond: Respond) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for tcp in listener.incoming() {
+                let conn = ServerConnection::new(tls.clone()).unwrap();
+                let mut stream = StreamOwned::new(conn, tcp.unwrap());
+                let mut head = Vec::new();
+                let mut byte = [0];
+                while !head.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let mut query = vec![0; len];
+                stream.read_exact(&mut query).unwrap();
+                let (status, content_type, body) = respond(query);
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    content_type,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "9.9.9.9".parse(),
+            Ok(Upstream::Udp("9.9.9.9:53".parse().unwrap()))
+        );
+        assert_eq!(
+            "udp://2606:4700::1111".parse(),
+            Ok(Upstream::Udp("[2606:4700::1111]:53".parse().unwrap()))
+        );
+        assert_eq!(
+            "tls://dns.quad9.net".parse(),
+            Ok(Upstream::Tls {
+                host: "dns.quad9.net".to_string(),
+                port: 853,
+                server_name: "dns.quad9.net".to_string(),
+            })
+        );
+        assert_eq!(
+            "tls://[2606:4700::1111]:8853#one.one.one.one".parse(),
+            Ok(Upstream::Tls {
+                host: "2606:4700::1111".to_string(),
+                port: 8853,
+                server_name: "one.one.one.one".to_string(),
+            })
+        );
+        assert_eq!(
+            "https://dns.google/dns-query".parse(),
+            Ok(Upstream::Https("https://dns.google/dns-query".to_string()))
+        );
+
+        assert!("dns.google".parse::<Upstream>().is_err());
+        assert!("tls://1.1.1.1:dot".parse::<Upstream>().is_err());
+        assert!("tls://1.1.1.1#bad name".parse::<Upstream>().is_err());
+        assert!("https:///dns-query".parse::<Upstream>().is_err());
+    }
+
+    #[test]
+    fn tls_pooling() {
+        let (server, client) = crate::tls::tests::self_signed(&[]);
+        let (port, accepted) = stub_tls(server);
+        let client = Client::new(client);
+        let upstream: Upstream = format!("tls://127.0.0.1:{}#localhost", port)
+            .parse()
+            .unwrap();
+
+        for id in 1..=3 {
+            let response = client
+                .exchange(&upstream, &query(id, "example.com."))
+                .unwrap();
+            let packet = DnsPacket::parse(&response).unwrap();
+            assert_eq!(packet.header.id, id);
+            assert!(packet.header.qr);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn tls_verification() {
+        let (server, _) = crate::tls::tests::self_signed(&[]);
+        let (port, _) = stub_tls(server);
+        let upstream: Upstream = format!("tls://127.0.0.1:{}#localhost", port)
+            .parse()
+            .unwrap();
+
+        // the self-signed certificate is not trusted by default
+        let client = Client::new(crate::tls::client_config(None).unwrap());
+        assert!(client
+            .exchange(&upstream, &query(1, "example.com."))
+            .is_err());
+    }
+
+    #[test]
+    fn https() {
+        let (server, client) = crate::tls::tests::self_signed(crate::doh::ALPN);
+        let config = "127.0.0.1 *.home.local".parse().unwrap();
+        let resolver = Arc::new(Resolver::new(
+            config,
+            Arc::new(RwLock::new(Default::default())),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            crate::doh::serve(vec![(listener, Some(server))], Vec::new(), resolver)
+        });
+
+        let client = Client::new(client);
+        let upstream: Upstream = format!("https://localhost:{}/dns-query", port)
+            .parse()
+            .unwrap();
+        let response = client
+            .exchange(&upstream, &query(7, "nas.home.local."))
+            .unwrap();
+        let packet = DnsPacket::parse(&response).unwrap();
+        assert_eq!(packet.header.id, 7);
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn https_errors() {
+        let responses: [Respond; 4] = [
+            |_| ("200 OK", "text/html", b"<html></html>".to_vec()),
+            |_| ("204 No Content", "application/dns-message", Vec::new()),
+            |mut query| {
+                query[0] ^= 0xff;
+                ("200 OK", "application/dns-message", query)
+            },
+            |query| ("200 OK", "application/dns-message", query),
+        ];
+        for (idx, respond) in responses.into_iter().enumerate() {
+            let (server, client) = crate::tls::tests::self_signed(&[]);
+            let port = stub_https(server, respond);
+            let client = Client::new(client);
+            let upstream: Upstream = format!("https://localhost:{}/dns-query", port)
+                .parse()
+                .unwrap();
+            let result = client.exchange(&upstream, &query(7, "example.com."));
+            // only a DNS message with the query ID is a response, leaving others to the next server
+            assert_eq!(result.is_ok(), idx == 3, "response {}", idx);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use std::fmt::Write as _;
+
+use crate::config::{fqdn, parse_character_strings, parse_number, parse_ttl, Config, Pattern};
+use crate::core::{DnsRData, DnsType, Soa, SvcParam, Svcb};
+use crate::monitor::NetworkState;
+
+/// Resource record of a zone file, the TTL is left to the config unless given
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Fully qualified
+    pub name: String,
+    pub ttl: Option<u32>,
+    pub r#type: DnsType,
+    /// Character-strings of a TXT record, or the single data of other records
+    pub data: Vec<DnsRData>,
+}
+
+/// Records of a file in the RFC 1035 master file format, names relative to `origin` unless
+/// `$ORIGIN` says otherwise. Types without a mnemonic are written `TYPE65280` with data
+/// in the generic `\# 3 0a0001` form, other mnemonics are errors rather than missing records.
+pub fn entries(source: &str, origin: Option<&str>) -> Result<Vec<Entry>, String> {
+    let mut origin = origin.map(fqdn);
+    let mut default_ttl = None;
+    let mut owner: Option<String> = None;
+    let mut entries = Vec::new();
+    for (line, indented, fields) in logical_lines(source)? {
+        let at = |e: String| format!("line {}: {}", line, e);
+        let mut fields = fields.iter().map(String::as_str).peekable();
+        let Some(&first) = fields.peek() else {
+            continue;
+        };
+        match first {
+            "$ORIGIN" | "$TTL" => {
+                let value = match (fields.nth(1), fields.next()) {
+                    (Some(value), None) => value,
+                    _ => return Err(at(format!("{} takes a single value", first))),
+                };
+                if first == "$ORIGIN" {
+                    origin = Some(absolute(value, origin.as_deref()).map_err(at)?);
+                } else {
+                    default_ttl = Some(parse_ttl(value).map_err(at)?);
+                }
+                continue;
+            }
+            _ if first.starts_with('$') => {
+                return Err(at(format!("unsupported directive {}", first)));
+            }
+            _ => {}
+        }
+        if !indented {
+            let name = fields.next().unwrap();
+            owner = Some(absolute(name, origin.as_deref()).map_err(at)?);
+        }
+        let name = owner
+            .clone()
+            .ok_or_else(|| at("missing owner name".to_string()))?;
+
+        // the TTL and the class may come in either order
+        let mut ttl = None;
+        for _ in 0..2 {
+            match fields.peek() {
+                Some(class) if class.eq_ignore_ascii_case("IN") => {}
+                Some(value) if ttl.is_none() && value.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl = Some(parse_ttl(value).map_err(at)?);
+                }
+                _ => break,
+            }
+            fields.next();
+        }
+        let r#type = fields
+            .next()
+            .ok_or_else(|| at("missing type".to_string()))?;
+        let r#type = r#type
+            .parse::<DnsType>()
+            .map_err(|_| at(format!("unsupported type {}", r#type)))?;
+        let fields: Vec<&str> = fields.collect();
+        let data = rdata(r#type, &fields, origin.as_deref())
+            .map_err(|e| at(format!("invalid {} record: {}", r#type, e)))?;
+        entries.push(Entry {
+            name,
+            ttl: ttl.or(default_ttl),
+            r#type,
+            data,
+        });
+    }
+    Ok(entries)
+}
+
+/// `@` stands for the origin, names without the trailing dot are relative to it
+fn absolute(name: &str, origin: Option<&str>) -> Result<String, String> {
+    match (name, origin) {
+        ("@", Some(origin)) => Ok(origin.to_string()),
+        _ if name.ends_with('.') => Ok(name.to_ascii_lowercase()),
+        (_, Some(".")) => Ok(format!("{}.", name.to_ascii_lowercase())),
+        (_, Some(origin)) => Ok(format!("{}.{}", name.to_ascii_lowercase(), origin)),
+        (_, None) => Err(format!("relative name {:?} without an origin", name)),
+    }
+}
+
+/// Data of a record of `r#type` from the `fields` of its zone file entry
+pub fn rdata(
+    r#type: DnsType,
+    fields: &[&str],
+    origin: Option<&str>,
+) -> Result<Vec<DnsRData>, String> {
+    let name = |s: &str| absolute(s, origin);
+    let data = match (r#type, fields) {
+        (DnsType::A, [ip]) => DnsRData::IP(ip.parse().map_err(|_| format!("{:?}", ip))?),
+        (DnsType::AAAA, [ip]) => DnsRData::IPv6(ip.parse().map_err(|_| format!("{:?}", ip))?),
+        (DnsType::NS, [host]) => DnsRData::Ns(name(host)?),
+        (DnsType::CNAME, [host]) => DnsRData::Cname(name(host)?),
+        (DnsType::PTR, [host]) => DnsRData::Ptr(name(host)?),
+        (DnsType::SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => {
+            DnsRData::Soa(Soa {
+                mname: name(mname)?,
+                rname: name(rname)?,
+                serial: serial
+                    .parse()
+                    .map_err(|_| format!("invalid serial {:?}", serial))?,
+                refresh: parse_ttl(refresh)?,
+                retry: parse_ttl(retry)?,
+                expire: parse_ttl(expire)?,
+                minimum: parse_ttl(minimum)?,
+            })
+        }
+        (DnsType::MX, [preference, exchange]) => DnsRData::Mx {
+            preference: parse_number(preference, "preference")?,
+            exchange: name(exchange)?,
+        },
+        (DnsType::TXT, [_, ..]) => {
+            let mut data = Vec::new();
+            for field in fields {
+                let text = if field.starts_with('"') {
+                    parse_character_strings(field)?.concat()
+                } else {
+                    field.as_bytes().to_vec()
+                };
+                data.extend(DnsRData::txt(&text));
+            }
+            return Ok(data);
+        }
+        (DnsType::SRV, [priority, weight, port, target]) => DnsRData::Srv {
+            priority: parse_number(priority, "priority")?,
+            weight: parse_number(weight, "weight")?,
+            port: parse_number(port, "port")?,
+            target: name(target)?,
+        },
+        (DnsType::SVCB | DnsType::HTTPS, [priority, target, params @ ..]) => {
+            let params = params
+                .iter()
+                .map(|p| p.parse())
+                .collect::<Result<Vec<SvcParam>, _>>()?;
+            let priority = parse_number(priority, "priority")?;
+            DnsRData::Svcb(Svcb::new(priority, name(target)?, params)?)
+        }
+        (DnsType::Other(_), ["\\#", len, hex @ ..]) => DnsRData::Unknown(generic(len, hex)?),
+        _ => return Err(format!("unexpected data {:?}", fields.join(" "))),
+    };
+    Ok(vec![data])
+}
+
+/// Data in the generic `\# LENGTH HEX...` form of RFC 3597
+fn generic(len: &str, hex: &[&str]) -> Result<Vec<u8>, String> {
+    let len = parse_number(len, "length")? as usize;
+    let hex = hex.concat();
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex {:?}", hex));
+    }
+    let data: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    if data.len() != len {
+        return Err(format!("{} bytes of data, not {}", data.len(), len));
+    }
+    Ok(data)
+}
+
+/// Fields of every entry with the number of the line it starts on, and whether it is
+/// indented to repeat the previous owner. Entries in parentheses span lines,
+/// comments start with `;` and quoted fields keep their quotes.
+fn logical_lines(source: &str) -> Result<Vec<(usize, bool, Vec<String>)>, String> {
+    let mut lines = Vec::new();
+    let mut fields = Vec::new();
+    let mut start = None;
+    let mut depth = 0;
+    for (idx, line) in source.lines().enumerate() {
+        let first = *start.get_or_insert((idx + 1, line.starts_with([' ', '\t'])));
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut escaped = false;
+        for c in line.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = !quoted,
+                ';' if !quoted => break,
+                '(' | ')' if !quoted => {
+                    if c == '(' {
+                        depth += 1;
+                    } else if depth == 0 {
+                        return Err(format!("line {}: unbalanced parentheses", idx + 1));
+                    } else {
+                        depth -= 1;
+                    }
+                    fields.extend((!field.is_empty()).then(|| std::mem::take(&mut field)));
+                    continue;
+                }
+                _ if c.is_whitespace() && !quoted => {
+                    fields.extend((!field.is_empty()).then(|| std::mem::take(&mut field)));
+                    continue;
+                }
+                _ => {}
+            }
+            field.push(c);
+        }
+        if quoted {
+            return Err(format!("line {}: unterminated string", idx + 1));
+        }
+        fields.extend((!field.is_empty()).then_some(field));
+        if depth == 0 {
+            lines.push((first.0, first.1, std::mem::take(&mut fields)));
+            start = None;
+        }
+    }
+    if depth > 0 {
+        return Err("unbalanced parentheses at the end of the file".to_string());
+    }
+    Ok(lines)
+}
+
+/// The rules active in `state` as a zone file, leaving out those shadowed by earlier rules
+pub fn export(config: &Config, state: &NetworkState) -> String {
+    let mut out = String::new();
+    writeln!(out, "; active smart hosts rules").unwrap();
+    writeln!(out, "$TTL {}", config.ttl.default).unwrap();
+    for zone in &config.zones {
+        let ttl = zone.ttl.unwrap_or(config.ttl.default);
+        let soa = DnsRData::Soa(zone.soa(config.ttl.negative));
+        writeln!(out, "{}. {} IN SOA {}", zone.apex, ttl, soa).unwrap();
+        for nameserver in &zone.nameservers {
+            writeln!(out, "{}. {} IN NS {}", zone.apex, ttl, nameserver).unwrap();
+        }
+    }
+
+    let active = |conditions: &[crate::condition::Expr]| conditions.iter().all(|c| c.eval(state));
+    // a rule without an address of the asked family leaves the name to the next one,
+    // names matched by an earlier pattern of the family are shadowed by it
+    let mut answered: Vec<(&Pattern, bool)> = Vec::new();
+    for rule in config.rules.iter().filter(|r| active(&r.conditions)) {
+        let ttl = config.answer_ttl(rule);
+        let mut families = Vec::new();
+        for pattern in &rule.patterns {
+            let name = pattern.to_string();
+            for address in &rule.addresses {
+                let ipv4 = address.is_ipv4();
+                if answered
+                    .iter()
+                    .any(|(shadowing, family)| *family == ipv4 && shadowing.matches(&name))
+                {
+                    continue;
+                }
+                let r#type = if ipv4 { "A" } else { "AAAA" };
+                writeln!(out, "{}. {} IN {} {}", name, ttl, r#type, address).unwrap();
+                families.push((pattern, ipv4));
+            }
+        }
+        answered.extend(families);
+    }
+    for record in config.records.iter().filter(|r| active(&r.conditions)) {
+        let data: Vec<String> = record.data.iter().map(|d| d.to_string()).collect();
+        writeln!(
+            out,
+            "{}. {} IN {} {}",
+            record.pattern,
+            config.record_ttl(record),
+            record.r#type,
+            data.join(" ")
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// `zone-file PATH [origin NAME]`
+pub fn parse_directive(line: &str) -> Result<(&str, Option<&str>), String> {
+    match line.split_whitespace().collect::<Vec<_>>()[..] {
+        [_, path] => Ok((path, None)),
+        [_, path, "origin", origin] => Ok((path, Some(origin))),
+        _ => Err("zone-file takes a path and an optional origin".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn master_file() {
+        let source = r#"
+$ORIGIN home.example.com.
+$TTL 1h
+@       IN  SOA ns1 hostmaster (
+                2026101901 ; serial
+                1h 10m 1d 5m )
+        IN  NS  ns1.example.com.
+nas     300 IN  A     192.168.1.10
+            IN  AAAA  fd00::10
+mail    IN  300 MX    10 nas
+@           TXT   "v=spf1 mx -all" "; not a comment"
+_sip._tcp   SRV   10 5 5060 nas
+app         HTTPS 1 . alpn="h2,h3" port=8443
+tv          CNAME nas
+loc         TYPE29 \# 4 ( 00 12
+                      16 13 )
+printer.home.local. A 192.168.1.20
+"#;
+        let lines: Vec<String> = entries(source, None)
+            .unwrap()
+            .iter()
+            .map(|e| {
+                let data: Vec<String> = e.data.iter().map(|d| d.to_string()).collect();
+                format!("{} {:?} {} {}", e.name, e.ttl, e.r#type, data.join(" "))
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "home.example.com. Some(3600) SOA ns1.home.example.com. \
+                 hostmaster.home.example.com. 2026101901 3600 600 86400 300",
+                "home.example.com. Some(3600) NS ns1.example.com.",
+                "nas.home.example.com. Some(300) A 192.168.1.10",
+                "nas.home.example.com. Some(3600) AAAA fd00::10",
+                "mail.home.example.com. Some(300) MX 10 nas.home.example.com.",
+                r#"home.example.com. Some(3600) TXT "v=spf1 mx -all" "; not a comment""#,
+                "_sip._tcp.home.example.com. Some(3600) SRV 10 5 5060 nas.home.example.com.",
+                r#"app.home.example.com. Some(3600) HTTPS 1 . alpn="h2,h3" port=8443"#,
+                "tv.home.example.com. Some(3600) CNAME nas.home.example.com.",
+                r"loc.home.example.com. Some(3600) TYPE29 \# 4 00121613",
+                "printer.home.local. Some(3600) A 192.168.1.20",
+            ]
+        );
+
+        assert_eq!(
+            entries("nas A 10.0.0.1", None).unwrap_err(),
+            r#"line 1: relative name "nas" without an origin"#
+        );
+        assert_eq!(
+            entries("nas A 10.0.0.1", Some("home.local"))
+                .unwrap()
+                .first()
+                .map(|e| e.name.as_str()),
+            Some("nas.home.local.")
+        );
+        assert_eq!(
+            entries("$ORIGIN home.local.\nnas A 10.0.0.1 10.0.0.2", None).unwrap_err(),
+            r#"line 2: invalid A record: unexpected data "10.0.0.1 10.0.0.2""#
+        );
+        // skipping records would make the names authoritatively missing
+        assert_eq!(
+            entries("@ CAA 0 issue \"ca.example\"", Some("home.local")).unwrap_err(),
+            "line 1: unsupported type CAA"
+        );
+        assert_eq!(
+            entries(r"@ TYPE257 \# 2 00", Some("home.local")).unwrap_err(),
+            r#"line 1: invalid TYPE257 record: 1 bytes of data, not 2"#
+        );
+        assert_eq!(
+            entries("@ SOA ns1. host. ( 1 2 3 4 5", Some(".")).unwrap_err(),
+            "unbalanced parentheses at the end of the file"
+        );
+        assert_eq!(
+            entries("$INCLUDE other.zone", None).unwrap_err(),
+            "line 1: unsupported directive $INCLUDE"
+        );
+    }
+
+    #[test]
+    fn exporting() {
+        let config: Config = r#"
+zone home.example.com ns1.example.com serial 7
+192.168.1.10        nas.home.example.com, ssid="home"
+10.8.0.10,fd00::10  nas.home.example.com
+10.8.0.11           nas.home.example.com, ttl=10s
+192.168.1.20        *.lab.home.example.com, ssid="office"
+192.168.1.30        *.iot.home.example.com
+192.168.1.31        cam.iot.home.example.com *.hall.iot.home.example.com
+fd00::31            cam.iot.home.example.com
+txt home.example.com "v=spf1 -all"
+"#
+        .parse()
+        .unwrap();
+        let state = NetworkState {
+            ssid: Some("home".to_string()),
+            ..Default::default()
+        };
+        let zone = export(&config, &state);
+        assert_eq!(
+            zone,
+            r#"; active smart hosts rules
+$TTL 3600
+home.example.com. 3600 IN SOA ns1.example.com. hostmaster.home.example.com. 7 3600 600 86400 60
+home.example.com. 3600 IN NS ns1.example.com.
+nas.home.example.com. 60 IN A 192.168.1.10
+nas.home.example.com. 3600 IN AAAA fd00::10
+*.iot.home.example.com. 3600 IN A 192.168.1.30
+cam.iot.home.example.com. 3600 IN AAAA fd00::31
+home.example.com. 3600 IN TXT "v=spf1 -all"
+"#
+        );
+
+        // exported zones read back
+        let records = entries(&zone, None).unwrap();
+        assert_eq!(records.len(), 7);
+        assert_eq!(
+            records[2].data,
+            vec![DnsRData::IP("192.168.1.10".parse().unwrap())]
+        );
+    }
+}